@@ -0,0 +1,58 @@
+use crate::{Db, Error, State, StoreRead, StoreWrite};
+use hivemind_types::{
+    block::{self, BlockHash, Header},
+    Body,
+};
+
+const TIP_KEY: &[u8] = b"tip";
+
+impl State {
+    /// Stores a block by its hash without connecting it, blocks of every branch are kept.
+    pub fn put_block(
+        &self,
+        txn: &mut impl StoreWrite,
+        header: &Header,
+        body: &Body,
+    ) -> Result<BlockHash, Error> {
+        if block::body_hash(body) != header.body_hash {
+            return Err(Error::BodyHashMismatch);
+        }
+        let hash = header.hash();
+        txn.put(Db::Headers, &hash, header)?;
+        txn.put(Db::Bodies, &hash, body)?;
+        Ok(hash)
+    }
+
+    pub fn get_header(
+        &self,
+        txn: &impl StoreRead,
+        hash: &BlockHash,
+    ) -> Result<Option<Header>, Error> {
+        txn.get(Db::Headers, hash)
+    }
+
+    pub fn get_body(&self, txn: &impl StoreRead, hash: &BlockHash) -> Result<Option<Body>, Error> {
+        txn.get(Db::Bodies, hash)
+    }
+
+    /// Returns the hash of the block at `height` of the best chain.
+    pub fn get_block_hash(
+        &self,
+        txn: &impl StoreRead,
+        height: u32,
+    ) -> Result<Option<BlockHash>, Error> {
+        txn.get(Db::BlockHashes, &height)
+    }
+
+    /// Returns the hash of the last connected block, `None` before the first block.
+    pub fn get_tip(&self, txn: &impl StoreRead) -> Result<Option<BlockHash>, Error> {
+        match txn.get_raw(Db::Meta, TIP_KEY)? {
+            Some(tip) => Ok(Some(bincode::deserialize(&tip)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn set_tip(&self, txn: &mut impl StoreWrite, tip: &BlockHash) -> Result<(), Error> {
+        txn.put_raw(Db::Meta, TIP_KEY, &bincode::serialize(tip)?)
+    }
+}
@@ -0,0 +1,236 @@
+//! Rebuilds the state from the blocks archived by a node into a fresh LMDB environment, and
+//! reports the first block after which it differs from the state the node had.
+//!
+//! Usage: hivemind-replay <node-dir> <output-dir> [--stop-at <height>]
+
+use heed::{flags::Flags, EnvOpenOptions};
+use hivemind_state::{Db, Error, HeedStore, State, StoreRead};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const MAP_SIZE: usize = 16 * 1024 * 1024 * 1024;
+
+struct Args {
+    node_dir: PathBuf,
+    output_dir: PathBuf,
+    stop_at: Option<u32>,
+}
+
+fn parse_args() -> Option<Args> {
+    let mut args = std::env::args().skip(1);
+    let node_dir = args.next()?.into();
+    let output_dir = args.next()?.into();
+    let stop_at = match args.next().as_deref() {
+        Some("--stop-at") => Some(args.next()?.parse().ok()?),
+        Some(_) => return None,
+        None => None,
+    };
+    if args.next().is_some() {
+        return None;
+    }
+    Some(Args {
+        node_dir,
+        output_dir,
+        stop_at,
+    })
+}
+
+fn open(dir: &PathBuf) -> Result<(heed::Env, HeedStore), Error> {
+    let env = EnvOpenOptions::new()
+        .map_size(MAP_SIZE)
+        .max_dbs(HeedStore::NUM_DBS)
+        .open(dir)?;
+    let store = HeedStore::new(&env)?;
+    Ok((env, store))
+}
+
+// Read-only, so replaying never migrates or otherwise writes to the node.
+fn open_node(dir: &PathBuf) -> Result<(heed::Env, HeedStore), Error> {
+    let mut options = EnvOpenOptions::new();
+    options.map_size(MAP_SIZE).max_dbs(HeedStore::NUM_DBS);
+    // SAFETY: the node's environment is only read, and LMDB allows readers alongside a writer.
+    unsafe {
+        options.flag(Flags::MdbRdOnly);
+    }
+    let env = options.open(dir)?;
+    let store = HeedStore::open(&env)?;
+    Ok((env, store))
+}
+
+fn first_difference(
+    node: &impl StoreRead,
+    replay: &impl StoreRead,
+) -> Result<Option<(Db, Vec<u8>)>, Error> {
+    for db in Db::ALL {
+        if db.commitment_tree().is_none() {
+            continue;
+        }
+        let mut node_entries = node.prefix_iter(db, &[])?;
+        let mut replay_entries = replay.prefix_iter(db, &[])?;
+        loop {
+            match (
+                node_entries.next().transpose()?,
+                replay_entries.next().transpose()?,
+            ) {
+                (None, None) => break,
+                (Some((key, _)), None) | (None, Some((key, _))) => return Ok(Some((db, key))),
+                (Some(node_entry), Some(replay_entry)) => {
+                    if node_entry != replay_entry {
+                        let key = std::cmp::min(node_entry.0, replay_entry.0);
+                        return Ok(Some((db, key)));
+                    }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn replay(args: &Args) -> Result<bool, Error> {
+    let (node_env, node_store) = open_node(&args.node_dir)?;
+    std::fs::create_dir_all(&args.output_dir)?;
+    let (replay_env, replay_store) = open(&args.output_dir)?;
+    let node_rotxn = node_env.read_txn()?;
+    let node = node_store.read(&node_rotxn);
+    let genesis = State::new().get_genesis(&node)?;
+    let state = match &genesis {
+        Some(config) => State::with_params(config.params.clone()),
+        None => State::new(),
+    };
+    {
+        let rotxn = replay_env.read_txn()?;
+        if state.get_tip(&replay_store.read(&rotxn))?.is_some()
+            || !replay_store.read(&rotxn).is_empty(Db::Utxos)?
+        {
+            return Err(Error::StateNotEmpty);
+        }
+    }
+    let node_tip = match state.get_tip(&node)? {
+        Some(tip) => state
+            .get_header(&node, &tip)?
+            .ok_or(Error::UnknownBlock { hash: tip })?,
+        None => {
+            println!("the node has no blocks");
+            return Ok(true);
+        }
+    };
+    let last_height = args.stop_at.map_or(node_tip.height, |stop_at| {
+        std::cmp::min(stop_at, node_tip.height)
+    });
+    // Chains with a genesis config start with its allocation at height 0.
+    let first_height = match &genesis {
+        Some(config) => {
+            let mut rwtxn = replay_env.write_txn()?;
+            let header = state.connect_genesis(&mut replay_store.write(&mut rwtxn), config)?;
+            rwtxn.commit()?;
+            if state.get_block_hash(&node, 0)? != Some(header.hash()) {
+                println!("state differs after genesis");
+                return Ok(false);
+            }
+            1
+        }
+        None => 0,
+    };
+    for height in first_height..=last_height {
+        let hash = state
+            .get_block_hash(&node, height)?
+            .ok_or(Error::InvalidBlockHeight { height })?;
+        let header = state
+            .get_header(&node, &hash)?
+            .ok_or(Error::UnknownBlock { hash })?;
+        let body = state
+            .get_body(&node, &hash)?
+            .ok_or(Error::UnknownBlock { hash })?;
+        let mut rwtxn = replay_env.write_txn()?;
+        let commitment = {
+            let mut txn = replay_store.write(&mut rwtxn);
+            if let Err(err) =
+                state
+                    .validate_body(&txn, body.clone(), height)
+                    .and_then(|fee_value| {
+                        state.connect_body(&mut txn, &body, height)?;
+                        state.connect_deposits(&mut txn, height, &header.deposits)?;
+                        state.connect_bundle_status(&mut txn, height, header.bundle_status)?;
+                        state.connect_oracle_fee(&mut txn, height, fee_value)
+                    })
+            {
+                println!("block {height} is invalid: {err}");
+                return Ok(false);
+            }
+            state.commitment(&txn)?
+        };
+        rwtxn.commit()?;
+        if commitment != header.commitment {
+            println!(
+                "state differs after block {height}: commitment {} instead of {}",
+                hex(&commitment),
+                hex(&header.commitment),
+            );
+            return Ok(false);
+        }
+    }
+    println!("replayed blocks {first_height} to {last_height}");
+    // Every block matched the commitment in its header, but the current state of the node can
+    // still have been corrupted after it was connected.
+    if last_height == node_tip.height {
+        let replay_rotxn = replay_env.read_txn()?;
+        let replayed = replay_store.read(&replay_rotxn);
+        if state.commitment(&node)? != state.commitment(&replayed)? {
+            print!("the current state of the node differs from the replayed state");
+            match first_difference(&node, &replayed)? {
+                Some((db, key)) => println!(", first at key {} of {}", hex(&key), db.name()),
+                None => println!(" only in its commitment nodes"),
+            }
+            return Ok(false);
+        }
+        println!("the current state of the node matches the replayed state");
+    }
+    Ok(true)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Some(args) => args,
+        None => {
+            eprintln!("usage: hivemind-replay <node-dir> <output-dir> [--stop-at <height>]");
+            return ExitCode::from(2);
+        }
+    };
+    match replay(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hivemind_state::{MemoryStore, StoreWrite};
+
+    #[test]
+    fn first_difference_is_the_lowest_differing_committed_key() {
+        let mut node = MemoryStore::new().unwrap();
+        let mut replay = MemoryStore::new().unwrap();
+        // Databases outside the commitment are ignored.
+        node.put_raw(Db::Events, &[0], &[0]).unwrap();
+        for store in [&mut node, &mut replay] {
+            store.put_raw(Db::Markets, &[1], &[1]).unwrap();
+        }
+        assert_eq!(first_difference(&node, &replay).unwrap(), None);
+        node.put_raw(Db::Markets, &[2], &[2]).unwrap();
+        node.put_raw(Db::Markets, &[3], &[3]).unwrap();
+        replay.put_raw(Db::Markets, &[2], &[0]).unwrap();
+        assert_eq!(
+            first_difference(&node, &replay).unwrap(),
+            Some((Db::Markets, vec![2]))
+        );
+    }
+}
@@ -0,0 +1,551 @@
+use crate::{
+    Db, Error, GenesisConfig, HeedStore, Mainchain, PruningMode, State, StateEvent, StoreRead,
+    StoreWrite,
+};
+use hivemind_types::{
+    block::{BlockHash, Header},
+    drivechain::{BundleStatus, Deposit, WithdrawalBundle},
+    Body,
+};
+
+// Height and events of a connected block.
+type Connected = (u32, Vec<StateEvent>);
+
+/// Outcome of a block committed by `ChainState::submit_block`.
+#[derive(Debug)]
+pub struct Submitted {
+    /// Heights connected in order, empty if the block was only archived.
+    pub heights: Vec<u32>,
+    /// Error of proposing the pending withdrawal bundle to the mainchain after the commit.
+    pub proposal_error: Option<Error>,
+}
+
+/// Archives blocks and keeps the state at the tip of the longest known chain, reorganizing to
+/// another branch when it becomes longer.
+pub struct ChainState {
+    pub env: heed::Env,
+    pub store: HeedStore,
+    pub state: State,
+    pub pruning: PruningMode,
+    /// Whether events of connected bodies are stored, see `State::get_events`.
+    pub store_events: bool,
+    /// Checks deposits credited and bundle statuses reported by headers, and pays out withdrawal
+    /// bundles. Blocks with deposits or bundle statuses are rejected without it.
+    pub mainchain: Option<Box<dyn Mainchain + Send + Sync>>,
+}
+
+impl ChainState {
+    /// Opens the state in `env`, with the chain params of its genesis config if it has one.
+    pub fn new(env: heed::Env, pruning: PruningMode) -> Result<Self, Error> {
+        let store = HeedStore::new(&env)?;
+        let mut state = State::new();
+        {
+            let rotxn = env.read_txn()?;
+            if let Some(config) = state.get_genesis(&store.read(&rotxn))? {
+                state.params = config.params;
+            }
+        }
+        Ok(Self {
+            env,
+            store,
+            state,
+            pruning,
+            store_events: false,
+            mainchain: None,
+        })
+    }
+
+    /// Connects the genesis of a new chain, see `State::connect_genesis`.
+    pub fn connect_genesis(&mut self, config: &GenesisConfig) -> Result<Header, Error> {
+        let mut rwtxn = self.env.write_txn()?;
+        let header = self
+            .state
+            .connect_genesis(&mut self.store.write(&mut rwtxn), config)?;
+        rwtxn.commit()?;
+        self.state.params = config.params.clone();
+        Ok(header)
+    }
+
+    /// Returns the header of the last connected block, `None` before the first block.
+    pub fn tip(&self) -> Result<Option<Header>, Error> {
+        let rotxn = self.env.read_txn()?;
+        let txn = self.store.read(&rotxn);
+        match self.state.get_tip(&txn)? {
+            Some(tip) => self
+                .state
+                .get_header(&txn, &tip)?
+                .map(Some)
+                .ok_or(Error::UnknownBlock { hash: tip }),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns deposits on the mainchain that are not credited yet, in the order the next blocks
+    /// must credit them.
+    pub fn pending_deposits(&self) -> Result<Vec<Deposit>, Error> {
+        let mainchain = self.mainchain.as_ref().ok_or(Error::NoMainchain)?;
+        let rotxn = self.env.read_txn()?;
+        let sequence = self.state.get_deposit_sequence(&self.store.read(&rotxn))?;
+        mainchain.get_deposits(sequence)
+    }
+
+    /// Archives a block and, if its chain is now the longest, disconnects the blocks of the best
+    /// chain back to the fork and connects the new branch, all in one transaction. Nothing is
+    /// written if any block of the new branch is invalid.
+    ///
+    /// After the transaction is committed, disconnected heights are published to subscribers of
+    /// the state as `StateEvent::BlockDisconnected`, then events of connected bodies are
+    /// published and the pending withdrawal bundle is proposed to the mainchain. The block is
+    /// already committed when the proposal fails, so its error is returned in
+    /// `Submitted::proposal_error` and the bundle is proposed again after the next block, or by
+    /// calling `propose_bundle`.
+    pub fn submit_block(&self, header: &Header, body: &Body) -> Result<Submitted, Error> {
+        let mut rwtxn = self.env.write_txn()?;
+        let (disconnected, connected) =
+            self.submit_block_in(&mut self.store.write(&mut rwtxn), header, body)?;
+        rwtxn.commit()?;
+        for height in disconnected {
+            self.state
+                .publish_events(height, vec![StateEvent::BlockDisconnected { height }]);
+        }
+        let mut heights = vec![];
+        for (height, events) in connected {
+            self.state.publish_events(height, events);
+            heights.push(height);
+        }
+        let proposal_error = match self.mainchain {
+            Some(_) => self.propose_bundle().err(),
+            None => None,
+        };
+        Ok(Submitted {
+            heights,
+            proposal_error,
+        })
+    }
+
+    /// Proposes the pending withdrawal bundle to the mainchain, unless this node already did,
+    /// and returns it. Once proposed, the bundle might be paid at any time, so blocks down to the
+    /// one that built it can't be disconnected anymore unless it fails, see `submit_block`.
+    pub fn propose_bundle(&self) -> Result<Option<WithdrawalBundle>, Error> {
+        let mainchain = self.mainchain.as_ref().ok_or(Error::NoMainchain)?;
+        let (height, entry) = {
+            let rotxn = self.env.read_txn()?;
+            let txn = self.store.read(&rotxn);
+            let (height, entry) = match self.state.get_pending_bundle(&txn)? {
+                Some(pending) => pending,
+                None => return Ok(None),
+            };
+            let proposed = self.state.get_proposed_bundle(&txn)?;
+            if proposed == Some((height, entry.bundle.hash())) {
+                return Ok(None);
+            }
+            (height, entry)
+        };
+        let hash = entry.bundle.hash();
+        mainchain.propose_bundle(&entry.bundle)?;
+        let mut rwtxn = self.env.write_txn()?;
+        self.state
+            .set_proposed_bundle(&mut self.store.write(&mut rwtxn), height, hash)?;
+        rwtxn.commit()?;
+        Ok(Some(entry.bundle))
+    }
+
+    fn submit_block_in(
+        &self,
+        txn: &mut impl StoreWrite,
+        header: &Header,
+        body: &Body,
+    ) -> Result<(Vec<u32>, Vec<Connected>), Error> {
+        let hash = header.hash();
+        if self.state.get_header(txn, &hash)?.is_some() {
+            return Ok((vec![], vec![]));
+        }
+        match self.state.get_header(txn, &header.prev_block_hash)? {
+            Some(parent) if parent.height + 1 == header.height => {}
+            // The genesis block of this chain was archived and returned above.
+            None if header.height == 0 && header.prev_block_hash == [0; 32] => {
+                if self.state.get_tip(txn)?.is_some() {
+                    return Err(Error::GenesisReplaced { hash });
+                }
+            }
+            Some(_) => {
+                return Err(Error::InvalidBlockHeight {
+                    height: header.height,
+                })
+            }
+            None => {
+                return Err(Error::UnknownBlock {
+                    hash: header.prev_block_hash,
+                })
+            }
+        }
+        self.state.put_block(txn, header, body)?;
+        let tip = match self.state.get_tip(txn)? {
+            Some(tip) => Some(
+                self.state
+                    .get_header(txn, &tip)?
+                    .ok_or(Error::UnknownBlock { hash: tip })?,
+            ),
+            None => None,
+        };
+        if let Some(tip) = &tip {
+            if header.height <= tip.height {
+                return Ok((vec![], vec![]));
+            }
+        }
+        let branch = self.get_branch(txn, hash, header.clone())?;
+        let fork_height = branch
+            .last()
+            .map_or(header.height, |(_, header)| header.height);
+        // Withdrawals of a proposed bundle would be bundled again on the new branch and paid
+        // twice, unless the bundle failed. A bundle that is gone from the chain was already
+        // disconnected after it failed.
+        if let Some((height, hash)) = self.state.get_proposed_bundle(txn)? {
+            let live = matches!(
+                self.state.get_bundle(txn, height)?,
+                Some(entry) if entry.bundle.hash() == hash && entry.status != BundleStatus::Failed
+            );
+            if fork_height <= height && live {
+                return Err(Error::BundleProposed { height });
+            }
+        }
+        let mut disconnected = vec![];
+        if let Some(tip) = &tip {
+            for height in (fork_height..=tip.height).rev() {
+                self.state.disconnect_body(txn, height)?;
+                txn.delete(Db::BlockHashes, &height)?;
+                disconnected.push(height);
+            }
+        }
+        let mut connected = vec![];
+        for (hash, header) in branch.into_iter().rev() {
+            let body = self
+                .state
+                .get_body(txn, &hash)?
+                .ok_or(Error::UnknownBlock { hash })?;
+            self.validate_deposits(&header.deposits)?;
+            self.validate_bundle_status(txn, header.bundle_status)?;
+            let fee_value = self.state.validate_body(txn, body.clone(), header.height)?;
+            let events = self.state.connect_body(txn, &body, header.height)?;
+            self.state
+                .connect_deposits(txn, header.height, &header.deposits)?;
+            self.state
+                .connect_bundle_status(txn, header.height, header.bundle_status)?;
+            self.state
+                .connect_oracle_fee(txn, header.height, fee_value)?;
+            if self.state.commitment(txn)? != header.commitment {
+                return Err(Error::CommitmentMismatch);
+            }
+            self.state.prune(txn, header.height, self.pruning)?;
+            if self.store_events {
+                self.state.put_events(txn, header.height, &events)?;
+            }
+            txn.put(Db::BlockHashes, &header.height, &hash)?;
+            connected.push((header.height, events));
+        }
+        self.state.set_tip(txn, &hash)?;
+        Ok((disconnected, connected))
+    }
+
+    // Sequences are checked when deposits are connected.
+    fn validate_deposits(&self, deposits: &[Deposit]) -> Result<(), Error> {
+        let first = match deposits.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let mainchain = self.mainchain.as_ref().ok_or(Error::NoMainchain)?;
+        let main_deposits = mainchain.get_deposits(first.sequence)?;
+        for (index, deposit) in deposits.iter().enumerate() {
+            if main_deposits.get(index) != Some(deposit) {
+                return Err(Error::UnknownDeposit {
+                    sequence: deposit.sequence,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Must be called before the body is connected, while the reported bundle is still pending.
+    fn validate_bundle_status(
+        &self,
+        txn: &impl StoreRead,
+        status: Option<BundleStatus>,
+    ) -> Result<(), Error> {
+        let status = match status {
+            Some(status) => status,
+            None => return Ok(()),
+        };
+        let mainchain = self.mainchain.as_ref().ok_or(Error::NoMainchain)?;
+        let (_, entry) = self
+            .state
+            .get_pending_bundle(txn)?
+            .ok_or(Error::InvalidBundleStatus)?;
+        if mainchain.get_bundle_status(&entry.bundle.hash())? != status {
+            return Err(Error::InvalidBundleStatus);
+        }
+        Ok(())
+    }
+
+    // Blocks from `hash` back to the oldest one that is not on the best chain.
+    fn get_branch(
+        &self,
+        txn: &impl StoreRead,
+        mut hash: BlockHash,
+        mut header: Header,
+    ) -> Result<Vec<(BlockHash, Header)>, Error> {
+        let mut branch = vec![];
+        while self.state.get_block_hash(txn, header.height)? != Some(hash) {
+            let prev_block_hash = header.prev_block_hash;
+            let height = header.height;
+            branch.push((hash, header));
+            if height == 0 {
+                break;
+            }
+            hash = prev_block_hash;
+            header = self
+                .state
+                .get_header(txn, &hash)?
+                .ok_or(Error::UnknownBlock { hash })?;
+        }
+        Ok(branch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{address, custom};
+    use crate::MemoryStore;
+    use hivemind_types::{block, sdk_types, HivemindContent, Transaction};
+
+    fn open(name: &str) -> ChainState {
+        let dir = std::env::temp_dir().join(format!("hivemind-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let env = heed::EnvOpenOptions::new()
+            .map_size(64 * 1024 * 1024)
+            .max_dbs(HeedStore::NUM_DBS)
+            .open(&dir)
+            .unwrap();
+        ChainState::new(env, PruningMode::Archive).unwrap()
+    }
+
+    // Each block holds a decision of `miner`, and is connected to `scratch` for its commitment.
+    fn mine(
+        state: &State,
+        scratch: &mut MemoryStore,
+        parent: Option<&Header>,
+        miner: u8,
+        length: u32,
+    ) -> Vec<(Header, Body)> {
+        let mut blocks: Vec<(Header, Body)> = vec![];
+        for _ in 0..length {
+            let parent = blocks.last().map(|(header, _)| header).or(parent);
+            let height = parent.map_or(0, |parent| parent.height + 1);
+            let decision = HivemindContent::Decision {
+                query: [miner; 32],
+                size: 2,
+                resolvable_height: height + 100,
+            };
+            let body = Body {
+                coinbase: vec![],
+                transactions: vec![Transaction {
+                    inputs: vec![],
+                    outputs: vec![custom(address(miner), decision)],
+                }],
+                authorizations: vec![],
+            };
+            state.connect_body(scratch, &body, height).unwrap();
+            let header = Header {
+                prev_block_hash: parent.map_or([0; 32], Header::hash),
+                height,
+                body_hash: block::body_hash(&body),
+                commitment: state.commitment(scratch).unwrap(),
+                deposits: vec![],
+                bundle_status: None,
+            };
+            blocks.push((header, body));
+        }
+        blocks
+    }
+
+    #[test]
+    fn reorgs_to_the_longest_chain() {
+        let chain = open("reorg");
+        let mut scratch = MemoryStore::new().unwrap();
+        let genesis = mine(&chain.state, &mut scratch, None, 1, 1).remove(0);
+        assert_eq!(
+            chain.submit_block(&genesis.0, &genesis.1).unwrap().heights,
+            vec![0]
+        );
+        let mut fork = scratch.clone();
+        let a = mine(&chain.state, &mut scratch, Some(&genesis.0), 2, 2);
+        let b = mine(&chain.state, &mut fork, Some(&genesis.0), 3, 3);
+
+        for (header, body) in &a {
+            assert_eq!(
+                chain.submit_block(header, body).unwrap().heights,
+                vec![header.height]
+            );
+        }
+        // Blocks of a branch that is not longer are only archived.
+        for (header, body) in &b[..2] {
+            assert!(chain.submit_block(header, body).unwrap().heights.is_empty());
+        }
+        assert_eq!(chain.tip().unwrap(), Some(a[1].0.clone()));
+        let events = chain.state.subscribe();
+        assert_eq!(
+            chain.submit_block(&b[2].0, &b[2].1).unwrap().heights,
+            vec![1, 2, 3]
+        );
+        let published: Vec<(u32, bool)> = events
+            .try_iter()
+            .map(|(height, events)| {
+                let disconnected = matches!(
+                    events.as_slice(),
+                    [StateEvent::BlockDisconnected { height: h }] if *h == height
+                );
+                (height, disconnected)
+            })
+            .collect();
+        // The old branch is disconnected from its tip down before the new one is connected.
+        assert_eq!(
+            published,
+            vec![(2, true), (1, true), (1, false), (2, false), (3, false)]
+        );
+        assert_eq!(chain.tip().unwrap(), Some(b[2].0.clone()));
+        {
+            let rotxn = chain.env.read_txn().unwrap();
+            let txn = chain.store.read(&rotxn);
+            assert_eq!(chain.state.commitment(&txn).unwrap(), b[2].0.commitment);
+            let outpoints = |miner| chain.state.get_address_outpoints(&txn, &address(miner));
+            assert!(outpoints(2).unwrap().is_empty());
+            assert_eq!(outpoints(3).unwrap().len(), 3);
+        }
+
+        // Nothing is written when a block of the branch is invalid.
+        let (mut header, body) = mine(&chain.state, &mut fork, Some(&b[2].0), 3, 1).remove(0);
+        header.commitment = [0; 32];
+        assert!(matches!(
+            chain.submit_block(&header, &body),
+            Err(Error::CommitmentMismatch)
+        ));
+        assert_eq!(chain.tip().unwrap(), Some(b[2].0.clone()));
+        let rotxn = chain.env.read_txn().unwrap();
+        assert_eq!(
+            chain
+                .state
+                .get_header(&chain.store.read(&rotxn), &header.hash())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn genesis_is_not_replaced() {
+        let chain = open("genesis");
+        let genesis = mine(&chain.state, &mut MemoryStore::new().unwrap(), None, 1, 1).remove(0);
+        chain.submit_block(&genesis.0, &genesis.1).unwrap();
+        let (header, body) =
+            mine(&chain.state, &mut MemoryStore::new().unwrap(), None, 2, 1).remove(0);
+        assert!(matches!(
+            chain.submit_block(&header, &body),
+            Err(Error::GenesisReplaced { hash }) if hash == header.hash()
+        ));
+        assert_eq!(chain.tip().unwrap(), Some(genesis.0));
+    }
+
+    struct RejectingMainchain;
+
+    impl Mainchain for RejectingMainchain {
+        fn get_deposits(&self, _: u64) -> Result<Vec<Deposit>, Error> {
+            Ok(vec![])
+        }
+
+        fn propose_bundle(&self, _: &WithdrawalBundle) -> Result<(), Error> {
+            Err(Error::BundleRejected)
+        }
+
+        fn get_bundle_status(&self, _: &[u8; 32]) -> Result<BundleStatus, Error> {
+            Ok(BundleStatus::Pending)
+        }
+    }
+
+    #[test]
+    fn bundle_proposal_errors_are_returned_after_the_commit() {
+        let mut chain = open("proposal");
+        chain.state.params.bundle_interval = 1;
+        chain.mainchain = Some(Box::new(RejectingMainchain));
+        let mut scratch = MemoryStore::new().unwrap();
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![Transaction {
+                inputs: vec![],
+                outputs: vec![sdk_types::Output {
+                    address: address(1),
+                    content: sdk_types::Content::Withdrawal {
+                        value: 0,
+                        main_fee: 0,
+                        main_address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+                            .parse()
+                            .unwrap(),
+                    },
+                }],
+            }],
+            authorizations: vec![],
+        };
+        chain.state.connect_body(&mut scratch, &body, 0).unwrap();
+        let header = Header {
+            prev_block_hash: [0; 32],
+            height: 0,
+            body_hash: block::body_hash(&body),
+            commitment: chain.state.commitment(&scratch).unwrap(),
+            deposits: vec![],
+            bundle_status: None,
+        };
+        let submitted = chain.submit_block(&header, &body).unwrap();
+        assert_eq!(submitted.heights, vec![0]);
+        assert!(matches!(
+            submitted.proposal_error,
+            Some(Error::BundleRejected)
+        ));
+        assert_eq!(chain.tip().unwrap(), Some(header));
+    }
+
+    #[test]
+    fn imported_snapshots_are_extended_by_the_next_block() {
+        let chain = open("snapshot");
+        let mut scratch = MemoryStore::new().unwrap();
+        let blocks = mine(&chain.state, &mut scratch, None, 1, 2);
+        for (header, body) in &blocks {
+            chain.submit_block(header, body).unwrap();
+        }
+        let mut snapshot = vec![];
+        {
+            let rotxn = chain.env.read_txn().unwrap();
+            chain
+                .state
+                .export_snapshot(&chain.store.read(&rotxn), &mut snapshot)
+                .unwrap();
+        }
+
+        let imported = open("snapshot-imported");
+        let mut rwtxn = imported.env.write_txn().unwrap();
+        imported
+            .state
+            .import_snapshot(
+                &mut imported.store.write(&mut rwtxn),
+                &snapshot[..],
+                &blocks[1].0.commitment,
+            )
+            .unwrap();
+        rwtxn.commit().unwrap();
+        assert_eq!(imported.tip().unwrap(), Some(blocks[1].0.clone()));
+        let (header, body) = mine(&chain.state, &mut scratch, Some(&blocks[1].0), 1, 1).remove(0);
+        assert_eq!(
+            imported.submit_block(&header, &body).unwrap().heights,
+            vec![2]
+        );
+        assert_eq!(imported.tip().unwrap(), Some(header));
+    }
+}
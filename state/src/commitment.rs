@@ -0,0 +1,476 @@
+use crate::{Db, Error, State, StoreRead, StoreWrite};
+use hivemind_types::{
+    merkle::{self, MerkleHash, MerkleProof, DEPTH},
+    proof::{DecisionOutcomeProof, MarketStateProof},
+    sdk_types::OutPoint,
+};
+
+// Big endian height followed by the prefix of the path, see `merkle::prefix`.
+fn node_key(height: usize, prefix: &MerkleHash) -> Vec<u8> {
+    let mut key = (height as u16).to_be_bytes().to_vec();
+    key.extend(prefix);
+    key
+}
+
+impl Db {
+    /// Returns the tree tag of entries of this database in the state commitment, `None` for
+    /// indexes and history that are not part of it.
+    pub fn commitment_tree(self) -> Option<u8> {
+        match self {
+            Db::Utxos => Some(merkle::UTXOS_TREE),
+            Db::Vectors => Some(merkle::VECTORS_TREE),
+            Db::Markets => Some(merkle::MARKETS_TREE),
+            Db::MarketToPositions => Some(merkle::MARKET_TO_POSITIONS_TREE),
+            Db::Bundles => Some(merkle::BUNDLES_TREE),
+            Db::Peg => Some(merkle::PEG_TREE),
+            Db::OraclePool => Some(merkle::ORACLE_POOL_TREE),
+            Db::VoteCoinRewards => Some(merkle::VOTECOIN_REWARDS_TREE),
+            _ => None,
+        }
+    }
+}
+
+enum Slot {
+    Empty,
+    /// Subtree with a single leaf, stored as its path followed by its leaf hash.
+    Leaf(MerkleHash, MerkleHash),
+    /// Subtree with at least two leaves, stored as its hash.
+    Node(MerkleHash),
+}
+
+impl Slot {
+    fn hash(&self) -> MerkleHash {
+        match self {
+            Slot::Empty => merkle::EMPTY,
+            Slot::Leaf(_, hash) | Slot::Node(hash) => *hash,
+        }
+    }
+}
+
+fn get_slot(
+    txn: &(impl StoreRead + ?Sized),
+    height: usize,
+    prefix: &MerkleHash,
+) -> Result<Slot, Error> {
+    let value = match txn.get_raw(Db::Commitment, &node_key(height, prefix))? {
+        Some(value) => value,
+        None => return Ok(Slot::Empty),
+    };
+    let hash = |bytes: &[u8]| -> Result<MerkleHash, Error> {
+        bytes.try_into().map_err(|_| Error::CorruptCommitment)
+    };
+    match value.len() {
+        32 => Ok(Slot::Node(hash(&value)?)),
+        64 => Ok(Slot::Leaf(hash(&value[..32])?, hash(&value[32..])?)),
+        _ => Err(Error::CorruptCommitment),
+    }
+}
+
+fn put_slot(
+    txn: &mut (impl StoreWrite + ?Sized),
+    height: usize,
+    prefix: &MerkleHash,
+    slot: &Slot,
+) -> Result<(), Error> {
+    let key = node_key(height, prefix);
+    match slot {
+        Slot::Empty => txn.delete_raw(Db::Commitment, &key),
+        Slot::Leaf(path, hash) => txn.put_raw(Db::Commitment, &key, &[*path, *hash].concat()),
+        Slot::Node(hash) => txn.put_raw(Db::Commitment, &key, hash),
+    }
+}
+
+fn parent_hash(
+    path: &MerkleHash,
+    height: usize,
+    hash: &MerkleHash,
+    sibling: &MerkleHash,
+) -> MerkleHash {
+    if merkle::is_right(path, height) {
+        merkle::node_hash(sibling, hash)
+    } else {
+        merkle::node_hash(hash, sibling)
+    }
+}
+
+/// Updates the commitment after the entry of `db` at `key` was set to `value`, or deleted if
+/// `value` is `None`.
+pub(crate) fn update_commitment(
+    txn: &mut (impl StoreWrite + ?Sized),
+    db: Db,
+    key: &[u8],
+    value: Option<&[u8]>,
+) -> Result<(), Error> {
+    let tree = match db.commitment_tree() {
+        Some(tree) => tree,
+        None => return Ok(()),
+    };
+    let path = merkle::leaf_path(tree, key);
+    let hash = value.map(|value| merkle::leaf_hash(&path, value));
+    update_leaf(txn, &path, hash)
+}
+
+/// Sets the leaf at `path` to `hash`, or removes it if `hash` is `None`, touching only the
+/// positions between the root and the leaf.
+pub(crate) fn update_leaf(
+    txn: &mut (impl StoreWrite + ?Sized),
+    path: &MerkleHash,
+    hash: Option<MerkleHash>,
+) -> Result<(), Error> {
+    // Walk down to the highest subtree of `path` with at most one leaf.
+    let mut height = DEPTH;
+    let mut slot = get_slot(txn, height, &merkle::prefix(path, height))?;
+    while let Slot::Node(_) = slot {
+        height = height.checked_sub(1).ok_or(Error::CorruptCommitment)?;
+        slot = get_slot(txn, height, &merkle::prefix(path, height))?;
+    }
+    let mut content = match (slot, hash) {
+        // Both leaves move down to the children of the node where their paths diverge.
+        (Slot::Leaf(other, other_hash), Some(hash)) if other != *path => {
+            let split = (0..height)
+                .rev()
+                .find(|height| merkle::is_right(path, *height) != merkle::is_right(&other, *height))
+                .ok_or(Error::CorruptCommitment)?;
+            put_slot(
+                txn,
+                split,
+                &merkle::prefix(path, split),
+                &Slot::Leaf(*path, hash),
+            )?;
+            put_slot(
+                txn,
+                split,
+                &merkle::prefix(&other, split),
+                &Slot::Leaf(other, other_hash),
+            )?;
+            let mut hash = parent_hash(path, split, &hash, &other_hash);
+            for height in split + 1..height {
+                put_slot(
+                    txn,
+                    height,
+                    &merkle::prefix(path, height),
+                    &Slot::Node(hash),
+                )?;
+                hash = parent_hash(path, height, &hash, &merkle::EMPTY);
+            }
+            Slot::Node(hash)
+        }
+        (_, Some(hash)) => Slot::Leaf(*path, hash),
+        (Slot::Leaf(other, _), None) if other == *path => Slot::Empty,
+        // The leaf doesn't exist.
+        (_, None) => return Ok(()),
+    };
+    // Rehash the nodes above, collapsing subtrees that are left with a single leaf.
+    for height in height..DEPTH {
+        let prefix = merkle::prefix(path, height);
+        let sibling_prefix = merkle::sibling_prefix(path, height);
+        content = match (content, get_slot(txn, height, &sibling_prefix)?) {
+            (Slot::Empty, Slot::Empty) => {
+                put_slot(txn, height, &prefix, &Slot::Empty)?;
+                Slot::Empty
+            }
+            (Slot::Empty, leaf @ Slot::Leaf(..)) => {
+                put_slot(txn, height, &prefix, &Slot::Empty)?;
+                put_slot(txn, height, &sibling_prefix, &Slot::Empty)?;
+                leaf
+            }
+            (leaf @ Slot::Leaf(..), Slot::Empty) => {
+                put_slot(txn, height, &prefix, &Slot::Empty)?;
+                leaf
+            }
+            (content, sibling) => {
+                put_slot(txn, height, &prefix, &content)?;
+                Slot::Node(parent_hash(path, height, &content.hash(), &sibling.hash()))
+            }
+        };
+    }
+    put_slot(txn, DEPTH, &[0; 32], &content)
+}
+
+impl State {
+    /// Returns the root of the sparse Merkle tree over utxos, vectors, markets,
+    /// market_to_positions, bundles and the peg. Nodes that connected the same blocks have the
+    /// same commitment.
+    pub fn commitment(&self, txn: &impl StoreRead) -> Result<MerkleHash, Error> {
+        Ok(get_slot(txn, DEPTH, &[0; 32])?.hash())
+    }
+
+    /// Returns a proof of the entry of `db` at an encoded `key`, or of its absence. Entries
+    /// deleted by pruning can't be proven either way.
+    pub fn prove(&self, txn: &impl StoreRead, db: Db, key: Vec<u8>) -> Result<MerkleProof, Error> {
+        let tree = db.commitment_tree().ok_or(Error::NotCommitted { db })?;
+        let value = txn.get_raw(db, &key)?;
+        let path = merkle::leaf_path(tree, &key);
+        let mut siblings = vec![];
+        let mut height = DEPTH;
+        let mut slot = get_slot(txn, height, &merkle::prefix(&path, height))?;
+        while let Slot::Node(_) = slot {
+            height = height.checked_sub(1).ok_or(Error::CorruptCommitment)?;
+            let sibling = get_slot(txn, height, &merkle::sibling_prefix(&path, height))?.hash();
+            siblings.push((sibling != merkle::EMPTY).then_some(sibling));
+            slot = get_slot(txn, height, &merkle::prefix(&path, height))?;
+        }
+        siblings.reverse();
+        let leaf = match slot {
+            Slot::Leaf(other, hash) if other != path => Some((other, hash)),
+            // The entry is committed but was deleted by pruning.
+            Slot::Leaf(..) if value.is_none() => return Err(Error::EntryPruned { db }),
+            _ => None,
+        };
+        Ok(MerkleProof {
+            tree,
+            key,
+            value,
+            leaf,
+            siblings,
+        })
+    }
+
+    /// Returns a proof of the utxo at `outpoint`, see `proof::verify_utxo` and
+    /// `proof::verify_position`.
+    pub fn prove_utxo(
+        &self,
+        txn: &impl StoreRead,
+        outpoint: &OutPoint,
+    ) -> Result<MerkleProof, Error> {
+        self.prove(txn, Db::Utxos, bincode::serialize(outpoint)?)
+    }
+
+    /// Returns a proof of the outcome of `decision` through the first unpruned market that uses
+    /// it, or else through its unspent Resolution output.
+    pub fn prove_decision_outcome(
+        &self,
+        txn: &impl StoreRead,
+        decision: &OutPoint,
+    ) -> Result<DecisionOutcomeProof, Error> {
+        for market in self.get_index(txn, Db::DecisionToMarkets, decision)? {
+            match self.prove(txn, Db::Markets, bincode::serialize(&market)?) {
+                Ok(proof) if proof.value.is_some() => {
+                    return Ok(DecisionOutcomeProof::Market { market, proof })
+                }
+                Ok(_) | Err(Error::EntryPruned { .. }) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        match txn.get(Db::DecisionToResolution, decision)? {
+            Some(outpoint) => Ok(DecisionOutcomeProof::Resolution {
+                outpoint,
+                proof: self.prove_utxo(txn, &outpoint)?,
+            }),
+            None => Err(Error::DecisionOutcomeUnprovable {
+                decision: *decision,
+            }),
+        }
+    }
+
+    pub fn prove_market_state(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+    ) -> Result<MarketStateProof, Error> {
+        let key = bincode::serialize(market)?;
+        Ok(MarketStateProof {
+            market: self.prove(txn, Db::Markets, key.clone())?,
+            state: self.prove(txn, Db::Vectors, key)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{custom, outpoint, value, Fixture, FUNDS};
+    use crate::MemoryStore;
+    use hivemind_types::{proof, HivemindContent, Transaction};
+
+    fn key(index: u32) -> Vec<u8> {
+        index.to_le_bytes().to_vec()
+    }
+
+    fn insert(store: &mut MemoryStore, indices: impl Iterator<Item = u32>) {
+        for index in indices {
+            let value = index.to_be_bytes();
+            store.put_raw(Db::Markets, &key(index), &value).unwrap();
+            update_commitment(store, Db::Markets, &key(index), Some(&value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn root_is_independent_of_order() {
+        let state = State::new();
+        let mut forward = MemoryStore::default();
+        insert(&mut forward, 0..100);
+        let mut backward = MemoryStore::default();
+        insert(&mut backward, (0..100).rev());
+        assert_eq!(
+            state.commitment(&forward).unwrap(),
+            state.commitment(&backward).unwrap()
+        );
+        // Only branch nodes and leaves are stored.
+        assert!(forward.prefix_iter(Db::Commitment, &[]).unwrap().count() < 300);
+
+        let mut partial = MemoryStore::default();
+        insert(&mut partial, 0..50);
+        for index in 50..100 {
+            update_commitment(&mut forward, Db::Markets, &key(index), None).unwrap();
+            forward.delete_raw(Db::Markets, &key(index)).unwrap();
+        }
+        assert_eq!(
+            state.commitment(&forward).unwrap(),
+            state.commitment(&partial).unwrap()
+        );
+        assert_eq!(
+            forward.prefix_iter(Db::Commitment, &[]).unwrap().count(),
+            partial.prefix_iter(Db::Commitment, &[]).unwrap().count()
+        );
+        for index in 0..50 {
+            update_commitment(&mut forward, Db::Markets, &key(index), None).unwrap();
+        }
+        assert_eq!(state.commitment(&forward).unwrap(), merkle::EMPTY);
+        assert!(forward.is_empty(Db::Commitment).unwrap());
+
+        // Slots that are neither a node nor a leaf are reported.
+        forward
+            .put_raw(Db::Commitment, &node_key(DEPTH, &[0; 32]), &[0; 5])
+            .unwrap();
+        assert!(matches!(
+            state.commitment(&forward),
+            Err(Error::CorruptCommitment)
+        ));
+    }
+
+    #[test]
+    fn proofs_verify() {
+        let state = State::new();
+        let mut store = MemoryStore::default();
+        insert(&mut store, 0..100);
+        let root = state.commitment(&store).unwrap();
+        for index in 0..200 {
+            let proof = state.prove(&store, Db::Markets, key(index)).unwrap();
+            assert_eq!(proof.value.is_some(), index < 100);
+            assert!(proof.verify(&root));
+            let mut forged = proof.clone();
+            forged.value = match proof.value {
+                Some(_) => None,
+                None => Some(vec![0]),
+            };
+            assert!(!forged.verify(&root));
+        }
+        assert!(matches!(
+            state.prove(&store, Db::Events, vec![]),
+            Err(Error::NotCommitted { db: Db::Events })
+        ));
+    }
+
+    #[test]
+    fn utxos_markets_and_outcomes_are_proven_against_the_commitment() {
+        let mut fixture = Fixture::new(false);
+        let (alice, alice_funds) = fixture.accounts[0];
+        let (bob, _) = fixture.accounts[1];
+        let buy = Transaction {
+            inputs: vec![alice_funds],
+            outputs: vec![fixture.position(alice, 0, 1000), value(alice, FUNDS - 1000)],
+        };
+        fixture.connect(3, vec![buy.clone()]);
+        let position = outpoint(&buy, 0);
+        let state = &fixture.state;
+        let store = &fixture.store;
+        let root = state.commitment(store).unwrap();
+        let utxo = state.prove_utxo(store, &position).unwrap();
+        assert_eq!(
+            proof::verify_utxo(&root, &position, &utxo),
+            store.get(Db::Utxos, &position).unwrap()
+        );
+        assert!(proof::verify_position(&root, &position, &alice, &fixture.market, &utxo).is_some());
+        assert!(proof::verify_position(&root, &position, &bob, &fixture.market, &utxo).is_none());
+        // Proofs are bound to their key and to spent utxos.
+        assert!(proof::verify_utxo(&root, &alice_funds, &utxo).is_none());
+        let spent = state.prove_utxo(store, &alice_funds).unwrap();
+        assert!(proof::verify_utxo(&root, &alice_funds, &spent).is_none());
+        let market = state.prove_market_state(store, &fixture.market).unwrap();
+        let (_, vector) = proof::verify_market_state(&root, &fixture.market, &market).unwrap();
+        assert_eq!(vector, fixture.vector());
+        let outcome = state
+            .prove_decision_outcome(store, &fixture.decision)
+            .unwrap();
+        assert_eq!(
+            proof::verify_decision_outcome(&root, &fixture.decision, &outcome),
+            None
+        );
+
+        fixture.resolve(100, 1);
+        let resolved = fixture.state.commitment(&fixture.store).unwrap();
+        let outcome = fixture
+            .state
+            .prove_decision_outcome(&fixture.store, &fixture.decision)
+            .unwrap();
+        assert_eq!(
+            proof::verify_decision_outcome(&resolved, &fixture.decision, &outcome),
+            Some(1)
+        );
+        assert_eq!(
+            proof::verify_decision_outcome(&root, &fixture.decision, &outcome),
+            None
+        );
+    }
+
+    #[test]
+    fn outcomes_of_decisions_without_markets_are_proven_by_their_resolution() {
+        let mut fixture = Fixture::new(false);
+        let (alice, funds) = fixture.accounts[0];
+        let decision = HivemindContent::Decision {
+            query: [1; 32],
+            size: 2,
+            resolvable_height: 4,
+        };
+        let create = Transaction {
+            inputs: vec![funds],
+            outputs: vec![custom(alice, decision), value(alice, FUNDS)],
+        };
+        fixture.connect(3, vec![create.clone()]);
+        let decision = outpoint(&create, 0);
+        assert!(matches!(
+            fixture
+                .state
+                .prove_decision_outcome(&fixture.store, &decision),
+            Err(Error::DecisionOutcomeUnprovable { .. })
+        ));
+
+        let resolution = HivemindContent::Resolution {
+            decision,
+            outcome: 1,
+        };
+        let resolve = Transaction {
+            inputs: vec![decision],
+            outputs: vec![custom(alice, resolution)],
+        };
+        fixture.connect(4, vec![resolve.clone()]);
+        let root = fixture.state.commitment(&fixture.store).unwrap();
+        let outcome = fixture
+            .state
+            .prove_decision_outcome(&fixture.store, &decision)
+            .unwrap();
+        assert!(matches!(outcome, DecisionOutcomeProof::Resolution { .. }));
+        assert_eq!(
+            proof::verify_decision_outcome(&root, &decision, &outcome),
+            Some(1)
+        );
+        // The proof is bound to its decision.
+        assert_eq!(
+            proof::verify_decision_outcome(&root, &fixture.decision, &outcome),
+            None
+        );
+
+        let spend = Transaction {
+            inputs: vec![outpoint(&resolve, 0)],
+            outputs: vec![],
+        };
+        fixture.connect(5, vec![spend]);
+        assert!(matches!(
+            fixture
+                .state
+                .prove_decision_outcome(&fixture.store, &decision),
+            Err(Error::DecisionOutcomeUnprovable { .. })
+        ));
+    }
+}
@@ -0,0 +1,542 @@
+use crate::{Db, Error, State, StoreRead, StoreWrite, UndoEntry};
+use bitcoin::hashes::Hash as _;
+use hivemind_types::{
+    drivechain::{BundleOutput, BundleStatus, Deposit, WithdrawalBundle},
+    sdk_types::{self, Address, OutPoint},
+    *,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+// Keys of the peg db.
+const DEPOSIT_SEQUENCE_KEY: &[u8] = b"deposit_sequence";
+// Height of the bundle waiting for its status from the mainchain.
+const PENDING_BUNDLE_KEY: &[u8] = b"pending_bundle";
+// Height and hash of the last bundle this node proposed to the mainchain. It is local to the
+// node and survives disconnecting the block that built the bundle.
+const PROPOSED_BUNDLE_KEY: &[u8] = b"proposed_bundle";
+
+/// A bundle built by the sidechain and what became of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleEntry {
+    pub bundle: WithdrawalBundle,
+    /// Withdrawal outputs removed by the bundle, in the same order, refunded if it fails.
+    pub withdrawals: Vec<Output>,
+    pub status: BundleStatus,
+}
+
+/// The mainchain the sidechain is pegged to.
+pub trait Mainchain {
+    /// Returns deposits to the sidechain with sequence numbers from `start`, in order.
+    fn get_deposits(&self, start: u64) -> Result<Vec<Deposit>, Error>;
+
+    /// Submits a bundle to be paid out by the mainchain.
+    fn propose_bundle(&self, bundle: &WithdrawalBundle) -> Result<(), Error>;
+
+    /// Returns the status of a bundle by its hash.
+    fn get_bundle_status(&self, hash: &[u8; 32]) -> Result<BundleStatus, Error>;
+}
+
+// Lets a mainchain be shared between a `ChainState` and whoever drives it, like a test making
+// deposits to a `MockMainchain`.
+impl<M: Mainchain + ?Sized> Mainchain for Arc<M> {
+    fn get_deposits(&self, start: u64) -> Result<Vec<Deposit>, Error> {
+        (**self).get_deposits(start)
+    }
+
+    fn propose_bundle(&self, bundle: &WithdrawalBundle) -> Result<(), Error> {
+        (**self).propose_bundle(bundle)
+    }
+
+    fn get_bundle_status(&self, hash: &[u8; 32]) -> Result<BundleStatus, Error> {
+        (**self).get_bundle_status(hash)
+    }
+}
+
+impl State {
+    /// Returns the sequence number of the next deposit to credit.
+    pub fn get_deposit_sequence(&self, txn: &impl StoreRead) -> Result<u64, Error> {
+        match txn.get_raw(Db::Peg, DEPOSIT_SEQUENCE_KEY)? {
+            Some(sequence) => Ok(bincode::deserialize(&sequence)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Credits deposits as Value outputs, continuing the sequence of credited deposits. It must
+    /// be called after the body at `height` was connected, in the same transaction, and adds its
+    /// writes to the undo data of `height`.
+    pub fn connect_deposits(
+        &self,
+        txn: &mut impl StoreWrite,
+        height: u32,
+        deposits: &[Deposit],
+    ) -> Result<(), Error> {
+        if deposits.is_empty() {
+            return Ok(());
+        }
+        let mut sequence = self.get_deposit_sequence(txn)?;
+        let mut undo = self.get_undo(txn, height)?.unwrap_or_default();
+        for deposit in deposits {
+            if deposit.sequence != sequence {
+                return Err(Error::InvalidDepositSequence {
+                    expected: sequence,
+                    found: deposit.sequence,
+                });
+            }
+            let output = Output {
+                address: deposit.address,
+                content: sdk_types::Content::Value(deposit.value),
+            };
+            self.put_utxo(
+                txn,
+                &mut undo,
+                &OutPoint::Deposit(deposit.outpoint),
+                &output,
+            )?;
+            sequence += 1;
+        }
+        let sequence = bincode::serialize(&sequence)?;
+        self.write(
+            txn,
+            &mut undo,
+            Db::Peg,
+            DEPOSIT_SEQUENCE_KEY.to_vec(),
+            Some(&sequence),
+        )?;
+        self.put_undo(txn, height, &undo)
+    }
+
+    /// Every `bundle_interval` blocks, moves up to `max_bundle_withdrawals` pending withdrawals
+    /// into a bundle, in outpoint order, and removes them from the sidechain. Only one bundle is
+    /// pending at a time, no bundle is built until the previous one is paid or failed.
+    pub(crate) fn connect_bundle(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        height: u32,
+    ) -> Result<(), Error> {
+        let interval = self.params.bundle_interval;
+        if !height.is_multiple_of(interval) || self.get_pending_bundle(txn)?.is_some() {
+            return Ok(());
+        }
+        let mut outpoints = vec![];
+        for item in txn
+            .prefix_iter(Db::PendingWithdrawals, &[])?
+            .take(self.params.max_bundle_withdrawals)
+        {
+            let (outpoint, _) = item?;
+            outpoints.push(bincode::deserialize::<OutPoint>(&outpoint)?);
+        }
+        if outpoints.is_empty() {
+            return Ok(());
+        }
+        let mut withdrawals = vec![];
+        let mut outputs = vec![];
+        let mut fee: u64 = 0;
+        for outpoint in &outpoints {
+            let output = self.get_utxo(txn, outpoint)?;
+            match &output.content {
+                sdk_types::Content::Withdrawal {
+                    value,
+                    main_fee,
+                    main_address,
+                } => {
+                    outputs.push(BundleOutput {
+                        address: main_address.clone(),
+                        value: *value,
+                    });
+                    fee += main_fee;
+                }
+                _ => unreachable!(),
+            }
+            self.delete_utxo(txn, undo, outpoint)?;
+            withdrawals.push(output);
+        }
+        let entry = BundleEntry {
+            bundle: WithdrawalBundle {
+                height,
+                withdrawals: outpoints,
+                outputs,
+                fee,
+            },
+            withdrawals,
+            status: BundleStatus::Pending,
+        };
+        self.put(txn, undo, Db::Bundles, &height, &entry)?;
+        let height = bincode::serialize(&height)?;
+        self.write(
+            txn,
+            undo,
+            Db::Peg,
+            PENDING_BUNDLE_KEY.to_vec(),
+            Some(&height),
+        )
+    }
+
+    /// Applies the final status of the pending bundle reported by the header at `height`. A
+    /// failed bundle refunds every withdrawal, value and mainchain fee, as a Value output at the
+    /// outpoint of the withdrawal. Like `connect_deposits`, it must be called after the body at
+    /// `height` was connected and adds its writes to the undo data of `height`.
+    pub fn connect_bundle_status(
+        &self,
+        txn: &mut impl StoreWrite,
+        height: u32,
+        status: Option<BundleStatus>,
+    ) -> Result<(), Error> {
+        let status = match status {
+            Some(BundleStatus::Pending) => return Err(Error::InvalidBundleStatus),
+            Some(status) => status,
+            None => return Ok(()),
+        };
+        let (bundle_height, mut entry) = self
+            .get_pending_bundle(txn)?
+            .ok_or(Error::InvalidBundleStatus)?;
+        let mut undo = self.get_undo(txn, height)?.unwrap_or_default();
+        if status == BundleStatus::Failed {
+            for (outpoint, withdrawal) in entry.bundle.withdrawals.iter().zip(&entry.withdrawals) {
+                let refund = match withdrawal.content {
+                    sdk_types::Content::Withdrawal {
+                        value, main_fee, ..
+                    } => value + main_fee,
+                    _ => unreachable!(),
+                };
+                let refund = Output {
+                    address: withdrawal.address,
+                    content: sdk_types::Content::Value(refund),
+                };
+                self.put_utxo(txn, &mut undo, outpoint, &refund)?;
+            }
+        }
+        entry.status = status;
+        self.put(txn, &mut undo, Db::Bundles, &bundle_height, &entry)?;
+        self.write(txn, &mut undo, Db::Peg, PENDING_BUNDLE_KEY.to_vec(), None)?;
+        self.put_undo(txn, height, &undo)
+    }
+
+    /// Returns the bundle built at `height`, if any.
+    pub fn get_bundle(
+        &self,
+        txn: &impl StoreRead,
+        height: u32,
+    ) -> Result<Option<BundleEntry>, Error> {
+        txn.get(Db::Bundles, &height)
+    }
+
+    /// Returns the bundle waiting for its status from the mainchain, with the height it was
+    /// built at.
+    pub fn get_pending_bundle(
+        &self,
+        txn: &impl StoreRead,
+    ) -> Result<Option<(u32, BundleEntry)>, Error> {
+        let height: u32 = match txn.get_raw(Db::Peg, PENDING_BUNDLE_KEY)? {
+            Some(height) => bincode::deserialize(&height)?,
+            None => return Ok(None),
+        };
+        let entry = self
+            .get_bundle(txn, height)?
+            .ok_or(Error::InvalidBundleStatus)?;
+        Ok(Some((height, entry)))
+    }
+
+    /// Returns the height and hash of the last bundle proposed by this node.
+    pub fn get_proposed_bundle(
+        &self,
+        txn: &impl StoreRead,
+    ) -> Result<Option<(u32, [u8; 32])>, Error> {
+        match txn.get_raw(Db::Meta, PROPOSED_BUNDLE_KEY)? {
+            Some(proposed) => Ok(Some(bincode::deserialize(&proposed)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn set_proposed_bundle(
+        &self,
+        txn: &mut impl StoreWrite,
+        height: u32,
+        hash: [u8; 32],
+    ) -> Result<(), Error> {
+        txn.put_raw(
+            Db::Meta,
+            PROPOSED_BUNDLE_KEY,
+            &bincode::serialize(&(height, hash))?,
+        )
+    }
+}
+
+/// A mainchain kept in memory, so the peg can be exercised offline. Deposits are made with
+/// `deposit`, and proposed bundles stay pending until `pay_bundles` or `fail_bundles` is called.
+/// Like on a drivechain mainchain, only one bundle can be pending at a time.
+#[derive(Default)]
+pub struct MockMainchain {
+    deposits: Mutex<Vec<Deposit>>,
+    bundles: Mutex<Vec<(WithdrawalBundle, BundleStatus)>>,
+}
+
+impl MockMainchain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes a deposit to `address` with a made up mainchain outpoint.
+    pub fn deposit(&self, address: Address, value: u64) -> Deposit {
+        let mut deposits = self.deposits.lock().unwrap();
+        let sequence = deposits.len() as u64;
+        let deposit = Deposit {
+            sequence,
+            outpoint: bitcoin::OutPoint {
+                txid: bitcoin::Txid::hash(&sequence.to_le_bytes()),
+                vout: 0,
+            },
+            address,
+            value,
+        };
+        deposits.push(deposit.clone());
+        deposit
+    }
+
+    /// Pays out the pending bundle.
+    pub fn pay_bundles(&self) {
+        self.set_pending_status(BundleStatus::Paid);
+    }
+
+    /// Fails the pending bundle, as if it expired without being paid.
+    pub fn fail_bundles(&self) {
+        self.set_pending_status(BundleStatus::Failed);
+    }
+
+    fn set_pending_status(&self, status: BundleStatus) {
+        for (_, bundle_status) in self.bundles.lock().unwrap().iter_mut() {
+            if *bundle_status == BundleStatus::Pending {
+                *bundle_status = status;
+            }
+        }
+    }
+
+    /// Returns the bundles proposed so far with their status, in the order they were proposed
+    /// in.
+    pub fn bundles(&self) -> Vec<(WithdrawalBundle, BundleStatus)> {
+        self.bundles.lock().unwrap().clone()
+    }
+
+    /// Returns the value paid out to `address` by all paid bundles.
+    pub fn paid(&self, address: &bitcoin::Address) -> u64 {
+        self.bundles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, status)| *status == BundleStatus::Paid)
+            .flat_map(|(bundle, _)| &bundle.outputs)
+            .filter(|output| output.address == *address)
+            .map(|output| output.value)
+            .sum()
+    }
+}
+
+impl Mainchain for MockMainchain {
+    fn get_deposits(&self, start: u64) -> Result<Vec<Deposit>, Error> {
+        let deposits = self.deposits.lock().unwrap();
+        let start = std::cmp::min(start as usize, deposits.len());
+        Ok(deposits[start..].to_vec())
+    }
+
+    fn propose_bundle(&self, bundle: &WithdrawalBundle) -> Result<(), Error> {
+        let mut bundles = self.bundles.lock().unwrap();
+        if bundles
+            .iter()
+            .any(|(proposed, _)| proposed.hash() == bundle.hash())
+        {
+            return Ok(());
+        }
+        if bundles
+            .iter()
+            .any(|(_, status)| *status == BundleStatus::Pending)
+        {
+            return Err(Error::BundleRejected);
+        }
+        bundles.push((bundle.clone(), BundleStatus::Pending));
+        Ok(())
+    }
+
+    fn get_bundle_status(&self, hash: &[u8; 32]) -> Result<BundleStatus, Error> {
+        Ok(self
+            .bundles
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(bundle, _)| bundle.hash() == *hash)
+            .map_or(BundleStatus::Pending, |(_, status)| *status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{address, body, outpoint, value_of};
+    use crate::{ChainParams, MemoryStore};
+    use hivemind_types::block::Header;
+
+    // Deposits 1000 at height 1 and withdraws 900 with a fee of 100 at height 2, bundled there.
+    fn withdraw(mainchain: &MockMainchain) -> (State, MemoryStore, OutPoint, bitcoin::Address) {
+        let state = State::with_params(ChainParams {
+            bundle_interval: 2,
+            ..ChainParams::default()
+        });
+        let mut store = MemoryStore::new().unwrap();
+        let deposit = mainchain.deposit(address(1), 1000);
+        state.connect_body(&mut store, &body(vec![]), 1).unwrap();
+        let sequence = state.get_deposit_sequence(&store).unwrap();
+        let deposits = mainchain.get_deposits(sequence).unwrap();
+        state.connect_deposits(&mut store, 1, &deposits).unwrap();
+        let main_address: bitcoin::Address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+            .parse()
+            .unwrap();
+        let withdrawal = Transaction {
+            inputs: vec![OutPoint::Deposit(deposit.outpoint)],
+            outputs: vec![Output {
+                address: address(1),
+                content: sdk_types::Content::Withdrawal {
+                    value: 900,
+                    main_fee: 100,
+                    main_address: main_address.clone(),
+                },
+            }],
+        };
+        state
+            .connect_body(&mut store, &body(vec![withdrawal.clone()]), 2)
+            .unwrap();
+        (state, store, outpoint(&withdrawal, 0), main_address)
+    }
+
+    #[test]
+    fn paid_bundles_pay_withdrawals_on_the_mainchain() {
+        let mainchain = MockMainchain::new();
+        let (state, mut store, withdrawal, main_address) = withdraw(&mainchain);
+        let (height, entry) = state.get_pending_bundle(&store).unwrap().unwrap();
+        assert_eq!(height, 2);
+        assert_eq!(entry.bundle.withdrawals, vec![withdrawal]);
+        assert_eq!(
+            entry.bundle.outputs,
+            vec![BundleOutput {
+                address: main_address.clone(),
+                value: 900,
+            }]
+        );
+        assert_eq!(entry.bundle.fee, 100);
+        assert!(state.get_utxo(&store, &withdrawal).is_err());
+
+        let hash = entry.bundle.hash();
+        mainchain.propose_bundle(&entry.bundle).unwrap();
+        assert_eq!(
+            mainchain.get_bundle_status(&hash).unwrap(),
+            BundleStatus::Pending
+        );
+        mainchain.pay_bundles();
+        let status = mainchain.get_bundle_status(&hash).unwrap();
+        state.connect_body(&mut store, &body(vec![]), 3).unwrap();
+        state
+            .connect_bundle_status(&mut store, 3, Some(status))
+            .unwrap();
+        assert_eq!(mainchain.paid(&main_address), 900);
+        assert_eq!(state.get_pending_bundle(&store).unwrap(), None);
+        let entry = state.get_bundle(&store, 2).unwrap().unwrap();
+        assert_eq!(entry.status, BundleStatus::Paid);
+        assert!(state.get_utxo(&store, &withdrawal).is_err());
+        // There is no pending bundle left to report a status for.
+        assert!(matches!(
+            state.connect_bundle_status(&mut store, 3, Some(BundleStatus::Paid)),
+            Err(Error::InvalidBundleStatus)
+        ));
+        // Deposits are credited once, in order.
+        let deposits = mainchain.get_deposits(0).unwrap();
+        assert!(matches!(
+            state.connect_deposits(&mut store, 3, &deposits),
+            Err(Error::InvalidDepositSequence {
+                expected: 1,
+                found: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn failed_bundles_refund_withdrawals() {
+        let mainchain = MockMainchain::new();
+        let (state, mut store, withdrawal, main_address) = withdraw(&mainchain);
+        let (_, entry) = state.get_pending_bundle(&store).unwrap().unwrap();
+        mainchain.propose_bundle(&entry.bundle).unwrap();
+        state.connect_body(&mut store, &body(vec![]), 3).unwrap();
+        assert!(matches!(
+            state.connect_bundle_status(&mut store, 3, Some(BundleStatus::Pending)),
+            Err(Error::InvalidBundleStatus)
+        ));
+        mainchain.fail_bundles();
+        let status = mainchain.get_bundle_status(&entry.bundle.hash()).unwrap();
+        assert_eq!(status, BundleStatus::Failed);
+        state
+            .connect_bundle_status(&mut store, 3, Some(status))
+            .unwrap();
+        // The mainchain fee is refunded along with the value.
+        let refund = state.get_utxo(&store, &withdrawal).unwrap();
+        assert_eq!(refund.address, address(1));
+        assert_eq!(value_of(&refund), 1000);
+        assert_eq!(mainchain.paid(&main_address), 0);
+        assert_eq!(state.get_pending_bundle(&store).unwrap(), None);
+
+        state.disconnect_body(&mut store, 3).unwrap();
+        assert!(state.get_utxo(&store, &withdrawal).is_err());
+        assert_eq!(state.get_pending_bundle(&store).unwrap(), Some((2, entry)));
+        state.disconnect_body(&mut store, 2).unwrap();
+        let deposit = OutPoint::Deposit(mainchain.get_deposits(0).unwrap()[0].outpoint);
+        assert_eq!(value_of(&state.get_utxo(&store, &deposit).unwrap()), 1000);
+        assert_eq!(state.get_bundle(&store, 2).unwrap(), None);
+        state.disconnect_body(&mut store, 1).unwrap();
+        assert_eq!(state.get_deposit_sequence(&store).unwrap(), 0);
+    }
+
+    #[test]
+    fn pending_bundles_are_imported_with_snapshots() {
+        let mainchain = MockMainchain::new();
+        let (state, mut store, withdrawal, _) = withdraw(&mainchain);
+        let commitment = state.commitment(&store).unwrap();
+        // The pending bundle is part of the commitment, so it can be checked on import.
+        let proof = state
+            .prove(&store, Db::Peg, PENDING_BUNDLE_KEY.to_vec())
+            .unwrap();
+        assert!(proof.value.is_some() && proof.verify(&commitment));
+        let tip = Header {
+            prev_block_hash: [0; 32],
+            height: 2,
+            body_hash: [0; 32],
+            commitment,
+            deposits: vec![],
+            bundle_status: None,
+        };
+        store.put(Db::Headers, &tip.hash(), &tip).unwrap();
+        state.set_tip(&mut store, &tip.hash()).unwrap();
+        let mut snapshot = vec![];
+        state.export_snapshot(&store, &mut snapshot).unwrap();
+
+        let mut imported = MemoryStore::new().unwrap();
+        state
+            .import_snapshot(&mut imported, &snapshot[..], &commitment)
+            .unwrap();
+        assert_eq!(state.get_deposit_sequence(&imported).unwrap(), 1);
+        assert_eq!(
+            state.get_pending_bundle(&imported).unwrap(),
+            state.get_pending_bundle(&store).unwrap()
+        );
+        // The imported state refunds the failed bundle like the one it was exported from.
+        for store in [&mut store, &mut imported] {
+            state.connect_body(store, &body(vec![]), 3).unwrap();
+            state
+                .connect_bundle_status(store, 3, Some(BundleStatus::Failed))
+                .unwrap();
+            assert_eq!(value_of(&state.get_utxo(store, &withdrawal).unwrap()), 1000);
+            assert_eq!(state.get_pending_bundle(store).unwrap(), None);
+        }
+        assert_eq!(
+            state.commitment(&imported).unwrap(),
+            state.commitment(&store).unwrap()
+        );
+        assert_ne!(state.commitment(&store).unwrap(), commitment);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::{get_votecoin_value, Db, Error, State, StoreRead, StoreWrite, UndoEntry};
+use hivemind_types::{
+    sdk_types::{self, GetValue as _, OutPoint},
+    *,
+};
+use serde::{Deserialize, Serialize};
+
+/// New value a coinbase may create on top of fees, by height.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Emission {
+    /// Coinbases only collect fees, for sidechains whose value only comes from deposits.
+    #[default]
+    None,
+    /// `initial` per block, halved every `interval` blocks. It never halves if `interval` is 0.
+    Halving { initial: u64, interval: u32 },
+}
+
+impl Emission {
+    pub fn subsidy(&self, height: u32) -> u64 {
+        match self {
+            Emission::None => 0,
+            Emission::Halving { initial, interval } => {
+                let halvings = height.checked_div(*interval).unwrap_or(0);
+                initial.checked_shr(halvings).unwrap_or(0)
+            }
+        }
+    }
+}
+
+/// Share of the fees of every block that goes to the oracle pool instead of the miner, to reward
+/// VoteCoin holders for resolving decisions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OracleFeeShare {
+    /// Share of fees in basis points, rounded down.
+    pub basis_points: u32,
+}
+
+impl OracleFeeShare {
+    pub fn fee(&self, fee_value: u64) -> u64 {
+        (fee_value as u128 * u128::from(self.basis_points.min(10_000)) / 10_000) as u64
+    }
+}
+
+pub(crate) const ORACLE_POOL_KEY: &[u8] = b"oracle_pool";
+
+const REWARD_SCALE: u128 = 1_000_000_000_000_000_000;
+
+/// Oracle fees not yet claimed by VoteCoin holders.
+///
+/// Every unit of VoteCoin earns an equal share of every oracle fee accrued while it is unspent,
+/// and its reward is claimed by spending it. Fees accrued while no VoteCoin exists are never
+/// claimed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OraclePool {
+    /// Value held by the pool.
+    pub value: u64,
+    /// VoteCoin in all unspent outputs.
+    pub votecoin: u64,
+    /// Oracle fees accrued per unit of VoteCoin since genesis, times `REWARD_SCALE`.
+    pub reward_per_votecoin: u128,
+}
+
+impl State {
+    pub fn get_oracle_pool(&self, txn: &impl StoreRead) -> Result<OraclePool, Error> {
+        match txn.get_raw(Db::OraclePool, ORACLE_POOL_KEY)? {
+            Some(pool) => Ok(bincode::deserialize(&pool)?),
+            None => Ok(OraclePool::default()),
+        }
+    }
+
+    fn put_oracle_pool(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        pool: &OraclePool,
+    ) -> Result<(), Error> {
+        let pool = bincode::serialize(pool)?;
+        self.write(
+            txn,
+            undo,
+            Db::OraclePool,
+            ORACLE_POOL_KEY.to_vec(),
+            Some(&pool),
+        )
+    }
+
+    /// Returns the oracle fees a VoteCoin output claims when it is spent.
+    pub fn get_votecoin_reward(
+        &self,
+        txn: &impl StoreRead,
+        outpoint: &OutPoint,
+        output: &Output,
+    ) -> Result<u64, Error> {
+        let votecoin = get_votecoin_value(output);
+        if votecoin == 0 {
+            return Ok(0);
+        }
+        let created: u128 = txn.get(Db::VoteCoinRewards, outpoint)?.unwrap_or(0);
+        let pool = self.get_oracle_pool(txn)?;
+        let reward = pool
+            .reward_per_votecoin
+            .checked_sub(created)
+            .and_then(|accrued| accrued.checked_mul(u128::from(votecoin)))
+            .ok_or(Error::OracleRewardOverflow)?
+            / REWARD_SCALE;
+        u64::try_from(reward).map_err(|_| Error::OracleRewardOverflow)
+    }
+
+    /// Adds VoteCoin of an output created at `outpoint` to the pool, or pays out its reward and
+    /// removes it if `add` is false.
+    pub(crate) fn update_oracle_pool(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        outpoint: &OutPoint,
+        output: &Output,
+        add: bool,
+    ) -> Result<(), Error> {
+        let votecoin = get_votecoin_value(output);
+        if votecoin == 0 {
+            return Ok(());
+        }
+        let mut pool = self.get_oracle_pool(txn)?;
+        if add {
+            pool.votecoin = pool
+                .votecoin
+                .checked_add(votecoin)
+                .ok_or(Error::OracleRewardOverflow)?;
+            self.put(
+                txn,
+                undo,
+                Db::VoteCoinRewards,
+                outpoint,
+                &pool.reward_per_votecoin,
+            )?;
+        } else {
+            // Rewards are rounded down, so together they never exceed the pool.
+            let reward = self.get_votecoin_reward(txn, outpoint, output)?;
+            pool.value = pool.value.saturating_sub(reward);
+            pool.votecoin = pool.votecoin.saturating_sub(votecoin);
+            self.delete(txn, undo, Db::VoteCoinRewards, outpoint)?;
+        }
+        self.put_oracle_pool(txn, undo, &pool)
+    }
+
+    /// Accrues the oracle share of `fee_value` to the pool. It must be called after the body at
+    /// `height` was connected, in the same transaction, and adds its writes to the undo data of
+    /// `height`.
+    pub fn connect_oracle_fee(
+        &self,
+        txn: &mut impl StoreWrite,
+        height: u32,
+        fee_value: u64,
+    ) -> Result<(), Error> {
+        let oracle_fee = match &self.params.oracle_fee_share {
+            Some(share) => share.fee(fee_value),
+            None => 0,
+        };
+        if oracle_fee == 0 {
+            return Ok(());
+        }
+        let mut undo = self.get_undo(txn, height)?.unwrap_or_default();
+        let mut pool = self.get_oracle_pool(txn)?;
+        pool.value = pool
+            .value
+            .checked_add(oracle_fee)
+            .ok_or(Error::OracleRewardOverflow)?;
+        if pool.votecoin > 0 {
+            let reward = u128::from(oracle_fee) * REWARD_SCALE / u128::from(pool.votecoin);
+            pool.reward_per_votecoin = pool
+                .reward_per_votecoin
+                .checked_add(reward)
+                .ok_or(Error::OracleRewardOverflow)?;
+        }
+        self.put_oracle_pool(txn, &mut undo, &pool)?;
+        self.put_undo(txn, height, &undo)
+    }
+
+    /// Checks that a coinbase only holds value and creates at most the subsidy at `height` plus
+    /// what `fee_value` leaves after the oracle fee.
+    pub fn validate_coinbase(
+        &self,
+        coinbase: &[Output],
+        height: u32,
+        fee_value: u64,
+    ) -> Result<(), Error> {
+        let mut coinbase_value: u64 = 0;
+        for output in coinbase {
+            if !matches!(output.content, sdk_types::Content::Value(_)) {
+                return Err(Error::InvalidCoinbaseOutput);
+            }
+            coinbase_value = coinbase_value
+                .checked_add(output.get_value())
+                .ok_or(Error::NotEnoughFeeValue)?;
+        }
+        let oracle_fee = match &self.params.oracle_fee_share {
+            Some(share) => share.fee(fee_value),
+            None => 0,
+        };
+        let subsidy = self.params.emission.subsidy(height);
+        if coinbase_value > subsidy.saturating_add(fee_value - oracle_fee) {
+            return Err(Error::NotEnoughFeeValue);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn connect_coinbase(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        body: &Body,
+    ) -> Result<(), Error> {
+        let merkle_root = body.compute_merkle_root();
+        for (vout, output) in body.coinbase.iter().enumerate() {
+            let outpoint = OutPoint::Coinbase {
+                merkle_root,
+                vout: vout as u32,
+            };
+            self.put_utxo(txn, undo, &outpoint, output)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{address, custom, outpoint, value};
+    use crate::{ChainParams, MemoryStore};
+
+    #[test]
+    fn subsidies_halve_every_interval() {
+        let emission = Emission::Halving {
+            initial: 100,
+            interval: 10,
+        };
+        assert_eq!(emission.subsidy(0), 100);
+        assert_eq!(emission.subsidy(9), 100);
+        assert_eq!(emission.subsidy(10), 50);
+        assert_eq!(emission.subsidy(25), 25);
+        // Once every bit is shifted out, and past 64 halvings, nothing is created.
+        assert_eq!(emission.subsidy(70), 0);
+        assert_eq!(emission.subsidy(u32::MAX), 0);
+        let constant = Emission::Halving {
+            initial: 100,
+            interval: 0,
+        };
+        assert_eq!(constant.subsidy(u32::MAX), 100);
+        assert_eq!(Emission::None.subsidy(0), 0);
+    }
+
+    #[test]
+    fn coinbases_create_at_most_the_subsidy_plus_fees_left_by_the_oracle_fee() {
+        let state = State::with_params(ChainParams {
+            emission: Emission::Halving {
+                initial: 100,
+                interval: 10,
+            },
+            oracle_fee_share: Some(OracleFeeShare {
+                basis_points: 2_500,
+            }),
+            ..ChainParams::default()
+        });
+        // The oracle fee is 10, a quarter of fees and none of the subsidy.
+        let coinbase = vec![value(address(5), 100), value(address(1), 30)];
+        assert!(state.validate_coinbase(&coinbase, 0, 40).is_ok());
+        let coinbase = vec![value(address(5), 131)];
+        assert!(matches!(
+            state.validate_coinbase(&coinbase, 0, 40),
+            Err(Error::NotEnoughFeeValue)
+        ));
+        // After a halving the subsidy is 50.
+        assert!(state
+            .validate_coinbase(&[value(address(5), 80)], 10, 40)
+            .is_ok());
+        assert!(matches!(
+            state.validate_coinbase(&[value(address(5), 81)], 10, 40),
+            Err(Error::NotEnoughFeeValue)
+        ));
+        let overflowing = vec![value(address(5), u64::MAX), value(address(1), 10)];
+        assert!(matches!(
+            state.validate_coinbase(&overflowing, 0, 40),
+            Err(Error::NotEnoughFeeValue)
+        ));
+        let votecoin = custom(address(1), HivemindContent::VoteCoin { value: 1 });
+        assert!(matches!(
+            state.validate_coinbase(&[votecoin], 0, 40),
+            Err(Error::InvalidCoinbaseOutput)
+        ));
+    }
+
+    #[test]
+    fn oracle_fees_are_claimed_by_spending_votecoin() {
+        let state = State::with_params(ChainParams {
+            oracle_fee_share: Some(OracleFeeShare {
+                basis_points: 2_500,
+            }),
+            ..ChainParams::default()
+        });
+        let mut store = MemoryStore::new().unwrap();
+        let votecoin = |holder, value| custom(address(holder), HivemindContent::VoteCoin { value });
+        let genesis = Transaction {
+            inputs: vec![],
+            outputs: vec![votecoin(1, 3), votecoin(2, 1)],
+        };
+        let outpoints = [outpoint(&genesis, 0), outpoint(&genesis, 1)];
+        let mut undo = vec![];
+        for (outpoint, output) in outpoints.iter().zip(&genesis.outputs) {
+            state
+                .put_utxo(&mut store, &mut undo, outpoint, output)
+                .unwrap();
+        }
+        state.put_undo(&mut store, 0, &undo).unwrap();
+        // Fees accrued while VoteCoin is unspent are split 3 to 1, rounded down.
+        state.connect_oracle_fee(&mut store, 0, 40).unwrap();
+        assert_eq!(state.get_oracle_pool(&store).unwrap().value, 10);
+        let rewards = |store: &MemoryStore| -> Vec<u64> {
+            outpoints
+                .iter()
+                .zip(&genesis.outputs)
+                .map(|(outpoint, output)| {
+                    state.get_votecoin_reward(store, outpoint, output).unwrap()
+                })
+                .collect()
+        };
+        assert_eq!(rewards(&store), [7, 2]);
+
+        let claim = |reward| Transaction {
+            inputs: vec![outpoints[0]],
+            outputs: vec![votecoin(1, 3), value(address(1), reward)],
+        };
+        let filled = state.fill_transaction(&store, &claim(7)).unwrap();
+        assert_eq!(state.validate_transaction(&store, &filled, 1).unwrap(), 0);
+        let filled = state.fill_transaction(&store, &claim(8)).unwrap();
+        assert!(matches!(
+            state.validate_transaction(&store, &filled, 1),
+            Err(Error::NotEnoughValueIn)
+        ));
+
+        // Spending VoteCoin pays its reward out of the pool, and VoteCoin created later only
+        // earns fees accrued after it.
+        let mut undo = vec![];
+        state
+            .delete_utxo(&mut store, &mut undo, &outpoints[0])
+            .unwrap();
+        let claimed = outpoint(&claim(7), 0);
+        state
+            .put_utxo(&mut store, &mut undo, &claimed, &votecoin(1, 3))
+            .unwrap();
+        state.put_undo(&mut store, 1, &undo).unwrap();
+        let pool = state.get_oracle_pool(&store).unwrap();
+        assert_eq!((pool.value, pool.votecoin), (3, 4));
+        assert_eq!(
+            state
+                .get_votecoin_reward(&store, &claimed, &votecoin(1, 3))
+                .unwrap(),
+            0
+        );
+        state.connect_oracle_fee(&mut store, 1, 40).unwrap();
+        assert_eq!(
+            state
+                .get_votecoin_reward(&store, &claimed, &votecoin(1, 3))
+                .unwrap(),
+            7
+        );
+        assert_eq!(
+            state
+                .get_votecoin_reward(&store, &outpoints[1], &votecoin(2, 1))
+                .unwrap(),
+            5
+        );
+
+        state.disconnect_body(&mut store, 1).unwrap();
+        assert_eq!(state.get_oracle_pool(&store).unwrap().value, 10);
+        assert_eq!(rewards(&store), [7, 2]);
+        // Without VoteCoin fees still accrue to the pool, but nobody can claim them.
+        state.disconnect_body(&mut store, 0).unwrap();
+        state.connect_oracle_fee(&mut store, 0, 40).unwrap();
+        let pool = state.get_oracle_pool(&store).unwrap();
+        assert_eq!(
+            (pool.value, pool.votecoin, pool.reward_per_votecoin),
+            (10, 0, 0)
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{Db, Error, State, StoreRead, StoreWrite};
+use hivemind_types::{
+    rust_decimal::Decimal,
+    sdk_types::{self, OutPoint},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{mpsc, Arc};
+
+/// Something that happened while connecting a body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StateEvent {
+    MarketCreated {
+        market: OutPoint,
+    },
+    /// Net delta of a single transaction in one market and what it cost, negative for sales.
+    Trade {
+        market: OutPoint,
+        txid: sdk_types::Txid,
+        delta: Vec<Decimal>,
+        cost: Decimal,
+    },
+    DecisionResolved {
+        decision: OutPoint,
+        outcome: u32,
+    },
+    MarketResolved {
+        market: OutPoint,
+        outcomes: Vec<u32>,
+    },
+    /// A position of a resolved market turned into a Value output.
+    PositionPaidOut {
+        market: OutPoint,
+        position: OutPoint,
+        value: u64,
+    },
+    /// A position of a resolved market with no winning shares was removed.
+    PositionVoided {
+        market: OutPoint,
+        position: OutPoint,
+    },
+    /// The body connected at `height` was disconnected in a reorg, undoing all events published
+    /// for it. It is published alone, at `height`, before the bodies of the new branch.
+    BlockDisconnected {
+        height: u32,
+    },
+}
+
+impl State {
+    /// Returns a receiver of events of every body published with `publish_events`.
+    pub fn subscribe(&self) -> mpsc::Receiver<(u32, Arc<Vec<StateEvent>>)> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends events of the body connected at `height` to all subscribers. It should be called
+    /// after the transaction that connected the body is committed.
+    pub fn publish_events(&self, height: u32, events: Vec<StateEvent>) {
+        let events = Arc::new(events);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send((height, events.clone())).is_ok());
+    }
+
+    /// Stores events of the body connected at `height`, they are removed when it is
+    /// disconnected.
+    pub fn put_events(
+        &self,
+        txn: &mut impl StoreWrite,
+        height: u32,
+        events: &[StateEvent],
+    ) -> Result<(), Error> {
+        txn.put(Db::Events, &height, &events)
+    }
+
+    pub fn get_events(&self, txn: &impl StoreRead, height: u32) -> Result<Vec<StateEvent>, Error> {
+        Ok(txn.get(Db::Events, &height)?.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{outpoint, value, Fixture, FUNDS};
+    use hivemind_types::Transaction;
+
+    #[test]
+    fn events_are_published_and_stored_until_disconnected() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let receiver = fixture.state.subscribe();
+        let (alice, funds) = fixture.accounts[0];
+        let buy = Transaction {
+            inputs: vec![funds],
+            outputs: vec![fixture.position(alice, 0, 1000), value(alice, FUNDS - 1000)],
+        };
+        let events = fixture.connect(3, vec![buy.clone()]);
+        assert!(matches!(
+            &events[..],
+            [StateEvent::Trade { market: m, txid, .. }] if *m == market && *txid == buy.txid()
+        ));
+        fixture.resolve(100, 0);
+        let position = outpoint(&buy, 0);
+        let resolved = vec![
+            StateEvent::DecisionResolved {
+                decision: fixture.decision,
+                outcome: 0,
+            },
+            StateEvent::MarketResolved {
+                market,
+                outcomes: vec![0],
+            },
+            StateEvent::PositionPaidOut {
+                market,
+                position,
+                value: 1000,
+            },
+        ];
+        let published: Vec<(u32, Vec<StateEvent>)> = receiver
+            .try_iter()
+            .map(|(height, events)| (height, events.to_vec()))
+            .collect();
+        assert_eq!(published, vec![(3, events), (100, resolved.clone())]);
+        // Dropped subscribers are forgotten, and new ones only receive later events.
+        drop(receiver);
+        let late = fixture.state.subscribe();
+        fixture.connect(101, vec![]);
+        let heights: Vec<u32> = late.try_iter().map(|(height, _)| height).collect();
+        assert_eq!(heights, vec![101]);
+
+        let store = &mut fixture.store;
+        assert_eq!(fixture.state.get_events(store, 100).unwrap(), resolved);
+        fixture.state.disconnect_body(store, 100).unwrap();
+        assert!(fixture.state.get_events(store, 100).unwrap().is_empty());
+        assert_eq!(fixture.state.get_events(store, 3).unwrap().len(), 1);
+    }
+}
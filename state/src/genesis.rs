@@ -0,0 +1,299 @@
+use crate::{Db, Emission, Error, OracleFeeShare, State, StoreRead, StoreWrite};
+use hivemind_types::{
+    block::{BlockHash, Header},
+    sdk_types::{self, Address, OutPoint},
+    *,
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+const GENESIS_KEY: &[u8] = b"genesis";
+
+/// Parameters of a chain, fixed by its genesis config.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChainParams {
+    /// Distinguishes chains started from otherwise identical configs.
+    pub chain_id: String,
+    /// Minimum fee for transactions that are not fee exempt.
+    pub min_fee: u64,
+    pub max_fee_exempt_transactions: usize,
+    pub emission: Emission,
+    /// Share of fees paid to the oracle reward pool, if any.
+    pub oracle_fee_share: Option<OracleFeeShare>,
+    /// Blocks between withdrawal bundles, at least 1.
+    pub bundle_interval: u32,
+    pub max_bundle_withdrawals: usize,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self {
+            chain_id: String::new(),
+            // Without a minimum fee, fee exemption only matters once one is set.
+            min_fee: 0,
+            // Fee exempt transactions are limited per block so they can't be used for spam.
+            max_fee_exempt_transactions: 100,
+            emission: Emission::None,
+            oracle_fee_share: None,
+            // About a day of mainchain blocks.
+            bundle_interval: 144,
+            max_bundle_withdrawals: 1000,
+        }
+    }
+}
+
+impl ChainParams {
+    /// Rejects params a chain can't run with.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.bundle_interval == 0 {
+            return Err(Error::InvalidBundleInterval);
+        }
+        Ok(())
+    }
+}
+
+/// An initial output holding either `value` or `reputation`, as VoteCoin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisOutput {
+    pub address: Address,
+    #[serde(default)]
+    pub value: u64,
+    #[serde(default)]
+    pub reputation: u64,
+}
+
+/// Chain parameters and initial allocation, read from TOML or JSON:
+///
+/// ```toml
+/// [params]
+/// chain_id = "hivemind-testnet"
+/// emission = { halving = { initial = 5000000000, interval = 210000 } }
+///
+/// [[outputs]]
+/// address = [...]
+/// value = 100000000
+///
+/// [[outputs]]
+/// address = [...]
+/// reputation = 1000
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisConfig {
+    #[serde(default)]
+    pub params: ChainParams,
+    #[serde(default)]
+    pub outputs: Vec<GenesisOutput>,
+}
+
+impl GenesisConfig {
+    pub fn from_toml(config: &str) -> Result<Self, Error> {
+        let config: Self = toml::from_str(config)?;
+        config.params.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_json(config: &str) -> Result<Self, Error> {
+        let config: Self = serde_json::from_str(config)?;
+        config.params.validate()?;
+        Ok(config)
+    }
+
+    /// Reads a config from a file, as JSON if its extension is `json` and as TOML otherwise.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let config = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Self::from_json(&config),
+            _ => Self::from_toml(&config),
+        }
+    }
+
+    /// Returns the blake3 hash of the encoded config. It is the body hash of the genesis header,
+    /// so the genesis hash only depends on the config.
+    pub fn hash(&self) -> Result<[u8; 32], Error> {
+        Ok(*blake3::hash(&bincode::serialize(self)?).as_bytes())
+    }
+
+    /// Returns a transaction without inputs creating the initial outputs, in the order they are
+    /// listed in.
+    pub fn transaction(&self) -> Result<Transaction, Error> {
+        let mut outputs = vec![];
+        for (index, output) in self.outputs.iter().enumerate() {
+            let content = match (output.value, output.reputation) {
+                (value, 0) if value > 0 => sdk_types::Content::Value(value),
+                (0, value) if value > 0 => {
+                    sdk_types::Content::Custom(HivemindContent::VoteCoin { value })
+                }
+                _ => return Err(Error::InvalidGenesisOutput { index }),
+            };
+            outputs.push(Output {
+                address: output.address,
+                content,
+            });
+        }
+        Ok(Transaction {
+            inputs: vec![],
+            outputs,
+        })
+    }
+}
+
+impl State {
+    /// Creates the initial outputs of `config` in an empty state and archives the genesis header
+    /// as the tip at height 0, so the first body is connected at height 1. The state should be
+    /// created with the params of `config`.
+    ///
+    /// Genesis can't be disconnected, no undo data is stored for it.
+    pub fn connect_genesis(
+        &self,
+        txn: &mut impl StoreWrite,
+        config: &GenesisConfig,
+    ) -> Result<Header, Error> {
+        if self.get_tip(txn)?.is_some() || !txn.is_empty(Db::Utxos)? {
+            return Err(Error::StateNotEmpty);
+        }
+        config.params.validate()?;
+        let transaction = config.transaction()?;
+        let txid = transaction.txid();
+        let mut undo = vec![];
+        for (vout, output) in transaction.outputs.iter().enumerate() {
+            let outpoint = OutPoint::Regular {
+                txid,
+                vout: vout as u32,
+            };
+            self.put_utxo(txn, &mut undo, &outpoint, output)?;
+        }
+        let header = Header {
+            prev_block_hash: [0; 32],
+            height: 0,
+            body_hash: config.hash()?,
+            commitment: self.commitment(txn)?,
+            deposits: vec![],
+            bundle_status: None,
+        };
+        let hash: BlockHash = header.hash();
+        txn.put(Db::Headers, &hash, &header)?;
+        txn.put(Db::BlockHashes, &header.height, &hash)?;
+        self.set_tip(txn, &hash)?;
+        txn.put_raw(Db::Meta, GENESIS_KEY, &bincode::serialize(config)?)?;
+        Ok(header)
+    }
+
+    /// Returns the config the state was started from, `None` if it has no genesis.
+    pub fn get_genesis(&self, txn: &impl StoreRead) -> Result<Option<GenesisConfig>, Error> {
+        match txn.get_raw(Db::Meta, GENESIS_KEY)? {
+            Some(config) => Ok(Some(bincode::deserialize(&config)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{address, body, custom, value};
+    use crate::MemoryStore;
+
+    #[test]
+    fn genesis_configs_are_read_from_toml_and_json() {
+        let bytes = |n: u8| format!("{:?}", [n; 32]);
+        let toml = format!(
+            "[params]\nchain_id = \"test\"\nmin_fee = 10\n\n\
+             [[outputs]]\naddress = {}\nvalue = 1000\n\n\
+             [[outputs]]\naddress = {}\nreputation = 50\n",
+            bytes(1),
+            bytes(2)
+        );
+        let json = format!(
+            r#"{{"params": {{"chain_id": "test", "min_fee": 10}},
+                "outputs": [{{"address": {}, "value": 1000}}, {{"address": {}, "reputation": 50}}]}}"#,
+            bytes(1),
+            bytes(2)
+        );
+        let config = GenesisConfig::from_toml(&toml).unwrap();
+        assert_eq!(GenesisConfig::from_json(&json).unwrap(), config);
+        // Params that are left out keep their defaults.
+        assert_eq!(
+            config.params,
+            ChainParams {
+                chain_id: "test".to_string(),
+                min_fee: 10,
+                ..ChainParams::default()
+            }
+        );
+        assert_eq!(
+            config.transaction().unwrap().outputs,
+            vec![
+                value(address(1), 1000),
+                custom(address(2), HivemindContent::VoteCoin { value: 50 }),
+            ]
+        );
+        let invalid = GenesisConfig {
+            params: ChainParams::default(),
+            outputs: vec![GenesisOutput {
+                address: address(1),
+                value: 1000,
+                reputation: 50,
+            }],
+        };
+        assert!(matches!(
+            invalid.transaction(),
+            Err(Error::InvalidGenesisOutput { index: 0 })
+        ));
+        assert!(matches!(
+            GenesisConfig::from_toml("[params]\nbundle_interval = 0\n"),
+            Err(Error::InvalidBundleInterval)
+        ));
+    }
+
+    #[test]
+    fn genesis_creates_the_initial_outputs_at_height_0() {
+        let config = GenesisConfig {
+            params: ChainParams {
+                min_fee: 10,
+                ..ChainParams::default()
+            },
+            outputs: vec![GenesisOutput {
+                address: address(1),
+                value: 1000,
+                reputation: 0,
+            }],
+        };
+        let state = State::with_params(config.params.clone());
+        let mut store = MemoryStore::new().unwrap();
+        let header = state.connect_genesis(&mut store, &config).unwrap();
+        assert_eq!(header.height, 0);
+        assert_eq!(header.body_hash, config.hash().unwrap());
+        assert_eq!(header.commitment, state.commitment(&store).unwrap());
+        assert_eq!(state.get_tip(&store).unwrap(), Some(header.hash()));
+        assert_eq!(
+            state.get_block_hash(&store, 0).unwrap(),
+            Some(header.hash())
+        );
+        assert_eq!(state.get_genesis(&store).unwrap(), Some(config.clone()));
+        assert!(matches!(
+            state.connect_genesis(&mut store, &config),
+            Err(Error::StateNotEmpty)
+        ));
+
+        // The minimum fee of the config applies to the first block.
+        let funds = OutPoint::Regular {
+            txid: config.transaction().unwrap().txid(),
+            vout: 0,
+        };
+        let spend = |value_out| Transaction {
+            inputs: vec![funds],
+            outputs: vec![value(address(1), value_out)],
+        };
+        assert!(matches!(
+            state.validate_transactions(&store, &body(vec![spend(995)]), 1),
+            Err(Error::FeeTooLow { fee: 5 })
+        ));
+        assert_eq!(
+            state
+                .validate_transactions(&store, &body(vec![spend(990)]), 1)
+                .unwrap(),
+            10
+        );
+    }
+}
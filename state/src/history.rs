@@ -0,0 +1,271 @@
+use crate::{Db, Error, State, StoreRead, StoreWrite, UndoEntry};
+use hivemind_types::{
+    nalgebra::DVector, rust_decimal::prelude::*, rust_decimal_macros::dec, sdk_types::OutPoint, *,
+};
+use serde::{Deserialize, Serialize};
+
+/// State of a market after a block that traded on it, the net delta of that block, and the
+/// gross number of shares its transactions traded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarketSnapshot {
+    pub state: Vec<Decimal>,
+    pub delta: Vec<Decimal>,
+    pub volume: Vec<Decimal>,
+}
+
+/// Price of a share over an interval of blocks, with the number of shares traded in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candle {
+    pub start_height: u32,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+}
+
+// Big endian heights keep the snapshots of a market ordered by height.
+fn history_key(market: &OutPoint, height: u32) -> Result<Vec<u8>, Error> {
+    let mut key = bincode::serialize(market)?;
+    key.extend(height.to_be_bytes());
+    Ok(key)
+}
+
+impl State {
+    pub(crate) fn put_snapshot(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        market: &OutPoint,
+        height: u32,
+        snapshot: &MarketSnapshot,
+    ) -> Result<(), Error> {
+        let key = history_key(market, height)?;
+        let value = bincode::serialize(snapshot)?;
+        self.write(txn, undo, Db::MarketHistory, key, Some(&value))
+    }
+
+    /// Returns snapshots of a market at heights `from..=to`.
+    pub fn get_snapshots(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+        from: u32,
+        to: u32,
+    ) -> Result<Vec<(u32, MarketSnapshot)>, Error> {
+        let start = history_key(market, from)?;
+        let end = history_key(market, to)?;
+        let prefix_len = start.len() - 4;
+        let mut snapshots = vec![];
+        for item in txn.range(Db::MarketHistory, &start, &end, false)? {
+            let (key, snapshot) = item?;
+            let snapshot = bincode::deserialize(&snapshot)?;
+            let height = key[prefix_len..]
+                .try_into()
+                .map_err(|_| Error::CorruptIndex {
+                    db: Db::MarketHistory,
+                })?;
+            snapshots.push((u32::from_be_bytes(height), snapshot));
+        }
+        Ok(snapshots)
+    }
+
+    /// Returns the state of a market as it was after the block at `height`.
+    pub fn get_state_at(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+        height: u32,
+    ) -> Result<Option<Vec<Decimal>>, Error> {
+        let start = history_key(market, 0)?;
+        let end = history_key(market, height)?;
+        let mut range = txn.range(Db::MarketHistory, &start, &end, true)?;
+        match range.next() {
+            Some(item) => {
+                let snapshot: MarketSnapshot = bincode::deserialize(&item?.1)?;
+                Ok(Some(snapshot.state))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the price of a share after every block in `from..=to` that traded on its market.
+    pub fn get_price_series(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+        share: &[u32],
+        from: u32,
+        to: u32,
+    ) -> Result<Vec<(u32, Decimal)>, Error> {
+        let b = self.get_b(txn, market)?;
+        let flat_indices = self.share_to_flat_index(txn, market, share)?;
+        let mut prices = vec![];
+        for (height, snapshot) in self.get_snapshots(txn, market, from, to)? {
+            let state = DVector::from(snapshot.state);
+            prices.push((height, lmsr_price(b, &state, &flat_indices)));
+        }
+        Ok(prices)
+    }
+
+    /// Returns OHLC candles of a share over intervals of `interval` blocks starting at `from`.
+    /// Intervals without trades repeat the previous close.
+    pub fn get_candles(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+        share: &[u32],
+        from: u32,
+        to: u32,
+        interval: u32,
+    ) -> Result<Vec<Candle>, Error> {
+        let interval = interval.max(1);
+        let b = self.get_b(txn, market)?;
+        let flat_indices = self.share_to_flat_index(txn, market, share)?;
+        let price = |state: Vec<Decimal>| lmsr_price(b, &DVector::from(state), &flat_indices);
+        let mut close = match from.checked_sub(1) {
+            Some(height) => self.get_state_at(txn, market, height)?.map(price),
+            None => None,
+        };
+        let mut snapshots = self
+            .get_snapshots(txn, market, from, to)?
+            .into_iter()
+            .peekable();
+        let mut candles = vec![];
+        let mut start_height = from;
+        while start_height <= to {
+            let end_height = start_height.saturating_add(interval - 1).min(to);
+            let mut candle: Option<Candle> = close.map(|close| Candle {
+                start_height,
+                open: close,
+                high: close,
+                low: close,
+                close,
+                volume: dec!(0),
+            });
+            while let Some((_, snapshot)) = snapshots.next_if(|(height, _)| *height <= end_height) {
+                let volume: Decimal = flat_indices
+                    .iter()
+                    .map(|flat_index| snapshot.volume[*flat_index as usize])
+                    .sum();
+                let snapshot_price = price(snapshot.state);
+                let candle = candle.get_or_insert(Candle {
+                    start_height,
+                    open: snapshot_price,
+                    high: snapshot_price,
+                    low: snapshot_price,
+                    close: snapshot_price,
+                    volume: dec!(0),
+                });
+                candle.high = candle.high.max(snapshot_price);
+                candle.low = candle.low.min(snapshot_price);
+                candle.close = snapshot_price;
+                candle.volume += volume;
+            }
+            if let Some(candle) = candle {
+                close = Some(candle.close);
+                candles.push(candle);
+            }
+            start_height = match end_height.checked_add(1) {
+                Some(height) => height,
+                None => break,
+            };
+        }
+        Ok(candles)
+    }
+
+    fn get_b(&self, txn: &impl StoreRead, market: &OutPoint) -> Result<Decimal, Error> {
+        Ok(Decimal::from(self.get_market(txn, market)?.b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{outpoint, value, Fixture, FUNDS};
+
+    #[test]
+    fn candles_follow_the_price_of_a_share() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let buys: Vec<Transaction> = fixture.accounts[..2]
+            .iter()
+            .zip(0..)
+            .map(|((address, funds), share)| Transaction {
+                inputs: vec![*funds],
+                outputs: vec![
+                    fixture.position(*address, share, 1000),
+                    value(*address, FUNDS - 1000),
+                ],
+            })
+            .collect();
+        fixture.connect(3, vec![buys[0].clone()]);
+        fixture.connect(5, vec![buys[1].clone()]);
+        // A share bought and sold back in one block leaves the state as it was, but is traded.
+        let (address, funds) = fixture.accounts[2];
+        let buy = Transaction {
+            inputs: vec![funds],
+            outputs: vec![
+                fixture.position(address, 0, 1000),
+                value(address, FUNDS - 1000),
+            ],
+        };
+        let sell = Transaction {
+            inputs: vec![outpoint(&buy, 0)],
+            outputs: vec![value(address, 500)],
+        };
+        fixture.connect(7, vec![buy, sell]);
+
+        let txn = &fixture.store;
+        let series = fixture
+            .state
+            .get_price_series(txn, &market, &[0], 0, 5)
+            .unwrap();
+        let heights: Vec<u32> = series.iter().map(|(height, _)| *height).collect();
+        // The market was created at height 2, and traded on at heights 3 and 5.
+        assert_eq!(heights, vec![2, 3, 5]);
+        let high = series[1].1;
+        assert_eq!(series[0].1, dec!(0.5));
+        assert!(high > dec!(0.5));
+        assert_eq!(series[2].1, dec!(0.5));
+        assert_eq!(
+            fixture.state.get_state_at(txn, &market, 4).unwrap(),
+            Some(vec![dec!(1000), dec!(0)])
+        );
+
+        let candles = fixture
+            .state
+            .get_candles(txn, &market, &[0], 2, 7, 2)
+            .unwrap();
+        let expected = [
+            (2, dec!(0.5), high, dec!(0.5), high, dec!(1000)),
+            (4, high, high, dec!(0.5), dec!(0.5), dec!(0)),
+            // Offsetting trades, so the price stays where it closed.
+            (6, dec!(0.5), dec!(0.5), dec!(0.5), dec!(0.5), dec!(2000)),
+        ];
+        let candles: Vec<_> = candles
+            .into_iter()
+            .map(|c| (c.start_height, c.open, c.high, c.low, c.close, c.volume))
+            .collect();
+        assert_eq!(candles, expected.to_vec());
+
+        // A key with a malformed height is reported instead of panicking.
+        let mut key = history_key(&market, 3).unwrap();
+        let value = fixture
+            .store
+            .get_raw(Db::MarketHistory, &key)
+            .unwrap()
+            .unwrap();
+        key.push(0);
+        fixture
+            .store
+            .put_raw(Db::MarketHistory, &key, &value)
+            .unwrap();
+        assert!(matches!(
+            fixture.state.get_snapshots(&fixture.store, &market, 0, 5),
+            Err(Error::CorruptIndex {
+                db: Db::MarketHistory
+            })
+        ));
+    }
+}
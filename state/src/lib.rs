@@ -5,50 +5,199 @@ use hivemind_types::{
 use nalgebra::DVector;
 use rust_decimal::prelude::*;
 use rust_decimal_macros::dec;
-use sdk_types::GetValue as _;
+use sdk_types::{GetAddress as _, GetValue as _};
 
-use heed::{types::*, RwTxn};
-use heed::{Database, RoTxn};
-use hivemind_types::{sdk_types::OutPoint, *};
+use hivemind_types::{
+    sdk_types::{Address, OutPoint},
+    *,
+};
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
+use std::sync::{mpsc, Arc, Mutex};
+
+pub mod archive;
+pub mod chain;
+pub mod commitment;
+pub mod drivechain;
+pub mod emission;
+pub mod events;
+pub mod genesis;
+pub mod history;
+mod migrations;
+pub mod portfolio;
+pub mod pruning;
+pub mod router;
+pub mod snapshot;
+pub mod store;
+mod undo;
+
+pub use chain::{ChainState, Submitted};
+pub use drivechain::{BundleEntry, Mainchain, MockMainchain};
+pub use emission::{Emission, OracleFeeShare, OraclePool};
+pub use events::StateEvent;
+pub use genesis::{ChainParams, GenesisConfig, GenesisOutput};
+pub use history::{Candle, MarketSnapshot};
+pub use migrations::SCHEMA_VERSION;
+pub use pruning::PruningMode;
+pub use snapshot::{SnapshotHeader, SNAPSHOT_VERSION};
+pub use store::{
+    Db, Entries, Entry, HeedRead, HeedStore, HeedWrite, MemoryStore, StoreRead, StoreWrite,
+};
+pub use undo::UndoEntry;
+
+type MarketToDelta = HashMap<OutPoint, DVector<Decimal>>;
+// A market and `(flat_index, value)` pairs of it held by an output.
+type FlatPositions = (OutPoint, Vec<(u32, u64)>);
+
+/// Sends the height and events of every published body to a subscriber, see `State::subscribe`.
+pub type Subscriber = mpsc::Sender<(u32, Arc<Vec<StateEvent>>)>;
 
+/// Validates and connects bodies against databases accessed through a `StoreRead` or
+/// `StoreWrite` transaction, see store.rs.
+#[derive(Default)]
 pub struct State {
-    pub utxos: Database<SerdeBincode<OutPoint>, SerdeBincode<Output>>,
-    pub vectors: Database<SerdeBincode<OutPoint>, SerdeBincode<Vec<Decimal>>>,
-    pub markets: Database<SerdeBincode<OutPoint>, SerdeBincode<Market>>,
-    // There is some aparent redundancy, position outpoints are stored twice: once as keys in utxos
-    // db and once as values in market_to_positions db.
-    pub market_to_positions: Database<SerdeBincode<OutPoint>, SerdeBincode<Vec<OutPoint>>>,
+    pub subscribers: Mutex<Vec<Subscriber>>,
+    pub params: ChainParams,
 }
 
 impl State {
-    pub const NUM_DBS: u32 = 4;
-
-    pub fn new(env: &heed::Env) -> Result<Self, Error> {
-        let utxos = env.create_database(Some("utxos"))?;
-        let vectors = env.create_database(Some("vectors"))?;
-        let markets = env.create_database(Some("markets"))?;
-        let market_to_positions = env.create_database(Some("market_to_positions"))?;
-        Ok(State {
-            utxos,
-            vectors,
-            markets,
-            market_to_positions,
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_params(params: ChainParams) -> Self {
+        Self {
+            params,
+            ..Self::default()
+        }
+    }
+
+    pub fn get_utxo(&self, txn: &impl StoreRead, outpoint: &OutPoint) -> Result<Output, Error> {
+        txn.get(Db::Utxos, outpoint)?.ok_or(Error::NoUtxo {
+            outpoint: *outpoint,
         })
     }
 
+    pub fn get_market(&self, txn: &impl StoreRead, market: &OutPoint) -> Result<Market, Error> {
+        txn.get(Db::Markets, market)?
+            .ok_or(Error::NoUtxo { outpoint: *market })
+    }
+
+    /// Returns the LMSR state vector of a market.
+    pub fn get_vector(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+    ) -> Result<Vec<Decimal>, Error> {
+        txn.get(Db::Vectors, market)?
+            .ok_or(Error::NoUtxo { outpoint: *market })
+    }
+
+    /// Returns outpoints of all utxos sent to an address.
+    pub fn get_address_outpoints(
+        &self,
+        txn: &impl StoreRead,
+        address: &Address,
+    ) -> Result<Vec<OutPoint>, Error> {
+        self.get_index(txn, Db::AddressToOutPoints, address)
+    }
+
+    fn put_utxo(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        outpoint: &OutPoint,
+        output: &Output,
+    ) -> Result<(), Error> {
+        let previous: Option<Output> = txn.get(Db::Utxos, outpoint)?;
+        if let Some(previous) = previous {
+            let key = address_key(&previous.address, outpoint)?;
+            self.write(txn, undo, Db::AddressToOutPoints, key, None)?;
+            self.update_oracle_pool(txn, undo, outpoint, &previous, false)?;
+        }
+        self.put(txn, undo, Db::Utxos, outpoint, output)?;
+        self.update_oracle_pool(txn, undo, outpoint, output, true)?;
+        match &output.content {
+            sdk_types::Content::Withdrawal { .. } => {
+                let key = bincode::serialize(outpoint)?;
+                self.write(txn, undo, Db::PendingWithdrawals, key, Some(&[][..]))?;
+            }
+            // The first resolution of a decision is final.
+            sdk_types::Content::Custom(HivemindContent::Resolution { decision, .. })
+                if txn
+                    .get::<_, OutPoint>(Db::DecisionToResolution, decision)?
+                    .is_none() =>
+            {
+                self.put(txn, undo, Db::DecisionToResolution, decision, outpoint)?;
+            }
+            _ => {}
+        }
+        let key = address_key(&output.address, outpoint)?;
+        self.write(txn, undo, Db::AddressToOutPoints, key, Some(&[][..]))
+    }
+
+    fn delete_utxo(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        outpoint: &OutPoint,
+    ) -> Result<(), Error> {
+        let previous: Option<Output> = txn.get(Db::Utxos, outpoint)?;
+        if let Some(previous) = previous {
+            match &previous.content {
+                sdk_types::Content::Withdrawal { .. } => {
+                    let key = bincode::serialize(outpoint)?;
+                    self.write(txn, undo, Db::PendingWithdrawals, key, None)?;
+                }
+                sdk_types::Content::Custom(HivemindContent::Resolution { decision, .. }) => {
+                    let resolution: Option<OutPoint> =
+                        txn.get(Db::DecisionToResolution, decision)?;
+                    if resolution == Some(*outpoint) {
+                        self.delete(txn, undo, Db::DecisionToResolution, decision)?;
+                    }
+                }
+                _ => {}
+            }
+            let key = address_key(&previous.address, outpoint)?;
+            self.write(txn, undo, Db::AddressToOutPoints, key, None)?;
+            self.update_oracle_pool(txn, undo, outpoint, &previous, false)?;
+        }
+        self.delete(txn, undo, Db::Utxos, outpoint)
+    }
+
+    /// Returns outpoints of all outputs holding shares of a market.
+    pub fn get_market_positions(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+    ) -> Result<Vec<OutPoint>, Error> {
+        self.get_index(txn, Db::MarketToPositions, market)
+    }
+
+    /// Returns the outpoints in the index entries of `key`, in key order, see `index_key`.
+    pub(crate) fn get_index<K: Serialize>(
+        &self,
+        txn: &impl StoreRead,
+        db: Db,
+        key: &K,
+    ) -> Result<Vec<OutPoint>, Error> {
+        let prefix = bincode::serialize(key)?;
+        let mut outpoints = vec![];
+        for item in txn.prefix_iter(db, &prefix)? {
+            let (key, _) = item?;
+            outpoints.push(bincode::deserialize(&key[prefix.len()..])?);
+        }
+        Ok(outpoints)
+    }
+
     pub fn fill_transaction(
         &self,
-        txn: &RoTxn,
+        txn: &impl StoreRead,
         transaction: &Transaction,
     ) -> Result<FilledTransaction, Error> {
         let mut spent_utxos = vec![];
         for input in &transaction.inputs {
-            let utxo = self
-                .utxos
-                .get(txn, input)?
-                .ok_or(Error::NoUtxo { outpoint: *input })?;
-            spent_utxos.push(utxo);
+            spent_utxos.push(self.get_utxo(txn, input)?);
         }
         Ok(FilledTransaction {
             spent_utxos,
@@ -58,33 +207,38 @@ impl State {
 
     fn get_deltas_and_values(
         &self,
-        txn: &RoTxn,
+        txn: &impl StoreRead,
         transaction: &FilledTransaction,
-    ) -> Result<(HashMap<OutPoint, DVector<Decimal>>, u64, u64), Error> {
+    ) -> Result<(MarketToDelta, u64, u64), Error> {
         // TODO: Use more efficient hash maps (there is no need to hash
         // OutPoints).
         let mut market_to_delta: HashMap<OutPoint, DVector<Decimal>> = HashMap::new();
         let mut input_value: u64 = 0;
-        for spent_utxo in &transaction.spent_utxos {
+        for (input, spent_utxo) in transaction
+            .transaction
+            .inputs
+            .iter()
+            .zip(&transaction.spent_utxos)
+        {
+            self.check_unresolved(txn, spent_utxo)?;
             input_value += spent_utxo.get_value();
-            match &spent_utxo.content {
-                sdk_types::Content::Custom(HivemindContent::Position {
-                    market,
-                    share,
-                    value,
-                }) => {
+            input_value += self.get_order_escrow_value(spent_utxo)?;
+            input_value += self.get_votecoin_reward(txn, input, spent_utxo)?;
+            if let sdk_types::Content::Custom(content) = &spent_utxo.content {
+                if let Some((market, positions)) = self.get_flat_positions(txn, content)? {
                     let size = self.get_size(txn, &market)?;
                     let delta = market_to_delta
-                        .entry(*market)
+                        .entry(market)
                         .or_insert(DVector::from_element(size as usize, dec!(0)));
-                    let flat_index = self.share_to_flat_index(txn, &market, &share)?;
-                    delta[flat_index as usize] -= Decimal::from(*value);
+                    for (flat_index, value) in &positions {
+                        delta[*flat_index as usize] -= Decimal::from(*value);
+                    }
                 }
-                _ => {}
-            };
+            }
         }
         let mut output_value: u64 = 0;
         for output in &transaction.transaction.outputs {
+            self.check_unresolved(txn, output)?;
             output_value += output.get_value();
             // It costs `b * ln(size)` to create a new market with `size` possible outcomes.
             //
@@ -94,73 +248,147 @@ impl State {
             // But when a market is resolved, its value would = to the market authors share in
             // fees.
             output_value += self.get_market_funding_cost(txn, output)?;
-            match &output.content {
-                sdk_types::Content::Custom(HivemindContent::Position {
-                    market,
-                    share,
-                    value,
-                }) => {
+            output_value += self.get_order_escrow_value(output)?;
+            if let sdk_types::Content::Custom(content) = &output.content {
+                if let Some((market, positions)) = self.get_flat_positions(txn, content)? {
                     let size = self.get_size(txn, &market)?;
                     let delta = market_to_delta
-                        .entry(*market)
+                        .entry(market)
                         .or_insert(DVector::from_element(size as usize, dec!(0)));
-                    let flat_index = self.share_to_flat_index(txn, &market, &share)?;
-                    delta[flat_index as usize] += Decimal::from(*value);
+                    for (flat_index, value) in &positions {
+                        delta[*flat_index as usize] += Decimal::from(*value);
+                    }
                 }
-                _ => {}
-            };
+            }
+        }
+        // The change of a trade is paid out of the value reserved for it.
+        for change in self.get_trade_changes(txn, &transaction.transaction.outputs)? {
+            output_value += change;
+        }
+        // A complete set of shares is always worth exactly one unit, so complete sets are minted
+        // for value and burned for value at par without going through the market maker. This
+        // leaves the market state vector untouched and doesn't change prices.
+        for delta in market_to_delta.values_mut() {
+            let sets = net_complete_sets(delta);
+            let value = sets.abs();
+            let value = value
+                .to_u64()
+                .ok_or(Error::U64Overflow { decimal: value })?;
+            if sets > dec!(0) {
+                output_value += value;
+            } else {
+                input_value += value;
+            }
         }
         Ok((market_to_delta, input_value, output_value))
     }
 
-    fn share_to_flat_index(
+    /// Returns the indices of the flattened market state vector covered by `share`. Every
+    /// `WILDCARD` index expands to all outcomes of its decision, so a marginal share like `[k, *]`
+    /// covers a slice of the state vector.
+    pub(crate) fn share_to_flat_index(
         &self,
-        txn: &RoTxn,
+        txn: &impl StoreRead,
         market: &OutPoint,
         share: &[u32],
-    ) -> Result<u32, Error> {
-        let market = self
-            .markets
-            .get(txn, market)?
-            .ok_or(Error::NoUtxo { outpoint: *market })?;
+    ) -> Result<Vec<u32>, Error> {
+        let market = self.get_market(txn, market)?;
+        if share.len() != market.shape.len() {
+            return Err(Error::InvalidShare);
+        }
 
         let mut step: u32 = market.shape.iter().product();
-        let mut flat_index = 0;
+        let mut flat_indices = vec![0];
         for (index, size) in share.iter().zip(market.shape.iter()) {
             step /= size;
-            flat_index += index * step;
+            let indices: Vec<u32> = if *index == WILDCARD {
+                (0..*size).collect()
+            } else if index < size {
+                vec![*index]
+            } else {
+                return Err(Error::InvalidShare);
+            };
+            flat_indices = flat_indices
+                .iter()
+                .flat_map(|flat_index| indices.iter().map(move |index| flat_index + index * step))
+                .collect();
         }
-        Ok(flat_index)
+        Ok(flat_indices)
     }
 
-    fn get_size(&self, txn: &RoTxn, market: &OutPoint) -> Result<u32, Error> {
-        let market = self
-            .markets
-            .get(txn, &market)?
-            .ok_or(Error::NoUtxo { outpoint: *market })?;
+    fn get_flat_positions(
+        &self,
+        txn: &impl StoreRead,
+        content: &HivemindContent,
+    ) -> Result<Option<FlatPositions>, Error> {
+        if let HivemindContent::PredicatePosition {
+            market,
+            predicate,
+            value,
+        } = content
+        {
+            let shape = self.get_market(txn, market)?.shape;
+            if !predicate.is_valid(&shape) {
+                return Err(Error::InvalidPredicate);
+            }
+            let positions = predicate
+                .flat_indices(&shape)
+                .into_iter()
+                .map(|flat_index| (flat_index, *value))
+                .collect();
+            return Ok(Some((*market, positions)));
+        }
+        match content.get_positions() {
+            Some((market, shares)) => {
+                let mut positions = vec![];
+                for (share, value) in &shares {
+                    for flat_index in self.share_to_flat_index(txn, &market, share)? {
+                        positions.push((flat_index, *value));
+                    }
+                }
+                Ok(Some((market, positions)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Positions of resolved markets were paid out, and their state vectors may be pruned.
+    fn check_unresolved(&self, txn: &impl StoreRead, output: &Output) -> Result<(), Error> {
+        let market = match &output.content {
+            sdk_types::Content::Custom(HivemindContent::PredicatePosition { market, .. }) => {
+                *market
+            }
+            sdk_types::Content::Custom(content) => match content.get_positions() {
+                Some((market, _)) => market,
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        let resolved = match txn.get::<_, Market>(Db::Markets, &market)? {
+            Some(market) => market.outcomes.iter().all(Option::is_some),
+            None => true,
+        };
+        if resolved {
+            return Err(Error::MarketResolved { market });
+        }
+        Ok(())
+    }
+
+    fn get_size(&self, txn: &impl StoreRead, market: &OutPoint) -> Result<u32, Error> {
+        let market = self.get_market(txn, market)?;
         Ok(market.shape.iter().product())
     }
 
-    fn get_cost(
+    pub(crate) fn get_cost(
         &self,
-        txn: &RoTxn,
+        txn: &impl StoreRead,
         market_to_delta: &HashMap<OutPoint, DVector<Decimal>>,
     ) -> Result<Decimal, Error> {
         let mut total_cost: Decimal = dec!(0);
         for (market, delta) in market_to_delta {
-            let state: Vec<Decimal> = self
-                .vectors
-                .get(txn, market)?
-                .ok_or(Error::NoUtxo { outpoint: *market })?
-                .iter()
-                .copied()
-                .collect();
-            let state = DVector::from(state);
+            let state = DVector::from(self.get_vector(txn, market)?);
             let b = {
-                let market = self
-                    .utxos
-                    .get(txn, market)?
-                    .ok_or(Error::NoUtxo { outpoint: *market })?;
+                let market = self.get_utxo(txn, market)?;
                 match market.content {
                     sdk_types::Content::Custom(HivemindContent::Market { b, .. }) => b,
                     _ => unreachable!(),
@@ -176,12 +404,11 @@ impl State {
     // TODO: Check that input_value in is enough to cover market creation.
     pub fn validate_transaction(
         &self,
-        txn: &RoTxn,
+        txn: &impl StoreRead,
         transaction: &FilledTransaction,
         height: u32,
     ) -> Result<u64, Error> {
-        let mut resolved_decisions = HashSet::new();
-        let mut spent_decisions = vec![];
+        let mut spent_decisions = HashMap::new();
         for (outpoint, spent_utxo) in transaction
             .transaction
             .inputs
@@ -190,21 +417,24 @@ impl State {
         {
             match &spent_utxo.content {
                 sdk_types::Content::Custom(HivemindContent::Decision {
-                    resolvable_height, ..
+                    resolvable_height,
+                    size,
+                    ..
                 }) => {
-                    if *resolvable_height < height {
+                    if height < *resolvable_height {
                         return Err(Error::DecisionSpentEarly);
                     }
-                    spent_decisions.push(outpoint);
+                    spent_decisions.insert(*outpoint, *size);
                 }
-                sdk_types::Content::Custom(HivemindContent::Resolution { decision, .. }) => {
-                    resolved_decisions.insert(decision);
+                // Withdrawals only leave the sidechain through bundles.
+                sdk_types::Content::Withdrawal { .. } => {
+                    return Err(Error::WithdrawalSpent {
+                        outpoint: *outpoint,
+                    });
                 }
                 sdk_types::Content::Custom(HivemindContent::Market { decisions, .. }) => {
                     for decision in decisions {
-                        let decision = self.utxos.get(txn, decision)?.ok_or(Error::NoUtxo {
-                            outpoint: *decision,
-                        })?;
+                        let decision = self.get_utxo(txn, decision)?;
                         match decision.content {
                             sdk_types::Content::Custom(HivemindContent::Decision {
                                 resolvable_height,
@@ -221,11 +451,45 @@ impl State {
                 _ => {}
             }
         }
-        for spent_decision in &spent_decisions {
-            if !resolved_decisions.contains(spent_decision) {
-                return Err(Error::DecisionSpentWithoutResolution);
+        // Every spent decision is resolved by exactly one Resolution output, to an outcome it has.
+        let mut resolved_decisions = HashSet::new();
+        for output in &transaction.transaction.outputs {
+            if let sdk_types::Content::Custom(HivemindContent::Resolution { decision, outcome }) =
+                &output.content
+            {
+                let size =
+                    spent_decisions
+                        .get(decision)
+                        .ok_or(Error::ResolutionWithoutDecision {
+                            decision: *decision,
+                        })?;
+                if outcome >= size {
+                    return Err(Error::InvalidOutcome {
+                        decision: *decision,
+                        outcome: *outcome,
+                    });
+                }
+                if !resolved_decisions.insert(*decision) {
+                    return Err(Error::DuplicateResolution {
+                        decision: *decision,
+                    });
+                }
             }
         }
+        if resolved_decisions.len() != spent_decisions.len() {
+            return Err(Error::DecisionSpentWithoutResolution);
+        }
+        self.validate_limit_orders(txn, transaction)?;
+        let votecoin_in: u64 = transaction.spent_utxos.iter().map(get_votecoin_value).sum();
+        let votecoin_out: u64 = transaction
+            .transaction
+            .outputs
+            .iter()
+            .map(get_votecoin_value)
+            .sum();
+        if votecoin_out > votecoin_in {
+            return Err(Error::NotEnoughVoteCoinIn);
+        }
         let (market_to_delta, input_value, output_value) =
             self.get_deltas_and_values(txn, transaction)?;
         let cost = self.get_cost(txn, &market_to_delta)?;
@@ -233,13 +497,324 @@ impl State {
         if cost + Decimal::from(output_value) > Decimal::from(input_value) {
             return Err(Error::NotEnoughValueIn);
         }
-        let fee =
-            input_value - cost.to_u64().ok_or(Error::U64Overflow { decimal: cost })? + output_value;
+        let fee = Decimal::from(input_value) - cost - Decimal::from(output_value);
+        let fee = fee
+            .floor()
+            .to_u64()
+            .ok_or(Error::U64Overflow { decimal: fee })?;
         Ok(fee)
     }
 
-    pub fn validate_body(&self, txn: &RoTxn, body: Body) -> Result<(), Error> {
+    /// Transactions that only consolidate Position outputs or sell them back to the market
+    /// maker, while reducing the number of UTXOs, don't have to pay the minimum fee.
+    pub fn is_fee_exempt(
+        &self,
+        txn: &impl StoreRead,
+        transaction: &FilledTransaction,
+    ) -> Result<bool, Error> {
+        if transaction.transaction.outputs.len() >= transaction.spent_utxos.len() {
+            return Ok(false);
+        }
+        // Ask orders also hold shares, but creating them is not consolidation.
+        let is_position = |content: &sdk_types::Content<HivemindContent>| {
+            matches!(
+                content,
+                sdk_types::Content::Custom(
+                    HivemindContent::Position { .. }
+                        | HivemindContent::PositionBundle { .. }
+                        | HivemindContent::PredicatePosition { .. }
+                )
+            )
+        };
+        if !transaction
+            .spent_utxos
+            .iter()
+            .all(|spent_utxo| is_position(&spent_utxo.content))
+        {
+            return Ok(false);
+        }
+        if !transaction.transaction.outputs.iter().all(|output| {
+            is_position(&output.content) || matches!(output.content, sdk_types::Content::Value(_))
+        }) {
+            return Ok(false);
+        }
+        // Buying shares from the market maker is never fee exempt.
+        let (market_to_delta, _, _) = self.get_deltas_and_values(txn, transaction)?;
+        let buys_shares = market_to_delta
+            .values()
+            .any(|delta| delta.iter().any(|d| *d > dec!(0)));
+        Ok(!buys_shares)
+    }
+
+    /// Checks limit orders created by a transaction, and returns the outpoints of the spent orders
+    /// it fills. Spent orders that are not filled are cancelled.
+    ///
+    /// A filled order pays its owner, in shares for bids and in value for asks, and its unfilled
+    /// remainder is recreated with the same address, market, share, side and price. Inputs
+    /// spending filled orders are authorized by the fill itself rather than by the owner, while
+    /// cancelled orders need the owner's authorization like any other input.
+    pub fn validate_limit_orders(
+        &self,
+        txn: &impl StoreRead,
+        transaction: &FilledTransaction,
+    ) -> Result<HashSet<OutPoint>, Error> {
+        type OrderKey = (Address, OutPoint, Vec<u32>, Side, Decimal);
+        let mut key_to_filled: HashMap<OrderKey, i128> = HashMap::new();
+        let mut order_inputs = vec![];
+        for (outpoint, spent_utxo) in transaction
+            .transaction
+            .inputs
+            .iter()
+            .zip(transaction.spent_utxos.iter())
+        {
+            if let sdk_types::Content::Custom(HivemindContent::LimitOrder {
+                market,
+                share,
+                side,
+                amount,
+                price,
+            }) = &spent_utxo.content
+            {
+                let key = (spent_utxo.address, *market, share.clone(), *side, *price);
+                *key_to_filled.entry(key.clone()).or_insert(0) += *amount as i128;
+                order_inputs.push((*outpoint, key));
+            }
+        }
+        let mut address_to_value: HashMap<Address, u64> = HashMap::new();
+        let mut address_to_shares: HashMap<(Address, OutPoint, Vec<u32>), u64> = HashMap::new();
+        for output in &transaction.transaction.outputs {
+            match &output.content {
+                sdk_types::Content::Value(value) => {
+                    *address_to_value.entry(output.address).or_insert(0) += value;
+                }
+                sdk_types::Content::Custom(HivemindContent::Position {
+                    market,
+                    share,
+                    value,
+                }) => {
+                    *address_to_shares
+                        .entry((output.address, *market, share.clone()))
+                        .or_insert(0) += value;
+                }
+                sdk_types::Content::Custom(HivemindContent::LimitOrder {
+                    market,
+                    share,
+                    side,
+                    amount,
+                    price,
+                }) => {
+                    if *amount == 0 || *price <= dec!(0) {
+                        return Err(Error::InvalidLimitOrder);
+                    }
+                    // Asks are checked like every other output holding shares.
+                    if *side == Side::Bid {
+                        self.share_to_flat_index(txn, market, share)?;
+                    }
+                    let key = (output.address, *market, share.clone(), *side, *price);
+                    if let Some(filled) = key_to_filled.get_mut(&key) {
+                        *filled -= *amount as i128;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut paid = HashSet::new();
+        for (key, filled) in &key_to_filled {
+            if *filled <= 0 {
+                continue;
+            }
+            let filled = *filled as u64;
+            let (address, market, share, side, price) = key;
+            match side {
+                Side::Bid => {
+                    let shares = address_to_shares
+                        .entry((*address, *market, share.clone()))
+                        .or_insert(0);
+                    if *shares < filled {
+                        continue;
+                    }
+                    *shares -= filled;
+                }
+                Side::Ask => {
+                    let payment = order_value(filled, *price).ok_or(Error::InvalidLimitOrder)?;
+                    let value = address_to_value.entry(*address).or_insert(0);
+                    if *value < payment {
+                        continue;
+                    }
+                    *value -= payment;
+                }
+            }
+            paid.insert(key);
+        }
+        let filled_orders = order_inputs
+            .into_iter()
+            .filter(|(_, key)| paid.contains(key))
+            .map(|(outpoint, _)| outpoint)
+            .collect();
+        Ok(filled_orders)
+    }
+
+    /// Checks that every input of the body is authorized by the owner of the utxo it spends,
+    /// except inputs spending filled limit orders, which are authorized by any valid signature.
+    /// Authorizations are listed in the order of the inputs of all transactions in the body.
+    pub fn validate_authorizations(&self, txn: &impl StoreRead, body: &Body) -> Result<(), Error> {
+        sdk_authorization_ed25519_dalek::verify_authorizations(body)?;
+        let mut authorizations = body.authorizations.iter();
+        for transaction in &body.transactions {
+            let transaction = self.fill_transaction(txn, transaction)?;
+            let filled_orders = self.validate_limit_orders(txn, &transaction)?;
+            for (outpoint, spent_utxo) in transaction
+                .transaction
+                .inputs
+                .iter()
+                .zip(transaction.spent_utxos.iter())
+            {
+                let authorized = match authorizations.next() {
+                    Some(authorization) => {
+                        filled_orders.contains(outpoint)
+                            || authorization.get_address() == spent_utxo.address
+                    }
+                    None => false,
+                };
+                if authorized {
+                    continue;
+                }
+                // Only the owner can spend an order without filling it.
+                if let sdk_types::Content::Custom(HivemindContent::LimitOrder { .. }) =
+                    spent_utxo.content
+                {
+                    return Err(Error::LimitOrderNotPaid);
+                }
+                return Err(Error::NotAuthorized {
+                    outpoint: *outpoint,
+                });
+            }
+        }
+        if authorizations.next().is_some() {
+            return Err(Error::TooManyAuthorizations);
+        }
+        Ok(())
+    }
+
+    /// Returns open limit orders of a market.
+    pub fn get_open_orders(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+    ) -> Result<Vec<(OutPoint, Output)>, Error> {
+        let mut orders = vec![];
+        for outpoint in self.get_index(txn, Db::MarketToOrders, market)? {
+            orders.push((outpoint, self.get_utxo(txn, &outpoint)?));
+        }
+        Ok(orders)
+    }
+
+    /// Returns unresolved decisions that become resolvable at heights `height..height + n`.
+    pub fn get_decisions_resolvable_within(
+        &self,
+        txn: &impl StoreRead,
+        height: u32,
+        n: u32,
+    ) -> Result<Vec<(u32, OutPoint)>, Error> {
+        let mut decisions = vec![];
+        if n == 0 {
+            return Ok(decisions);
+        }
+        let end = height.saturating_add(n - 1);
+        // Keys are a 4 byte height followed by a decision, so they all sort below 8 max bytes.
+        for item in txn.range(
+            Db::HeightToDecisions,
+            &height.to_be_bytes(),
+            &[u8::MAX; 8],
+            false,
+        )? {
+            let (key, _) = item?;
+            let (resolvable_height, decision) = decode_decision_key(&key)?;
+            if resolvable_height > end {
+                break;
+            }
+            decisions.push((resolvable_height, decision));
+        }
+        Ok(decisions)
+    }
+
+    // `max_cost` minus the cost of every Trade output, priced one after the other.
+    fn get_trade_changes(
+        &self,
+        txn: &impl StoreRead,
+        outputs: &[Output],
+    ) -> Result<Vec<u64>, Error> {
+        let mut market_to_delta: HashMap<OutPoint, DVector<Decimal>> = HashMap::new();
+        let mut changes = vec![];
+        for output in outputs {
+            let sdk_types::Content::Custom(content @ HivemindContent::Trade { max_cost, .. }) =
+                &output.content
+            else {
+                continue;
+            };
+            let Some((market, positions)) = self.get_flat_positions(txn, content)? else {
+                continue;
+            };
+            let size = self.get_size(txn, &market)?;
+            let before = market_to_delta
+                .remove(&market)
+                .unwrap_or(DVector::from_element(size as usize, dec!(0)));
+            let mut after = before.clone();
+            for (flat_index, value) in &positions {
+                after[*flat_index as usize] += Decimal::from(*value);
+            }
+            let cost = self.get_cost(txn, &HashMap::from([(market, after.clone())]))?
+                - self.get_cost(txn, &HashMap::from([(market, before)]))?;
+            market_to_delta.insert(market, after);
+            let change = Decimal::from(*max_cost) - cost;
+            if change < dec!(0) {
+                return Err(Error::MaxCostExceeded {
+                    max_cost: *max_cost,
+                    cost,
+                });
+            }
+            let change = change.floor();
+            let change = change
+                .to_u64()
+                .ok_or(Error::U64Overflow { decimal: change })?;
+            changes.push(change);
+        }
+        Ok(changes)
+    }
+
+    fn get_order_escrow_value(&self, output: &Output) -> Result<u64, Error> {
+        match &output.content {
+            sdk_types::Content::Custom(content) => {
+                content.get_escrow_value().ok_or(Error::InvalidLimitOrder)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    pub fn validate_body(
+        &self,
+        txn: &impl StoreRead,
+        body: Body,
+        height: u32,
+    ) -> Result<u64, Error> {
+        self.validate_authorizations(txn, &body)?;
+        let fee_value = self.validate_transactions(txn, &body, height)?;
+        self.validate_coinbase(&body.coinbase, height, fee_value)?;
+        Ok(fee_value)
+    }
+
+    /// Validates the transactions of a body at `height`, except for their authorizations, and
+    /// returns the total fee they pay.
+    pub fn validate_transactions(
+        &self,
+        txn: &impl StoreRead,
+        body: &Body,
+        height: u32,
+    ) -> Result<u64, Error> {
         let mut fee_value = 0;
+        let mut fee_exempt_transactions = 0;
+        let mut fees = vec![];
+        let mut market_to_deltas = vec![];
         {
             let mut spent = HashSet::new();
             for transaction in &body.transactions {
@@ -248,59 +823,262 @@ impl State {
                         return Err(Error::UtxoDoubleSpent { outpoint: *input });
                     }
                     spent.insert(input);
-                    let transaction = self.fill_transaction(txn, transaction)?;
-                    fee_value += self.validate_transaction(txn, &transaction, 0)?;
                 }
+                let transaction = self.fill_transaction(txn, transaction)?;
+                let fee = self.validate_transaction(txn, &transaction, height)?;
+                let is_fee_exempt = self.is_fee_exempt(txn, &transaction)?;
+                let (market_to_delta, _, _) = self.get_deltas_and_values(txn, &transaction)?;
+                fees.push((fee, is_fee_exempt, batch_refund_address(&transaction)));
+                market_to_deltas.push(market_to_delta);
+            }
+        }
+        let batch_refunds = self.get_batch_refunds(txn, &market_to_deltas)?;
+        for ((fee, is_fee_exempt, refund_address), refund) in fees.into_iter().zip(batch_refunds) {
+            // When the batch price is worse than the price quoted for the transaction alone, the
+            // difference is paid out of its fee. When it is better, the difference is refunded,
+            // see `batch_refund_address`.
+            let fee = if refund < dec!(0) {
+                let shortfall = (-refund).ceil();
+                let shortfall = shortfall
+                    .to_u64()
+                    .ok_or(Error::U64Overflow { decimal: shortfall })?;
+                fee.checked_sub(shortfall).ok_or(Error::NotEnoughValueIn)?
+            } else if refund_address.is_none() {
+                let refund = refund_value(refund)?;
+                fee.checked_add(refund).ok_or(Error::U64Overflow {
+                    decimal: Decimal::from(fee) + Decimal::from(refund),
+                })?
+            } else {
+                fee
+            };
+            if is_fee_exempt {
+                fee_exempt_transactions += 1;
+            } else if fee < self.params.min_fee {
+                return Err(Error::FeeTooLow { fee });
             }
+            fee_value += fee;
+        }
+        if fee_exempt_transactions > self.params.max_fee_exempt_transactions {
+            return Err(Error::TooManyFeeExemptTransactions);
         }
-        let mut coinbase_value = 0;
-        for output in &body.coinbase {
-            coinbase_value += output.get_value();
+        Ok(fee_value)
+    }
+
+    // What every transaction was quoted trading alone minus what it is charged in the batch.
+    fn get_batch_refunds(
+        &self,
+        txn: &impl StoreRead,
+        market_to_deltas: &[HashMap<OutPoint, DVector<Decimal>>],
+    ) -> Result<Vec<Decimal>, Error> {
+        let batch_costs = self.get_batch_costs(txn, market_to_deltas)?;
+        let mut refunds = vec![];
+        for (market_to_cost, market_to_delta) in batch_costs.iter().zip(market_to_deltas) {
+            let mut refund = dec!(0);
+            for (market, cost) in market_to_cost {
+                let b = Decimal::from(self.get_market(txn, market)?.b);
+                let state = DVector::from(self.get_vector(txn, market)?);
+                let delta = &market_to_delta[market];
+                refund += lmsr_cost(b, &(&state + delta)) - lmsr_cost(b, &state) - cost;
+            }
+            refunds.push(refund);
         }
+        Ok(refunds)
+    }
 
-        if coinbase_value > fee_value {
-            return Err(Error::NotEnoughFeeValue);
+    // Uniform batch prices only approximate `C(q + D) - C(q)`, so the difference is split pro
+    // rata to the gross shares traded, and together transactions pay exactly the batch cost.
+    fn get_batch_costs(
+        &self,
+        txn: &impl StoreRead,
+        market_to_deltas: &[HashMap<OutPoint, DVector<Decimal>>],
+    ) -> Result<Vec<HashMap<OutPoint, Decimal>>, Error> {
+        let mut batch_market_to_delta: HashMap<OutPoint, DVector<Decimal>> = HashMap::new();
+        for market_to_delta in market_to_deltas {
+            for (market, delta) in market_to_delta {
+                let batch_delta = batch_market_to_delta
+                    .entry(*market)
+                    .or_insert(DVector::from_element(delta.len(), dec!(0)));
+                *batch_delta += delta;
+            }
         }
-        Ok(())
+        let gross = |delta: &DVector<Decimal>| delta.iter().map(|d| d.abs()).sum::<Decimal>();
+        let mut costs = vec![HashMap::new(); market_to_deltas.len()];
+        for (market, batch_delta) in &batch_market_to_delta {
+            let Market { b, batch, .. } = self.get_market(txn, market)?;
+            if !batch {
+                continue;
+            }
+            let b = Decimal::from(b);
+            let state = DVector::from(self.get_vector(txn, market)?);
+            let prices = lmsr_batch_prices(b, &state, batch_delta);
+            let batch_cost = lmsr_cost(b, &(&state + batch_delta)) - lmsr_cost(b, &state);
+            let traders: Vec<(usize, &DVector<Decimal>)> = market_to_deltas
+                .iter()
+                .enumerate()
+                .filter_map(|(i, market_to_delta)| Some((i, market_to_delta.get(market)?)))
+                .filter(|(_, delta)| gross(delta) > dec!(0))
+                .collect();
+            let total_gross: Decimal = traders.iter().map(|(_, delta)| gross(delta)).sum();
+            let difference = batch_cost - prices.dot(batch_delta);
+            // The last trader takes what is left of the difference, so rounding doesn't create
+            // or destroy value.
+            let mut left = difference;
+            for (n, (i, delta)) in traders.iter().enumerate() {
+                let share = if n + 1 == traders.len() {
+                    left
+                } else {
+                    difference * gross(delta) / total_gross
+                };
+                left -= share;
+                costs[*i].insert(*market, prices.dot(delta) + share);
+            }
+        }
+        Ok(costs)
     }
 
-    pub fn connect_body(&self, txn: &mut RwTxn, body: &Body) -> Result<(), Error> {
+    /// Connects a body and returns events describing what happened in it.
+    pub fn connect_body(
+        &self,
+        txn: &mut impl StoreWrite,
+        body: &Body,
+        height: u32,
+    ) -> Result<Vec<StateEvent>, Error> {
+        let mut undo = vec![];
+        let mut events = vec![];
         let mut body_market_to_delta = HashMap::new();
+        let mut market_to_deltas = vec![];
         let mut decision_to_outcome = HashMap::new();
+        // Indices of trade events, with their transaction and market, so their cost can be set
+        // to what batch auction markets actually charge.
+        let mut trade_events = vec![];
+        let mut refund_addresses = vec![];
+        self.connect_coinbase(txn, &mut undo, body)?;
         for transaction in &body.transactions {
-            for input in &transaction.inputs {
-                self.utxos.delete(txn, input)?;
-                panic!("this is incorrect! Delete data from other dbs as well.");
+            // Spent utxos are needed to compute deltas, so they are read before being deleted.
+            let filled_transaction = self.fill_transaction(txn, transaction)?;
+            refund_addresses.push(batch_refund_address(&filled_transaction));
+            for (input, spent_utxo) in transaction
+                .inputs
+                .iter()
+                .zip(filled_transaction.spent_utxos.iter())
+            {
+                self.delete_utxo(txn, &mut undo, input)?;
+                if let sdk_types::Content::Custom(content) = &spent_utxo.content {
+                    if let Some(market) = content.get_position_market() {
+                        let key = position_key(&market, input)?;
+                        self.write(txn, &mut undo, Db::MarketToPositions, key, None)?;
+                    }
+                    match content {
+                        HivemindContent::LimitOrder { market, .. } => {
+                            self.remove_from_index(
+                                txn,
+                                &mut undo,
+                                Db::MarketToOrders,
+                                market,
+                                input,
+                            )?;
+                        }
+                        // Spent decisions are resolved.
+                        HivemindContent::Decision {
+                            resolvable_height, ..
+                        } => {
+                            self.remove_from_index(
+                                txn,
+                                &mut undo,
+                                Db::HeightToDecisions,
+                                &resolvable_height.to_be_bytes(),
+                                input,
+                            )?;
+                        }
+                        _ => {}
+                    }
+                }
             }
             let txid = transaction.txid();
+            let trade_changes = self.get_trade_changes(txn, &transaction.outputs)?;
+            let mut trades = 0;
             for (vout, output) in transaction.outputs.iter().enumerate() {
                 let outpoint = OutPoint::Regular {
                     txid,
                     vout: vout as u32,
                 };
-                self.utxos.put(txn, &outpoint, output)?;
+                // Trades settle into positions, with their change paid in extra outputs right
+                // after the transaction's own outputs, one vout per Trade output even if its
+                // change is 0, see `HivemindContent::Trade`.
+                if let sdk_types::Content::Custom(HivemindContent::Trade {
+                    market,
+                    share,
+                    amount,
+                    change: change_address,
+                    ..
+                }) = &output.content
+                {
+                    let change = trade_changes[trades];
+                    let change_outpoint = OutPoint::Regular {
+                        txid,
+                        vout: (transaction.outputs.len() + trades) as u32,
+                    };
+                    trades += 1;
+                    if change > 0 {
+                        let change = Output {
+                            address: *change_address,
+                            content: sdk_types::Content::Value(change),
+                        };
+                        self.put_utxo(txn, &mut undo, &change_outpoint, &change)?;
+                    }
+                    let position = Output {
+                        address: output.address,
+                        content: sdk_types::Content::Custom(HivemindContent::Position {
+                            market: *market,
+                            share: share.clone(),
+                            value: *amount,
+                        }),
+                    };
+                    self.put_utxo(txn, &mut undo, &outpoint, &position)?;
+                } else {
+                    self.put_utxo(txn, &mut undo, &outpoint, output)?;
+                }
 
+                if let sdk_types::Content::Custom(content) = &output.content {
+                    if let Some(market) = content.get_position_market() {
+                        let key = position_key(&market, &outpoint)?;
+                        self.write(txn, &mut undo, Db::MarketToPositions, key, Some(&[][..]))?;
+                    }
+                }
                 match &output.content {
-                    sdk_types::Content::Custom(HivemindContent::Position { market, .. }) => {
-                        let mut positions = self
-                            .market_to_positions
-                            .get(txn, market)?
-                            .ok_or(Error::NoUtxo { outpoint: *market })?;
-                        positions.push(outpoint);
-                        self.market_to_positions.put(txn, market, &positions)?;
+                    sdk_types::Content::Custom(HivemindContent::LimitOrder { market, .. }) => {
+                        self.push_to_index(txn, &mut undo, Db::MarketToOrders, market, outpoint)?;
+                    }
+                    sdk_types::Content::Custom(HivemindContent::Decision {
+                        resolvable_height,
+                        ..
+                    }) => {
+                        self.push_to_index(
+                            txn,
+                            &mut undo,
+                            Db::HeightToDecisions,
+                            &resolvable_height.to_be_bytes(),
+                            outpoint,
+                        )?;
                     }
                     sdk_types::Content::Custom(HivemindContent::Resolution {
                         decision,
                         outcome,
                     }) => {
-                        decision_to_outcome.insert(decision, *outcome);
+                        decision_to_outcome.insert(*decision, *outcome);
+                        events.push(StateEvent::DecisionResolved {
+                            decision: *decision,
+                            outcome: *outcome,
+                        });
                     }
-                    sdk_types::Content::Custom(HivemindContent::Market { b, decisions }) => {
+                    sdk_types::Content::Custom(HivemindContent::Market {
+                        b,
+                        decisions,
+                        batch,
+                    }) => {
                         let mut shape = vec![];
                         for decision in decisions {
-                            let decision = self.utxos.get(txn, decision)?.ok_or(Error::NoUtxo {
-                                outpoint: *decision,
-                            })?;
+                            let decision = self.get_utxo(txn, decision)?;
                             let size = match decision.content {
                                 sdk_types::Content::Custom(HivemindContent::Decision {
                                     size,
@@ -310,110 +1088,219 @@ impl State {
                             };
                             shape.push(size);
                         }
-                        let outcomes = std::iter::repeat(None).take(shape.len()).collect();
+                        let outcomes = std::iter::repeat_n(None, shape.len()).collect();
+                        let size: u32 = shape.iter().product();
+                        let state = vec![dec!(0); size as usize];
+                        self.put(txn, &mut undo, Db::Vectors, &outpoint, &state)?;
+                        let snapshot = MarketSnapshot {
+                            delta: state.clone(),
+                            volume: state.clone(),
+                            state,
+                        };
+                        self.put_snapshot(txn, &mut undo, &outpoint, height, &snapshot)?;
+                        for decision in decisions {
+                            self.push_to_index(
+                                txn,
+                                &mut undo,
+                                Db::DecisionToMarkets,
+                                decision,
+                                outpoint,
+                            )?;
+                        }
 
-                        self.markets.put(
-                            txn,
-                            &outpoint,
-                            &Market {
-                                b: *b,
-                                decisions: decisions.clone(),
-                                shape,
-                                outcomes,
-                            },
-                        )?;
+                        let market = Market {
+                            b: *b,
+                            decisions: decisions.clone(),
+                            shape,
+                            outcomes,
+                            batch: *batch,
+                        };
+                        self.put(txn, &mut undo, Db::Markets, &outpoint, &market)?;
+                        events.push(StateEvent::MarketCreated { market: outpoint });
                     }
                     _ => {}
                 }
             }
-            let transaction = self.fill_transaction(txn, transaction)?;
-            let (market_to_delta, _, _) = self.get_deltas_and_values(txn, &transaction)?;
+            let (market_to_delta, _, _) = self.get_deltas_and_values(txn, &filled_transaction)?;
             for (market, delta) in &market_to_delta {
                 let body_delta = body_market_to_delta
                     .entry(*market)
                     .or_insert(DVector::from_element(delta.len(), dec!(0)));
                 *body_delta += delta;
+                if delta.iter().any(|d| *d != dec!(0)) {
+                    let cost = self.get_cost(txn, &HashMap::from([(*market, delta.clone())]))?;
+                    trade_events.push((events.len(), market_to_deltas.len(), *market));
+                    events.push(StateEvent::Trade {
+                        market: *market,
+                        txid,
+                        delta: delta.iter().copied().collect(),
+                        cost,
+                    });
+                }
+            }
+            market_to_deltas.push(market_to_delta);
+        }
+        let batch_costs = self.get_batch_costs(txn, &market_to_deltas)?;
+        for (index, transaction, market) in &trade_events {
+            if let (Some(batch_cost), StateEvent::Trade { cost, .. }) =
+                (batch_costs[*transaction].get(market), &mut events[*index])
+            {
+                *cost = *batch_cost;
             }
         }
+        // Batch auction refunds are added to the output at vout `outputs.len()`, the change of
+        // the first Trade output if there is one, see `batch_refund_address`.
+        let batch_refunds = self.get_batch_refunds(txn, &market_to_deltas)?;
+        for ((transaction, refund), refund_address) in body
+            .transactions
+            .iter()
+            .zip(batch_refunds)
+            .zip(refund_addresses)
+        {
+            let refund = refund_value(refund)?;
+            let refund_address = match refund_address {
+                Some(refund_address) if refund > 0 => refund_address,
+                _ => continue,
+            };
+            let outpoint = OutPoint::Regular {
+                txid: transaction.txid(),
+                vout: transaction.outputs.len() as u32,
+            };
+            let change = match txn.get::<_, Output>(Db::Utxos, &outpoint)? {
+                Some(change) => change.get_value(),
+                None => 0,
+            };
+            let change = Output {
+                address: refund_address,
+                content: sdk_types::Content::Value(change + refund),
+            };
+            self.put_utxo(txn, &mut undo, &outpoint, &change)?;
+        }
         for (market, delta) in &body_market_to_delta {
-            let state = self
-                .vectors
-                .get(txn, market)?
-                .ok_or(Error::NoUtxo { outpoint: *market })?;
-            let state = DVector::from(state);
+            let state = DVector::from(self.get_vector(txn, market)?);
             let new_state = state + delta;
             let new_state: Vec<Decimal> = new_state.iter().copied().collect();
-            self.vectors.put(txn, market, &new_state)?;
+            self.put(txn, &mut undo, Db::Vectors, market, &new_state)?;
+            // Volume is summed over transactions, so trades that offset each other still count.
+            let volume = market_to_deltas
+                .iter()
+                .filter_map(|market_to_delta| market_to_delta.get(market))
+                .fold(
+                    DVector::from_element(delta.len(), dec!(0)),
+                    |volume, delta| volume + delta.map(|d| d.abs()),
+                );
+            let snapshot = MarketSnapshot {
+                state: new_state,
+                delta: delta.iter().copied().collect(),
+                volume: volume.iter().copied().collect(),
+            };
+            self.put_snapshot(txn, &mut undo, market, height, &snapshot)?;
         }
 
         // After all market decisions are resolved the market itself is resolved.
         // All Position outputs with share == outcome turn into Value outputs.
         // All other Position outputs are removed.
 
-        let mut updated_markets = vec![];
         let mut resolved_markets = vec![];
-        for item in self.markets.iter(txn)? {
-            let (outpoint, market) = item?;
-            let mut outcomes = vec![];
-            for decision in &market.decisions {
-                outcomes.push(decision_to_outcome.get(decision).copied());
-            }
-            if outcomes.iter().all(Option::is_some) {
-                let outcomes: Vec<u32> = outcomes.iter().copied().map(Option::unwrap).collect();
-                resolved_markets.push((outpoint, outcomes));
+        for (decision, outcome) in &decision_to_outcome {
+            for outpoint in self.get_index(txn, Db::DecisionToMarkets, decision)? {
+                // Markets missing from the state were pruned after they resolved.
+                let mut market: Market = match txn.get(Db::Markets, &outpoint)? {
+                    Some(market) => market,
+                    None => continue,
+                };
+                // The first resolution of a decision is final.
+                let mut resolved = false;
+                for (market_decision, market_outcome) in
+                    market.decisions.iter().zip(market.outcomes.iter_mut())
+                {
+                    if market_decision == decision && market_outcome.is_none() {
+                        *market_outcome = Some(*outcome);
+                        resolved = true;
+                    }
+                }
+                if !resolved {
+                    continue;
+                }
+                if market.outcomes.iter().all(Option::is_some) {
+                    self.push_to_index(txn, &mut undo, Db::ResolvedMarkets, &height, outpoint)?;
+                    let outcomes: Vec<u32> = market
+                        .outcomes
+                        .iter()
+                        .copied()
+                        .map(Option::unwrap)
+                        .collect();
+                    events.push(StateEvent::MarketResolved {
+                        market: outpoint,
+                        outcomes: outcomes.clone(),
+                    });
+                    resolved_markets.push((outpoint, outcomes));
+                }
+                self.put(txn, &mut undo, Db::Markets, &outpoint, &market)?;
             }
-            updated_markets.push((outpoint, Market { outcomes, ..market }));
         }
         for (outpoint, outcomes) in &resolved_markets {
-            let resolved_positions =
-                self.market_to_positions
-                    .get(txn, &outpoint)?
-                    .ok_or(Error::NoUtxo {
-                        outpoint: *outpoint,
-                    })?;
+            let resolved_positions = self.get_market_positions(txn, outpoint)?;
+            // Validated outcomes are below the size of their decision, so they cover one index.
+            let winning_index = self.share_to_flat_index(txn, outpoint, outcomes)?[0];
             for position_outpoint in &resolved_positions {
-                let position = self
-                    .utxos
-                    .get(txn, position_outpoint)?
-                    .ok_or(Error::NoUtxo {
-                        outpoint: *outpoint,
-                    })?;
-                match &position.content {
-                    sdk_types::Content::Custom(HivemindContent::Position {
-                        share, value, ..
-                    }) => {
-                        if share == outcomes {
-                            let content = sdk_types::Content::<HivemindContent>::Value(*value);
-                            self.utxos.put(
-                                txn,
-                                position_outpoint,
-                                &Output {
-                                    content,
-                                    ..position.clone()
-                                },
-                            )?;
-                        } else {
-                            self.utxos.delete(txn, position_outpoint)?;
+                let position = self.get_utxo(txn, position_outpoint)?;
+                let positions = match &position.content {
+                    sdk_types::Content::Custom(content) => {
+                        match self.get_flat_positions(txn, content)? {
+                            Some((_, positions)) => positions,
+                            None => unreachable!(),
                         }
                     }
                     _ => unreachable!(),
+                };
+                // A bundle pays out the sum of all of its winning shares.
+                let payout: u64 = positions
+                    .iter()
+                    .filter(|(flat_index, _)| *flat_index == winning_index)
+                    .map(|(_, value)| value)
+                    .sum();
+                if payout > 0 {
+                    let content = sdk_types::Content::<HivemindContent>::Value(payout);
+                    let output = Output {
+                        content,
+                        ..position.clone()
+                    };
+                    self.put_utxo(txn, &mut undo, position_outpoint, &output)?;
+                    events.push(StateEvent::PositionPaidOut {
+                        market: *outpoint,
+                        position: *position_outpoint,
+                        value: payout,
+                    });
+                } else {
+                    self.delete_utxo(txn, &mut undo, position_outpoint)?;
+                    events.push(StateEvent::PositionVoided {
+                        market: *outpoint,
+                        position: *position_outpoint,
+                    });
+                }
+                let key = position_key(outpoint, position_outpoint)?;
+                self.write(txn, &mut undo, Db::MarketToPositions, key, None)?;
+            }
+            // Ask orders are settled together with positions, only bids stay open so their
+            // owners can cancel them.
+            for order in self.get_index(txn, Db::MarketToOrders, outpoint)? {
+                if resolved_positions.contains(&order) {
+                    self.remove_from_index(txn, &mut undo, Db::MarketToOrders, outpoint, &order)?;
                 }
             }
         }
-        for (outpoint, market) in &updated_markets {
-            self.markets.put(txn, outpoint, market)?;
-        }
-        Ok(())
+        self.connect_bundle(txn, &mut undo, height)?;
+        self.put_undo(txn, height, &undo)?;
+        Ok(events)
     }
 
-    fn get_market_funding_cost(&self, txn: &RoTxn, output: &Output) -> Result<u64, Error> {
+    fn get_market_funding_cost(&self, txn: &impl StoreRead, output: &Output) -> Result<u64, Error> {
         match &output.content {
-            sdk_types::Content::Custom(HivemindContent::Market { b, decisions }) => {
+            sdk_types::Content::Custom(HivemindContent::Market { b, decisions, .. }) => {
                 let mut size: u32 = 1;
                 for outpoint in decisions {
-                    let decision = self.utxos.get(txn, outpoint)?.ok_or(Error::NoUtxo {
-                        outpoint: *outpoint,
-                    })?;
+                    let decision = self.get_utxo(txn, outpoint)?;
                     size *= match decision.content {
                         sdk_types::Content::Custom(HivemindContent::Decision { size, .. }) => {
                             Ok(size)
@@ -433,6 +1320,83 @@ impl State {
     }
 }
 
+fn decode_decision_key(key: &[u8]) -> Result<(u32, OutPoint), Error> {
+    let corrupt = || Error::CorruptIndex {
+        db: Db::HeightToDecisions,
+    };
+    let height = key.get(..4).ok_or_else(corrupt)?;
+    let height = u32::from_be_bytes(height.try_into().map_err(|_| corrupt())?);
+    Ok((height, bincode::deserialize(&key[4..])?))
+}
+
+// Index entries are the encoded key followed by the encoded outpoint, with empty values, so
+// they are listed with a prefix iterator.
+fn index_key<K: Serialize>(key: &K, outpoint: &OutPoint) -> Result<Vec<u8>, Error> {
+    let mut key = bincode::serialize(key)?;
+    key.extend(bincode::serialize(outpoint)?);
+    Ok(key)
+}
+
+fn position_key(market: &OutPoint, position: &OutPoint) -> Result<Vec<u8>, Error> {
+    index_key(market, position)
+}
+
+fn address_key(address: &Address, outpoint: &OutPoint) -> Result<Vec<u8>, Error> {
+    index_key(address, outpoint)
+}
+
+/// Takes the complete sets out of a delta of a market state, and returns how many were minted,
+/// or burned if negative. They are worth one unit each, whatever the market maker's prices.
+pub(crate) fn net_complete_sets(delta: &mut DVector<Decimal>) -> Decimal {
+    let minted = delta.iter().copied().min().unwrap_or(dec!(0));
+    if minted > dec!(0) {
+        delta.iter_mut().for_each(|d| *d -= minted);
+        return minted;
+    }
+    let burned = -delta.iter().copied().max().unwrap_or(dec!(0));
+    if burned > dec!(0) {
+        delta.iter_mut().for_each(|d| *d += burned);
+        return -burned;
+    }
+    dec!(0)
+}
+
+// Rounded down. Negative refunds are paid out of the fee, see `validate_transactions`.
+fn refund_value(refund: Decimal) -> Result<u64, Error> {
+    if refund <= dec!(0) {
+        return Ok(0);
+    }
+    let refund = refund.floor();
+    refund
+        .to_u64()
+        .ok_or(Error::U64Overflow { decimal: refund })
+}
+
+// The change address of the first Trade output, or else the address of the first input.
+fn batch_refund_address(transaction: &FilledTransaction) -> Option<Address> {
+    let change = transaction
+        .transaction
+        .outputs
+        .iter()
+        .find_map(|output| match &output.content {
+            sdk_types::Content::Custom(HivemindContent::Trade { change, .. }) => Some(*change),
+            _ => None,
+        });
+    change.or_else(|| {
+        transaction
+            .spent_utxos
+            .first()
+            .map(|spent_utxo| spent_utxo.address)
+    })
+}
+
+pub(crate) fn get_votecoin_value(output: &Output) -> u64 {
+    match &output.content {
+        sdk_types::Content::Custom(content) => content.get_votecoin_value(),
+        _ => 0,
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("authorization error")]
@@ -441,6 +1405,10 @@ pub enum Error {
     Sdk(#[from] sdk_types::Error),
     #[error("heed error")]
     Heed(#[from] heed::Error),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
     #[error("utxo {outpoint} doesn't exist")]
     NoUtxo { outpoint: OutPoint },
     #[error("outpoint {outpoint} doesn't refer to a valid market")]
@@ -451,12 +1419,1027 @@ pub enum Error {
     NotEnoughValueIn,
     #[error("fee value is not enough to cover coinbase value out")]
     NotEnoughFeeValue,
+    #[error("transaction fee {fee} is lower than the minimum fee")]
+    FeeTooLow { fee: u64 },
+    #[error("too many fee exempt transactions in this block")]
+    TooManyFeeExemptTransactions,
     #[error("utxo {outpoint} was spent more than once in this block")]
     UtxoDoubleSpent { outpoint: OutPoint },
     #[error("decision output is spent before its resolvable height was reached")]
     DecisionSpentEarly,
     #[error("decision output is spent without a resolution output being created")]
     DecisionSpentWithoutResolution,
+    #[error("resolution of decision {decision} doesn't spend it")]
+    ResolutionWithoutDecision { decision: OutPoint },
+    #[error("decision {decision} has no outcome {outcome}")]
+    InvalidOutcome { decision: OutPoint, outcome: u32 },
+    #[error("decision {decision} is resolved more than once")]
+    DuplicateResolution { decision: OutPoint },
     #[error("can't create market using a decision that is already resolvable at this height")]
     MarketUsingResolvableDecision,
+    #[error("predicate refers to decisions or outcomes that are not in the market")]
+    InvalidPredicate,
+    #[error("share doesn't match the shape of its market")]
+    InvalidShare,
+    #[error("limit order must have a positive amount and price")]
+    InvalidLimitOrder,
+    #[error("limit order is spent without paying its owner for the filled amount")]
+    LimitOrderNotPaid,
+    #[error("utxo {outpoint} is spent without its owner's authorization")]
+    NotAuthorized { outpoint: OutPoint },
+    #[error("body has more authorizations than inputs")]
+    TooManyAuthorizations,
+    #[error("trade costs {cost} which is more than its max cost {max_cost}")]
+    MaxCostExceeded { max_cost: u64, cost: Decimal },
+    #[error("no undo data for the body connected at height {height}")]
+    NoUndoData { height: u32 },
+    #[error("{db:?} has a malformed key")]
+    CorruptIndex { db: Db },
+    #[error("databases have schema version {found}, but at most {supported} is supported")]
+    IncompatibleSchema { found: u32, supported: u32 },
+    #[error("state commitment node is corrupt")]
+    CorruptCommitment,
+    #[error("{db:?} is not part of the state commitment")]
+    NotCommitted { db: Db },
+    #[error("decision {decision} is not used by any unpruned market and has no resolution")]
+    DecisionOutcomeUnprovable { decision: OutPoint },
+    #[error("snapshot can only be imported into an empty state")]
+    StateNotEmpty,
+    #[error("file is not a snapshot of a supported version")]
+    InvalidSnapshot,
+    #[error("snapshot checksum doesn't match its contents")]
+    SnapshotChecksumMismatch,
+    #[error("snapshot doesn't match the trusted state commitment")]
+    CommitmentMismatch,
+    #[error("market {market} is resolved and can't be traded on")]
+    MarketResolved { market: OutPoint },
+    #[error("body doesn't match the body hash of its header")]
+    BodyHashMismatch,
+    #[error("unknown block {hash:?}")]
+    UnknownBlock { hash: block::BlockHash },
+    #[error("no block is connected yet")]
+    NoTip,
+    #[error("block height {height} doesn't follow its parent")]
+    InvalidBlockHeight { height: u32 },
+    #[error("block {hash:?} can't replace the genesis block")]
+    GenesisReplaced { hash: block::BlockHash },
+    #[error("votecoin value out is greater than votecoin value in")]
+    NotEnoughVoteCoinIn,
+    #[error("toml error")]
+    Toml(#[from] toml::de::Error),
+    #[error("json error")]
+    Json(#[from] serde_json::Error),
+    #[error("genesis output {index} must hold either value or reputation")]
+    InvalidGenesisOutput { index: usize },
+    #[error("coinbase outputs can only hold value")]
+    InvalidCoinbaseOutput,
+    #[error("oracle rewards overflow")]
+    OracleRewardOverflow,
+    #[error("withdrawal {outpoint} can't be spent")]
+    WithdrawalSpent { outpoint: OutPoint },
+    #[error("expected deposit {expected}, found deposit {found}")]
+    InvalidDepositSequence { expected: u64, found: u64 },
+    #[error("deposit {sequence} is not on the mainchain")]
+    UnknownDeposit { sequence: u64 },
+    #[error("no mainchain to check deposits against")]
+    NoMainchain,
+    #[error("bundle status doesn't match the pending bundle on the mainchain")]
+    InvalidBundleStatus,
+    #[error("bundle interval must be at least 1")]
+    InvalidBundleInterval,
+    #[error("mainchain rejected the bundle")]
+    BundleRejected,
+    #[error("blocks down to height {height} can't be disconnected, its bundle was proposed")]
+    BundleProposed { height: u32 },
+    #[error("state is pruned and misses entries of resolved markets")]
+    StatePruned,
+    #[error("entry of {db:?} was pruned")]
+    EntryPruned { db: Db },
+    #[error("database {db:?} doesn't exist")]
+    MissingDatabase { db: Db },
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const FUNDS: u64 = 10_000_000_000_000_000;
+
+    pub(crate) fn address(n: u8) -> Address {
+        // Addresses are fixed size hashes, so any bytes of that size decode as one.
+        bincode::deserialize(&[n; 32]).unwrap()
+    }
+
+    pub(crate) fn value(address: Address, value: u64) -> Output {
+        Output {
+            address,
+            content: sdk_types::Content::Value(value),
+        }
+    }
+
+    pub(crate) fn custom(address: Address, content: HivemindContent) -> Output {
+        Output {
+            address,
+            content: sdk_types::Content::Custom(content),
+        }
+    }
+
+    pub(crate) fn value_of(output: &Output) -> u64 {
+        match output.content {
+            sdk_types::Content::Value(value) => value,
+            _ => panic!("not a value output"),
+        }
+    }
+
+    pub(crate) fn outpoint(transaction: &Transaction, vout: u32) -> OutPoint {
+        OutPoint::Regular {
+            txid: transaction.txid(),
+            vout,
+        }
+    }
+
+    pub(crate) fn body(transactions: Vec<Transaction>) -> Body {
+        Body {
+            coinbase: vec![],
+            transactions,
+            authorizations: vec![],
+        }
+    }
+
+    pub(crate) fn dump(store: &MemoryStore, dbs: &[Db]) -> Vec<(Db, Vec<Entry>)> {
+        dbs.iter()
+            .map(|db| {
+                let entries = store.prefix_iter(*db, &[]).unwrap();
+                (*db, entries.map(Result::unwrap).collect())
+            })
+            .collect()
+    }
+
+    // A market on one binary decision and three accounts holding `FUNDS`, connected at heights
+    // 1 and 2 without validation, so inputs need no authorizations.
+    pub(crate) struct Fixture {
+        pub state: State,
+        pub store: MemoryStore,
+        pub decision: OutPoint,
+        pub market: OutPoint,
+        pub accounts: Vec<(Address, OutPoint)>,
+    }
+
+    impl Fixture {
+        pub fn new(batch: bool) -> Self {
+            let state = State::new();
+            let store = MemoryStore::new().unwrap();
+            let addresses: Vec<Address> = (1..=3).map(address).collect();
+            let decision = HivemindContent::Decision {
+                query: [0; 32],
+                size: 2,
+                resolvable_height: 100,
+            };
+            let mut outputs = vec![custom(addresses[0], decision)];
+            outputs.extend(addresses.iter().map(|address| value(*address, FUNDS)));
+            let funding = Transaction {
+                inputs: vec![],
+                outputs,
+            };
+            let decision = outpoint(&funding, 0);
+            let market = HivemindContent::Market {
+                b: 1,
+                decisions: vec![decision],
+                batch,
+            };
+            let market = Transaction {
+                inputs: vec![],
+                outputs: vec![custom(addresses[0], market)],
+            };
+            let accounts = addresses
+                .into_iter()
+                .zip(1..)
+                .map(|(address, vout)| (address, outpoint(&funding, vout)))
+                .collect();
+            let mut fixture = Self {
+                state,
+                store,
+                decision,
+                market: outpoint(&market, 0),
+                accounts,
+            };
+            fixture.connect(1, vec![funding]);
+            fixture.connect(2, vec![market]);
+            fixture
+        }
+
+        /// Connects, stores and publishes a body, and returns its events.
+        pub fn connect(&mut self, height: u32, transactions: Vec<Transaction>) -> Vec<StateEvent> {
+            let events = self
+                .state
+                .connect_body(&mut self.store, &body(transactions), height)
+                .unwrap();
+            self.state
+                .put_events(&mut self.store, height, &events)
+                .unwrap();
+            self.state.publish_events(height, events.clone());
+            events
+        }
+
+        /// Validates a transaction as if it was in the next block, and returns its fee.
+        pub fn validate(&self, transaction: &Transaction) -> Result<u64, Error> {
+            let transaction = self.state.fill_transaction(&self.store, transaction)?;
+            self.state
+                .validate_transaction(&self.store, &transaction, 3)
+        }
+
+        pub fn filled_orders(&self, transaction: &Transaction) -> HashSet<OutPoint> {
+            let transaction = self
+                .state
+                .fill_transaction(&self.store, transaction)
+                .unwrap();
+            self.state
+                .validate_limit_orders(&self.store, &transaction)
+                .unwrap()
+        }
+
+        pub fn utxo(&self, outpoint: &OutPoint) -> Option<Output> {
+            self.store.get(Db::Utxos, outpoint).unwrap()
+        }
+
+        pub fn position(&self, address: Address, share: u32, value: u64) -> Output {
+            let position = HivemindContent::Position {
+                market: self.market,
+                share: vec![share],
+                value,
+            };
+            custom(address, position)
+        }
+
+        pub fn vector(&self) -> Vec<Decimal> {
+            self.state.get_vector(&self.store, &self.market).unwrap()
+        }
+
+        /// Resolves the decision to `outcome` in a block at `height`.
+        pub fn resolve(&mut self, height: u32, outcome: u32) {
+            let resolution = HivemindContent::Resolution {
+                decision: self.decision,
+                outcome,
+            };
+            let resolution = Transaction {
+                inputs: vec![self.decision],
+                outputs: vec![custom(self.accounts[0].0, resolution)],
+            };
+            self.connect(height, vec![resolution]);
+        }
+    }
+
+    #[test]
+    fn bundles_pay_out_the_sum_of_their_winning_shares() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let bundle = |shares: &[(u32, u64)]| HivemindContent::PositionBundle {
+            market,
+            shares: shares
+                .iter()
+                .map(|(share, value)| (vec![*share], *value))
+                .collect(),
+        };
+        let (alice, alice_funds) = fixture.accounts[0];
+        let (bob, bob_funds) = fixture.accounts[1];
+        let buy = |address, funds, shares: &[(u32, u64)]| Transaction {
+            inputs: vec![funds],
+            outputs: vec![
+                custom(address, bundle(shares)),
+                value(address, FUNDS - 1000),
+            ],
+        };
+        let alice_buy = buy(alice, alice_funds, &[(0, 1000), (1, 400)]);
+        let bob_buy = buy(bob, bob_funds, &[(1, 600)]);
+        // A bundle costs the same as the positions it holds.
+        let fee = fixture.validate(&alice_buy).unwrap();
+        let positions = Transaction {
+            inputs: vec![alice_funds],
+            outputs: vec![
+                fixture.position(alice, 0, 1000),
+                fixture.position(alice, 1, 400),
+                value(alice, FUNDS - 1000),
+            ],
+        };
+        assert_eq!(fixture.validate(&positions).unwrap(), fee);
+        // Every share of a bundle must be a share of its market.
+        assert!(matches!(
+            fixture.validate(&buy(alice, alice_funds, &[(0, 1000), (2, 400)])),
+            Err(Error::InvalidShare)
+        ));
+        fixture.connect(3, vec![alice_buy.clone(), bob_buy.clone()]);
+        // Alice's bundle holds 400 complete sets, which are minted outside the market maker.
+        assert_eq!(fixture.vector(), vec![dec!(600), dec!(600)]);
+
+        fixture.resolve(100, 0);
+        let payout = fixture.utxo(&outpoint(&alice_buy, 0)).unwrap();
+        assert_eq!(payout.address, alice);
+        assert_eq!(value_of(&payout), 1000);
+        // Bundles without winning shares are removed.
+        assert_eq!(fixture.utxo(&outpoint(&bob_buy, 0)), None);
+    }
+
+    #[test]
+    fn fee_exempt_transactions_are_limited_per_block() {
+        let mut fixture = Fixture::new(false);
+        fixture.state.params = ChainParams {
+            min_fee: 10,
+            max_fee_exempt_transactions: 3,
+            ..ChainParams::default()
+        };
+        let (alice, alice_funds) = fixture.accounts[0];
+        let count = fixture.state.params.max_fee_exempt_transactions + 1;
+        let positions = Transaction {
+            inputs: vec![],
+            outputs: (0..2 * count)
+                .map(|_| fixture.position(alice, 0, 10))
+                .collect(),
+        };
+        fixture.connect(3, vec![positions.clone()]);
+        // Every transaction merges two positions into one.
+        let merges: Vec<Transaction> = (0..count as u32)
+            .map(|i| Transaction {
+                inputs: vec![outpoint(&positions, 2 * i), outpoint(&positions, 2 * i + 1)],
+                outputs: vec![fixture.position(alice, 0, 20)],
+            })
+            .collect();
+        let txn = &fixture.store;
+        let merge = fixture.state.fill_transaction(txn, &merges[0]).unwrap();
+        assert!(fixture.state.is_fee_exempt(txn, &merge).unwrap());
+        // Merges pay no fee, below the minimum, which only exempt transactions may.
+        fixture
+            .state
+            .validate_transactions(txn, &body(merges[1..].to_vec()), 3)
+            .unwrap();
+        assert!(matches!(
+            fixture.state.validate_transactions(txn, &body(merges), 3),
+            Err(Error::TooManyFeeExemptTransactions)
+        ));
+
+        // Buying shares from the market maker is never exempt, even while merging positions.
+        let buy = Transaction {
+            inputs: vec![outpoint(&positions, 0), outpoint(&positions, 1)],
+            outputs: vec![fixture.position(alice, 0, 30)],
+        };
+        let buy = fixture.state.fill_transaction(txn, &buy).unwrap();
+        assert!(!fixture.state.is_fee_exempt(txn, &buy).unwrap());
+        let transfer = |fee| Transaction {
+            inputs: vec![alice_funds],
+            outputs: vec![value(alice, FUNDS - fee)],
+        };
+        assert!(matches!(
+            fixture
+                .state
+                .validate_transactions(txn, &body(vec![transfer(9)]), 3),
+            Err(Error::FeeTooLow { fee: 9 })
+        ));
+        assert_eq!(
+            fixture
+                .state
+                .validate_transactions(txn, &body(vec![transfer(10)]), 3)
+                .unwrap(),
+            10
+        );
+    }
+
+    #[test]
+    fn predicate_positions_are_priced_and_paid_as_the_shares_they_hold() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let (alice, funds) = fixture.accounts[0];
+        let first = Predicate::Outcomes {
+            decision: 0,
+            outcomes: vec![0],
+        };
+        let predicate = |predicate: Predicate| {
+            let position = HivemindContent::PredicatePosition {
+                market,
+                predicate,
+                value: 1000,
+            };
+            custom(alice, position)
+        };
+        let buy = |output| Transaction {
+            inputs: vec![funds],
+            outputs: vec![output, value(alice, FUNDS - 1000)],
+        };
+        assert_eq!(
+            fixture.validate(&buy(predicate(first.clone()))).unwrap(),
+            fixture
+                .validate(&buy(fixture.position(alice, 0, 1000)))
+                .unwrap()
+        );
+        // A predicate that always holds is a complete set, and costs exactly its value.
+        let always = Predicate::Or(vec![first.clone(), Predicate::Not(Box::new(first.clone()))]);
+        assert_eq!(fixture.validate(&buy(predicate(always))).unwrap(), 0);
+        let invalid = Predicate::Outcomes {
+            decision: 1,
+            outcomes: vec![0],
+        };
+        assert!(matches!(
+            fixture.validate(&buy(predicate(invalid))),
+            Err(Error::InvalidPredicate)
+        ));
+
+        let positions = Transaction {
+            inputs: vec![funds],
+            outputs: vec![
+                predicate(first.clone()),
+                predicate(Predicate::Not(Box::new(first))),
+                value(alice, FUNDS - 1000),
+            ],
+        };
+        fixture.connect(3, vec![positions.clone()]);
+        // Together the predicates are a complete set, which leaves the market maker untouched.
+        assert_eq!(fixture.vector(), vec![dec!(0), dec!(0)]);
+        fixture.resolve(100, 0);
+        assert_eq!(
+            value_of(&fixture.utxo(&outpoint(&positions, 0)).unwrap()),
+            1000
+        );
+        assert_eq!(fixture.utxo(&outpoint(&positions, 1)), None);
+    }
+
+    #[test]
+    fn wildcard_shares_cover_every_outcome_of_their_decision() {
+        let mut fixture = Fixture::new(false);
+        let (alice, funds) = fixture.accounts[0];
+        let decision = HivemindContent::Decision {
+            query: [1; 32],
+            size: 3,
+            resolvable_height: 100,
+        };
+        let decision = Transaction {
+            inputs: vec![],
+            outputs: vec![custom(alice, decision)],
+        };
+        fixture.connect(3, vec![decision.clone()]);
+        let decisions = vec![fixture.decision, outpoint(&decision, 0)];
+        let market = HivemindContent::Market {
+            b: 1,
+            decisions: decisions.clone(),
+            batch: false,
+        };
+        let market = Transaction {
+            inputs: vec![],
+            outputs: vec![custom(alice, market)],
+        };
+        fixture.connect(4, vec![market.clone()]);
+        let market = outpoint(&market, 0);
+        let position = |share: Vec<u32>, value| {
+            let position = HivemindContent::Position {
+                market,
+                share,
+                value,
+            };
+            custom(alice, position)
+        };
+        let buy = |outputs: Vec<Output>| Transaction {
+            inputs: vec![funds],
+            outputs: [outputs, vec![value(alice, FUNDS - 1000)]].concat(),
+        };
+        let marginal = buy(vec![
+            position(vec![1, WILDCARD], 100),
+            position(vec![WILDCARD, 2], 10),
+        ]);
+        // Marginal shares cost the same as all the shares they cover.
+        let shares = [
+            (vec![1, 0], 100),
+            (vec![1, 1], 100),
+            (vec![1, 2], 110),
+            (vec![0, 2], 10),
+        ];
+        let bundle = HivemindContent::PositionBundle {
+            market,
+            shares: shares.into_iter().collect(),
+        };
+        assert_eq!(
+            fixture.validate(&marginal).unwrap(),
+            fixture.validate(&buy(vec![custom(alice, bundle)])).unwrap()
+        );
+        for share in [vec![2, 0], vec![0], vec![0, WILDCARD, 0]] {
+            assert!(matches!(
+                fixture.validate(&buy(vec![position(share, 100)])),
+                Err(Error::InvalidShare)
+            ));
+        }
+        fixture.connect(5, vec![marginal.clone()]);
+        let vector = { fixture.state.get_vector(&fixture.store, &market).unwrap() };
+        let expected = [0, 0, 10, 100, 100, 110];
+        assert_eq!(vector, expected.map(Decimal::from).to_vec());
+
+        // Both decisions resolve in the same block, so the market resolves to [1, 2].
+        let resolutions = decisions
+            .iter()
+            .zip([1, 2])
+            .map(|(decision, outcome)| {
+                let resolution = HivemindContent::Resolution {
+                    decision: *decision,
+                    outcome,
+                };
+                Transaction {
+                    inputs: vec![*decision],
+                    outputs: vec![custom(alice, resolution)],
+                }
+            })
+            .collect();
+        fixture.connect(100, resolutions);
+        for (vout, expected) in [(0, 100), (1, 10)] {
+            let payout = fixture.utxo(&outpoint(&marginal, vout)).unwrap();
+            assert_eq!(value_of(&payout), expected);
+        }
+    }
+
+    #[test]
+    fn complete_sets_are_minted_and_burned_at_par() {
+        let mut fixture = Fixture::new(false);
+        let (alice, funds) = fixture.accounts[0];
+        let mint = Transaction {
+            inputs: vec![funds],
+            outputs: vec![
+                fixture.position(alice, 0, 1000),
+                fixture.position(alice, 1, 1000),
+                value(alice, FUNDS - 1000),
+            ],
+        };
+        assert_eq!(fixture.validate(&mint).unwrap(), 0);
+        fixture.connect(3, vec![mint.clone()]);
+        // The market maker is not involved, so prices don't move.
+        assert_eq!(fixture.vector(), vec![dec!(0), dec!(0)]);
+
+        let burn = |value_out| Transaction {
+            inputs: vec![outpoint(&mint, 0), outpoint(&mint, 1)],
+            outputs: vec![value(alice, value_out)],
+        };
+        assert_eq!(fixture.validate(&burn(1000)).unwrap(), 0);
+        {
+            let txn = &fixture.store;
+            let transaction = fixture.state.fill_transaction(txn, &burn(1000)).unwrap();
+            assert!(fixture.state.is_fee_exempt(txn, &transaction).unwrap());
+        }
+        assert!(matches!(
+            fixture.validate(&burn(1001)),
+            Err(Error::NotEnoughValueIn)
+        ));
+        fixture.connect(4, vec![burn(1000)]);
+        assert_eq!(fixture.vector(), vec![dec!(0), dec!(0)]);
+    }
+
+    #[test]
+    fn limit_orders_are_filled_partially_filled_and_cancelled() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let (bob, bob_funds) = fixture.accounts[1];
+        let (carol, carol_funds) = fixture.accounts[2];
+        let ask = |amount| {
+            let order = HivemindContent::LimitOrder {
+                market,
+                share: vec![0],
+                side: Side::Ask,
+                amount,
+                price: dec!(0.6),
+            };
+            custom(bob, order)
+        };
+        // Bob mints a complete set and offers the first share.
+        let post = Transaction {
+            inputs: vec![bob_funds],
+            outputs: vec![
+                ask(100),
+                fixture.position(bob, 1, 100),
+                value(bob, FUNDS - 100),
+            ],
+        };
+        assert_eq!(fixture.validate(&post).unwrap(), 0);
+        let empty = Transaction {
+            inputs: vec![bob_funds],
+            outputs: vec![ask(0), value(bob, FUNDS)],
+        };
+        assert!(matches!(
+            fixture.validate(&empty),
+            Err(Error::InvalidLimitOrder)
+        ));
+        fixture.connect(3, vec![post.clone()]);
+        let order = outpoint(&post, 0);
+        let open_orders = |fixture: &Fixture| {
+            let txn = &fixture.store;
+            fixture.state.get_open_orders(txn, &market).unwrap()
+        };
+        assert_eq!(open_orders(&fixture)[0].0, order);
+
+        let fill = Transaction {
+            inputs: vec![order, carol_funds],
+            outputs: vec![
+                value(bob, 60),
+                fixture.position(carol, 0, 100),
+                value(carol, FUNDS - 60),
+            ],
+        };
+        assert_eq!(fixture.validate(&fill).unwrap(), 0);
+        assert!(fixture.filled_orders(&fill).contains(&order));
+
+        let partial_fill = |payment| Transaction {
+            inputs: vec![order, carol_funds],
+            outputs: vec![
+                value(bob, payment),
+                fixture.position(carol, 0, 60),
+                ask(40),
+                value(carol, FUNDS - 36),
+            ],
+        };
+        assert_eq!(fixture.validate(&partial_fill(36)).unwrap(), 0);
+        assert!(fixture.filled_orders(&partial_fill(36)).contains(&order));
+        // Underpaying doesn't fill the order, so only bob could sign for it.
+        assert!(fixture.filled_orders(&partial_fill(35)).is_empty());
+
+        let cancel = Transaction {
+            inputs: vec![order],
+            outputs: vec![fixture.position(bob, 0, 100)],
+        };
+        assert_eq!(fixture.validate(&cancel).unwrap(), 0);
+        assert!(fixture.filled_orders(&cancel).is_empty());
+        fixture.connect(4, vec![cancel]);
+        assert!(open_orders(&fixture).is_empty());
+        // Cancelled orders leave no entries behind.
+        assert_eq!(dump(&fixture.store, &[Db::MarketToOrders])[0].1, vec![]);
+    }
+
+    #[test]
+    fn batch_trades_clear_at_one_price() {
+        // Large enough to move prices of a market with b = 1.
+        const AMOUNT: u64 = 1_000_000_000_000_000;
+        let mut fixture = Fixture::new(true);
+        let buys: Vec<Transaction> = fixture.accounts[..2]
+            .iter()
+            .zip(0..)
+            .map(|((address, funds), share)| Transaction {
+                inputs: vec![*funds],
+                outputs: vec![
+                    fixture.position(*address, share, AMOUNT),
+                    value(*address, FUNDS - AMOUNT),
+                ],
+            })
+            .collect();
+        let fees: Vec<u64> = buys
+            .iter()
+            .map(|buy| fixture.validate(buy).unwrap())
+            .collect();
+        // Together the buys are complete sets, so both shares clear at one half, less than either
+        // buy was quoted alone. Without a Trade output the difference is refunded to the address
+        // of the first input, right after the signed outputs.
+        let batch_fee = fixture
+            .state
+            .validate_transactions(&fixture.store, &body(buys.clone()), 3)
+            .unwrap();
+        assert_eq!(batch_fee, fees.iter().sum::<u64>());
+        // Buys of one share clear above their quotes, so transactions paying exactly their quotes
+        // can't pay for the batch.
+        let exact: Vec<Transaction> = fixture.accounts[..2]
+            .iter()
+            .map(|(address, funds)| {
+                let buy = |change| Transaction {
+                    inputs: vec![*funds],
+                    outputs: vec![
+                        fixture.position(*address, 0, AMOUNT),
+                        value(*address, change),
+                    ],
+                };
+                let fee = fixture.validate(&buy(FUNDS - AMOUNT)).unwrap();
+                buy(FUNDS - AMOUNT + fee)
+            })
+            .collect();
+        assert!(matches!(
+            fixture
+                .state
+                .validate_transactions(&fixture.store, &body(exact), 3),
+            Err(Error::NotEnoughValueIn)
+        ));
+        fixture.connect(3, buys.clone());
+        let mut refunds = 0;
+        for (buy, (address, _)) in buys.iter().zip(&fixture.accounts) {
+            let refund = fixture.utxo(&outpoint(buy, 2)).unwrap();
+            assert_eq!(refund.address, *address);
+            refunds += value_of(&refund);
+        }
+        assert!((AMOUNT - 4..=AMOUNT).contains(&(batch_fee + refunds)));
+        assert_eq!(
+            fixture.vector(),
+            vec![Decimal::from(AMOUNT), Decimal::from(AMOUNT)]
+        );
+    }
+
+    #[test]
+    fn batches_pay_exactly_the_lmsr_cost_of_their_net_delta() {
+        const AMOUNT: u64 = 1_000_000_000_000_000;
+        let mut fixture = Fixture::new(true);
+        let buys: Vec<Transaction> = fixture.accounts[..2]
+            .iter()
+            .zip([AMOUNT, AMOUNT / 3])
+            .map(|((address, funds), amount)| Transaction {
+                inputs: vec![*funds],
+                outputs: vec![
+                    fixture.position(*address, 0, amount),
+                    value(*address, FUNDS - amount),
+                ],
+            })
+            .collect();
+        let state = DVector::from(fixture.vector());
+        let batch_delta = DVector::from_vec(vec![Decimal::from(AMOUNT + AMOUNT / 3), dec!(0)]);
+        let batch_cost = lmsr_cost(dec!(1), &(&state + &batch_delta)) - lmsr_cost(dec!(1), &state);
+        let prices = lmsr_batch_prices(dec!(1), &state, &batch_delta);
+        // Uniform prices alone would charge a little more or less than the batch moves the market.
+        assert_ne!(prices.dot(&batch_delta), batch_cost);
+        let events = fixture.connect(3, buys);
+        let costs: Vec<Decimal> = events
+            .iter()
+            .filter_map(|event| match event {
+                StateEvent::Trade { cost, .. } => Some(*cost),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(costs.len(), 2);
+        assert_eq!(costs.iter().sum::<Decimal>(), batch_cost);
+        // Every share is still charged about the same.
+        let per_share = |cost: Decimal, amount: u64| cost / Decimal::from(amount);
+        assert!(
+            (per_share(costs[0], AMOUNT) - per_share(costs[1], AMOUNT / 3)).abs() < dec!(0.001)
+        );
+    }
+
+    #[test]
+    fn batch_refunds_are_added_to_the_change_of_the_first_trade() {
+        const AMOUNT: u64 = 1_000_000_000_000_000;
+        let mut fixture = Fixture::new(true);
+        let market = fixture.market;
+        let (alice, alice_funds) = fixture.accounts[0];
+        let (bob, bob_funds) = fixture.accounts[1];
+        let trade = |amount, max_cost| {
+            let trade = HivemindContent::Trade {
+                market,
+                share: vec![0],
+                amount,
+                max_cost,
+                change: alice,
+            };
+            custom(alice, trade)
+        };
+        let max_costs = [AMOUNT, AMOUNT / 2];
+        let trades = Transaction {
+            inputs: vec![alice_funds],
+            outputs: vec![
+                trade(AMOUNT, max_costs[0]),
+                trade(AMOUNT / 2, max_costs[1]),
+                value(alice, FUNDS - AMOUNT - AMOUNT / 2),
+            ],
+        };
+        // Bob buys the other share in the same block, which makes both shares cheaper than they
+        // were quoted.
+        let buy = Transaction {
+            inputs: vec![bob_funds],
+            outputs: vec![
+                fixture.position(bob, 1, 2 * AMOUNT),
+                value(bob, FUNDS - 2 * AMOUNT),
+            ],
+        };
+        let state = DVector::from(fixture.vector());
+        let cost = |amount: u64| {
+            let delta = DVector::from_vec(vec![Decimal::from(amount), dec!(0)]);
+            lmsr_cost(dec!(1), &(&state + delta)) - lmsr_cost(dec!(1), &state)
+        };
+        // The second trade is priced after the first.
+        let changes = [
+            max_costs[0] - cost(AMOUNT).ceil().to_u64().unwrap(),
+            max_costs[1]
+                - (cost(AMOUNT + AMOUNT / 2) - cost(AMOUNT))
+                    .ceil()
+                    .to_u64()
+                    .unwrap(),
+        ];
+        let block = vec![trades.clone(), buy.clone()];
+        fixture
+            .state
+            .validate_transactions(&fixture.store, &body(block.clone()), 3)
+            .unwrap();
+        let events = fixture.connect(3, block);
+        let charged = |transaction: &Transaction| {
+            events
+                .iter()
+                .find_map(|event| match event {
+                    StateEvent::Trade { txid, cost, .. } if *txid == transaction.txid() => {
+                        Some(*cost)
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let refund = refund_value(cost(AMOUNT + AMOUNT / 2) - charged(&trades)).unwrap();
+        assert!(refund > 0);
+
+        // Changes follow the three signed outputs in the order of the Trade outputs, and the
+        // refund goes to the first one.
+        let change = |vout| {
+            fixture
+                .utxo(&outpoint(&trades, vout))
+                .map(|change| value_of(&change))
+        };
+        assert!((changes[0] + refund..=changes[0] + refund + 1).contains(&change(3).unwrap()));
+        assert!((changes[1]..=changes[1] + 1).contains(&change(4).unwrap()));
+        assert_eq!(change(5), None);
+        // Bob has no Trade output, so his refund is paid to the address of his input.
+        let quoted = {
+            let delta = DVector::from_vec(vec![dec!(0), Decimal::from(2 * AMOUNT)]);
+            lmsr_cost(dec!(1), &(&state + delta)) - lmsr_cost(dec!(1), &state)
+        };
+        let refund = fixture.utxo(&outpoint(&buy, 2)).unwrap();
+        assert_eq!(refund.address, bob);
+        assert_eq!(
+            value_of(&refund),
+            refund_value(quoted - charged(&buy)).unwrap()
+        );
+    }
+
+    #[test]
+    fn trades_return_change_and_respect_max_cost() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let (alice, funds) = fixture.accounts[0];
+        let trade = |max_cost| {
+            let trade = HivemindContent::Trade {
+                market,
+                share: vec![0],
+                amount: 1000,
+                max_cost,
+                change: alice,
+            };
+            Transaction {
+                inputs: vec![funds],
+                outputs: vec![custom(alice, trade), value(alice, FUNDS - max_cost)],
+            }
+        };
+        // Buying 1000 of one of two outcomes costs a little over 500.
+        assert!(matches!(
+            fixture.validate(&trade(400)),
+            Err(Error::MaxCostExceeded { max_cost: 400, .. })
+        ));
+        let transaction = trade(600);
+        assert_eq!(fixture.validate(&transaction).unwrap(), 0);
+        fixture.connect(3, vec![transaction.clone()]);
+        let position = fixture.utxo(&outpoint(&transaction, 0)).unwrap();
+        assert!(matches!(
+            position.content,
+            sdk_types::Content::Custom(HivemindContent::Position { value: 1000, .. })
+        ));
+        // The change follows the transaction's own outputs.
+        let change = fixture.utxo(&outpoint(&transaction, 2)).unwrap();
+        assert!((99..=100).contains(&value_of(&change)));
+        assert_eq!(fixture.vector(), vec![dec!(1000), dec!(0)]);
+    }
+
+    #[test]
+    fn decisions_are_listed_by_resolvable_height_until_resolved() {
+        let mut fixture = Fixture::new(false);
+        let (alice, _) = fixture.accounts[0];
+        let decisions = [300, 200, 200].map(|resolvable_height| {
+            let decision = HivemindContent::Decision {
+                query: [1; 32],
+                size: 2,
+                resolvable_height,
+            };
+            custom(alice, decision)
+        });
+        let decisions = Transaction {
+            inputs: vec![],
+            outputs: decisions.to_vec(),
+        };
+        fixture.connect(3, vec![decisions.clone()]);
+        let within = |fixture: &Fixture, height, n| {
+            let txn = &fixture.store;
+            fixture
+                .state
+                .get_decisions_resolvable_within(txn, height, n)
+                .unwrap()
+        };
+        let (first, second, third) = (
+            outpoint(&decisions, 0),
+            outpoint(&decisions, 1),
+            outpoint(&decisions, 2),
+        );
+        assert_eq!(
+            within(&fixture, 100, 101),
+            vec![(100, fixture.decision), (200, second), (200, third)]
+        );
+        assert!(within(&fixture, 101, 99).is_empty());
+        assert!(within(&fixture, 300, 0).is_empty());
+
+        let resolution = Transaction {
+            inputs: vec![fixture.decision],
+            outputs: vec![custom(
+                alice,
+                HivemindContent::Resolution {
+                    decision: fixture.decision,
+                    outcome: 0,
+                },
+            )],
+        };
+        assert!(matches!(
+            fixture.validate(&resolution),
+            Err(Error::DecisionSpentEarly)
+        ));
+        fixture.resolve(100, 0);
+        assert_eq!(
+            within(&fixture, 0, u32::MAX),
+            vec![(200, second), (200, third), (300, first)]
+        );
+        let key = 100u32.to_be_bytes();
+        assert!(fixture
+            .store
+            .prefix_iter(Db::HeightToDecisions, &key)
+            .unwrap()
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn resolutions_must_spend_their_decision_once_to_one_of_its_outcomes() {
+        let fixture = Fixture::new(false);
+        let (alice, funds) = fixture.accounts[0];
+        let decision = fixture.decision;
+        let resolution = |outcome| custom(alice, HivemindContent::Resolution { decision, outcome });
+        let validate = |inputs: Vec<OutPoint>, outputs: Vec<Output>| {
+            let transaction = Transaction { inputs, outputs };
+            let transaction = fixture
+                .state
+                .fill_transaction(&fixture.store, &transaction)?;
+            fixture
+                .state
+                .validate_transaction(&fixture.store, &transaction, 100)
+        };
+        // Nobody can resolve a decision they can't spend.
+        assert!(matches!(
+            validate(vec![funds], vec![resolution(1), value(alice, FUNDS)]),
+            Err(Error::ResolutionWithoutDecision { .. })
+        ));
+        assert!(matches!(
+            validate(vec![decision], vec![]),
+            Err(Error::DecisionSpentWithoutResolution)
+        ));
+        for outcome in [2, WILDCARD] {
+            assert!(matches!(
+                validate(vec![decision], vec![resolution(outcome)]),
+                Err(Error::InvalidOutcome { outcome: found, .. }) if found == outcome
+            ));
+        }
+        assert!(matches!(
+            validate(vec![decision], vec![resolution(0), resolution(1)]),
+            Err(Error::DuplicateResolution { .. })
+        ));
+        assert!(validate(vec![decision], vec![resolution(1)]).is_ok());
+    }
+
+    #[test]
+    fn disconnect_restores_the_state_before_the_body() {
+        let mut fixture = Fixture::new(false);
+        let before = dump(&fixture.store, &Db::ALL);
+        let commitment = fixture.state.commitment(&fixture.store).unwrap();
+        let (alice, funds) = fixture.accounts[0];
+        let buy = Transaction {
+            inputs: vec![funds],
+            outputs: vec![fixture.position(alice, 0, 1000), value(alice, FUNDS - 1000)],
+        };
+        fixture.connect(3, vec![buy.clone()]);
+        assert_eq!(fixture.vector(), vec![dec!(1000), dec!(0)]);
+        assert_eq!(
+            fixture
+                .state
+                .get_market_positions(&fixture.store, &fixture.market)
+                .unwrap(),
+            vec![outpoint(&buy, 0)]
+        );
+        assert_ne!(
+            fixture.state.commitment(&fixture.store).unwrap(),
+            commitment
+        );
+
+        fixture
+            .state
+            .disconnect_body(&mut fixture.store, 3)
+            .unwrap();
+        assert!(matches!(
+            fixture.state.disconnect_body(&mut fixture.store, 3),
+            Err(Error::NoUndoData { height: 3 })
+        ));
+        assert_eq!(fixture.vector(), vec![dec!(0), dec!(0)]);
+        assert_eq!(value_of(&fixture.utxo(&funds).unwrap()), FUNDS);
+        assert_eq!(fixture.utxo(&outpoint(&buy, 0)), None);
+        let positions = fixture
+            .state
+            .get_market_positions(&fixture.store, &fixture.market)
+            .unwrap();
+        assert!(positions.is_empty());
+        assert_eq!(
+            fixture.state.commitment(&fixture.store).unwrap(),
+            commitment
+        );
+        assert_eq!(dump(&fixture.store, &Db::ALL), before);
+    }
 }
@@ -0,0 +1,413 @@
+use crate::{
+    address_key, commitment, index_key, position_key, Db, Entry, Error, State, StoreRead,
+    StoreWrite,
+};
+use hivemind_types::{
+    sdk_types::{self, Hash, OutPoint},
+    *,
+};
+use serde::Deserialize;
+
+/// Version of the layout of all databases written by this version of the crate.
+pub const SCHEMA_VERSION: u32 = 1;
+
+pub(crate) const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+type Migration = fn(&mut dyn StoreWrite) -> Result<(), Error>;
+
+/// Migration `i` upgrades databases from version `i` to version `i + 1`. Every change to the
+/// encoding of keys or values must bump `SCHEMA_VERSION` and append a migration here.
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [migrate_unversioned];
+
+/// Upgrades databases to `SCHEMA_VERSION` within the caller's transaction, so a failed migration
+/// leaves them untouched. Databases written by a newer version are refused.
+pub(crate) fn migrate(txn: &mut dyn StoreWrite) -> Result<(), Error> {
+    let version = match txn.get_raw(Db::Meta, SCHEMA_VERSION_KEY)? {
+        Some(version) => bincode::deserialize(&version)?,
+        None if txn.is_empty(Db::Utxos)? => SCHEMA_VERSION,
+        // Databases written before versioning have data but no version.
+        None => 0,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(Error::IncompatibleSchema {
+            found: version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(txn)?;
+    }
+    txn.put_raw(
+        Db::Meta,
+        SCHEMA_VERSION_KEY,
+        &bincode::serialize(&SCHEMA_VERSION)?,
+    )
+}
+
+impl State {
+    /// Returns the schema version databases were written with.
+    pub fn schema_version(&self, txn: &impl StoreRead) -> Result<Option<u32>, Error> {
+        match txn.get_raw(Db::Meta, SCHEMA_VERSION_KEY)? {
+            Some(version) => Ok(Some(bincode::deserialize(&version)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// Migrations collect entries before writing, because databases can't be written to while they
+// are iterated over.
+fn entries(txn: &dyn StoreWrite, db: Db) -> Result<Vec<Entry>, Error> {
+    txn.prefix_iter(db, &[])?.collect()
+}
+
+// Before versioning, markets had no `batch` flag.
+#[derive(Deserialize)]
+enum UnversionedHivemindContent {
+    Resolution {
+        decision: OutPoint,
+        outcome: u32,
+    },
+    Decision {
+        query: Hash,
+        size: u32,
+        resolvable_height: u32,
+    },
+    Market {
+        b: u64,
+        decisions: Vec<OutPoint>,
+    },
+    Position {
+        market: OutPoint,
+        share: Vec<u32>,
+        value: u64,
+    },
+}
+
+impl From<UnversionedHivemindContent> for HivemindContent {
+    fn from(content: UnversionedHivemindContent) -> Self {
+        match content {
+            UnversionedHivemindContent::Resolution { decision, outcome } => {
+                Self::Resolution { decision, outcome }
+            }
+            UnversionedHivemindContent::Decision {
+                query,
+                size,
+                resolvable_height,
+            } => Self::Decision {
+                query,
+                size,
+                resolvable_height,
+            },
+            UnversionedHivemindContent::Market { b, decisions } => Self::Market {
+                b,
+                decisions,
+                batch: false,
+            },
+            UnversionedHivemindContent::Position {
+                market,
+                share,
+                value,
+            } => Self::Position {
+                market,
+                share,
+                value,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UnversionedMarket {
+    b: u64,
+    shape: Vec<u32>,
+    decisions: Vec<OutPoint>,
+    outcomes: Vec<Option<u32>>,
+}
+
+impl From<UnversionedMarket> for Market {
+    fn from(market: UnversionedMarket) -> Self {
+        Self {
+            b: market.b,
+            shape: market.shape,
+            decisions: market.decisions,
+            outcomes: market.outcomes,
+            batch: false,
+        }
+    }
+}
+
+/// Version 0 to 1, from the layout written before versioning:
+///
+/// - markets get the `batch` flag, existing markets are continuous,
+/// - positions move from the legacy market_to_positions db, which stored a `Vec<OutPoint>` per
+///   market, into the composite key db,
+/// - utxos are indexed by address, markets by decision and unresolved decisions by the height at
+///   which they become resolvable, withdrawals as pending and resolutions by their decision;
+///   there were no limit orders, so markets have no order entries,
+/// - resolved markets are indexed as resolved at height 0, so they are pruned as soon as pruning
+///   is enabled,
+/// - the state commitment is computed over all entries.
+fn migrate_unversioned(txn: &mut dyn StoreWrite) -> Result<(), Error> {
+    let mut outputs = vec![];
+    for (outpoint, output) in entries(txn, Db::Utxos)? {
+        let outpoint: OutPoint = bincode::deserialize(&outpoint)?;
+        let output: sdk_types::Output<UnversionedHivemindContent> = bincode::deserialize(&output)?;
+        let content = match output.content {
+            sdk_types::Content::Custom(content) => sdk_types::Content::Custom(content.into()),
+            sdk_types::Content::Value(value) => sdk_types::Content::Value(value),
+            sdk_types::Content::Withdrawal {
+                value,
+                main_fee,
+                main_address,
+            } => sdk_types::Content::Withdrawal {
+                value,
+                main_fee,
+                main_address,
+            },
+        };
+        let output = Output {
+            address: output.address,
+            content,
+        };
+        outputs.push((outpoint, output));
+    }
+    let mut markets = vec![];
+    for (outpoint, market) in entries(txn, Db::Markets)? {
+        let outpoint: OutPoint = bincode::deserialize(&outpoint)?;
+        let market: UnversionedMarket = bincode::deserialize(&market)?;
+        markets.push((outpoint, Market::from(market)));
+    }
+
+    for (outpoint, output) in &outputs {
+        txn.put_raw(
+            Db::Utxos,
+            &bincode::serialize(outpoint)?,
+            &bincode::serialize(output)?,
+        )?;
+        txn.put_raw(
+            Db::AddressToOutPoints,
+            &address_key(&output.address, outpoint)?,
+            &[],
+        )?;
+        match &output.content {
+            sdk_types::Content::Withdrawal { .. } => {
+                txn.put_raw(Db::PendingWithdrawals, &bincode::serialize(outpoint)?, &[])?;
+            }
+            sdk_types::Content::Custom(HivemindContent::Decision {
+                resolvable_height, ..
+            }) => {
+                let key = index_key(&resolvable_height.to_be_bytes(), outpoint)?;
+                txn.put_raw(Db::HeightToDecisions, &key, &[])?;
+            }
+            sdk_types::Content::Custom(HivemindContent::Resolution { decision, .. }) => {
+                txn.put_raw(
+                    Db::DecisionToResolution,
+                    &bincode::serialize(decision)?,
+                    &bincode::serialize(outpoint)?,
+                )?;
+            }
+            _ => {}
+        }
+    }
+    for (outpoint, market) in &markets {
+        let key = bincode::serialize(outpoint)?;
+        txn.put_raw(Db::Markets, &key, &bincode::serialize(market)?)?;
+        if market.outcomes.iter().all(Option::is_some) {
+            txn.put_raw(Db::ResolvedMarkets, &index_key(&0u32, outpoint)?, &[])?;
+        }
+        for decision in &market.decisions {
+            txn.put_raw(Db::DecisionToMarkets, &index_key(decision, outpoint)?, &[])?;
+        }
+    }
+
+    for (market, positions) in entries(txn, Db::LegacyMarketToPositions)? {
+        let market: OutPoint = bincode::deserialize(&market)?;
+        let positions: Vec<OutPoint> = bincode::deserialize(&positions)?;
+        for position in &positions {
+            txn.put_raw(
+                Db::MarketToPositions,
+                &position_key(&market, position)?,
+                &[],
+            )?;
+        }
+    }
+    txn.clear(Db::LegacyMarketToPositions)?;
+
+    txn.clear(Db::Commitment)?;
+    for db in Db::ALL
+        .into_iter()
+        .filter(|db| db.commitment_tree().is_some())
+    {
+        for (key, value) in entries(txn, db)? {
+            commitment::update_commitment(txn, db, &key, Some(&value))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{address, custom, value};
+    use crate::MemoryStore;
+    use serde::Serialize;
+
+    // Unversioned markets are encoded as they are now without the `batch` flag, their last byte.
+    fn unversioned<T: Serialize>(value: &T) -> Vec<u8> {
+        let mut bytes = bincode::serialize(value).unwrap();
+        bytes.pop();
+        bytes
+    }
+
+    #[test]
+    fn migrates_unversioned_databases() {
+        let decision = HivemindContent::Decision {
+            query: [0; 32],
+            size: 2,
+            resolvable_height: 100,
+        };
+        let transaction = Transaction {
+            inputs: vec![],
+            outputs: vec![custom(address(1), decision), value(address(1), 1000)],
+        };
+        let outpoint = |vout| OutPoint::Regular {
+            txid: transaction.txid(),
+            vout,
+        };
+        let market = HivemindContent::Market {
+            b: 1,
+            decisions: vec![outpoint(0)],
+            batch: false,
+        };
+        let position = HivemindContent::Position {
+            market: outpoint(2),
+            share: vec![0],
+            value: 10,
+        };
+        let utxos = vec![
+            (outpoint(0), transaction.outputs[0].clone()),
+            (outpoint(1), transaction.outputs[1].clone()),
+            (outpoint(2), custom(address(2), market)),
+            (outpoint(3), custom(address(2), position)),
+            (
+                outpoint(4),
+                Output {
+                    address: address(3),
+                    content: sdk_types::Content::Withdrawal {
+                        value: 900,
+                        main_fee: 100,
+                        main_address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+                            .parse()
+                            .unwrap(),
+                    },
+                },
+            ),
+        ];
+        let market = Market {
+            b: 1,
+            shape: vec![2],
+            decisions: vec![outpoint(0)],
+            outcomes: vec![None],
+            batch: false,
+        };
+
+        let mut store = MemoryStore::default();
+        let mut expected = MemoryStore::new().unwrap();
+        let mut put = |db, key: Vec<u8>, legacy: Vec<u8>, value: Vec<u8>| {
+            store.put_raw(db, &key, &legacy).unwrap();
+            expected.put_raw(db, &key, &value).unwrap();
+            commitment::update_commitment(&mut expected, db, &key, Some(&value)).unwrap();
+        };
+        for (outpoint, output) in &utxos {
+            let legacy = match output.content {
+                sdk_types::Content::Custom(HivemindContent::Market { .. }) => unversioned(output),
+                _ => bincode::serialize(output).unwrap(),
+            };
+            let key = bincode::serialize(outpoint).unwrap();
+            put(Db::Utxos, key, legacy, bincode::serialize(output).unwrap());
+        }
+        let key = bincode::serialize(&outpoint(2)).unwrap();
+        let bytes = bincode::serialize(&market).unwrap();
+        put(Db::Markets, key, unversioned(&market), bytes);
+        let key = position_key(&outpoint(2), &outpoint(3)).unwrap();
+        put(Db::MarketToPositions, key, vec![], vec![]);
+        // Legacy positions are moved, so they are only in the legacy db.
+        store.clear(Db::MarketToPositions).unwrap();
+        store
+            .put(
+                Db::LegacyMarketToPositions,
+                &outpoint(2),
+                &vec![outpoint(3)],
+            )
+            .unwrap();
+
+        migrate(&mut store).unwrap();
+        let state = State::new();
+        assert_eq!(state.schema_version(&store).unwrap(), Some(SCHEMA_VERSION));
+        let dbs = [
+            Db::Utxos,
+            Db::Markets,
+            Db::MarketToPositions,
+            Db::LegacyMarketToPositions,
+        ];
+        for db in dbs {
+            assert_eq!(
+                entries(&store, db).unwrap(),
+                entries(&expected, db).unwrap()
+            );
+        }
+        assert_eq!(
+            state.commitment(&store).unwrap(),
+            state.commitment(&expected).unwrap()
+        );
+        assert_eq!(
+            state.get_market_positions(&store, &outpoint(2)).unwrap(),
+            vec![outpoint(3)]
+        );
+        assert_eq!(
+            state.get_address_outpoints(&store, &address(1)).unwrap(),
+            vec![outpoint(0), outpoint(1)]
+        );
+        assert_eq!(
+            state
+                .get_index(&store, Db::DecisionToMarkets, &outpoint(0))
+                .unwrap(),
+            vec![outpoint(2)]
+        );
+        assert_eq!(
+            state
+                .get_decisions_resolvable_within(&store, 0, 101)
+                .unwrap(),
+            vec![(100, outpoint(0))]
+        );
+        assert!(state
+            .get_open_orders(&store, &outpoint(2))
+            .unwrap()
+            .is_empty());
+        // Withdrawals from before bundles existed are bundled like new ones.
+        assert_eq!(
+            entries(&store, Db::PendingWithdrawals).unwrap(),
+            vec![(bincode::serialize(&outpoint(4)).unwrap(), vec![])]
+        );
+
+        // Migrating again leaves the databases as they are.
+        let migrated: Vec<_> = Db::ALL
+            .iter()
+            .map(|db| entries(&store, *db).unwrap())
+            .collect();
+        migrate(&mut store).unwrap();
+        let again: Vec<_> = Db::ALL
+            .iter()
+            .map(|db| entries(&store, *db).unwrap())
+            .collect();
+        assert_eq!(again, migrated);
+
+        // Databases written by a newer version are refused.
+        let newer = bincode::serialize(&(SCHEMA_VERSION + 1)).unwrap();
+        store.put_raw(Db::Meta, SCHEMA_VERSION_KEY, &newer).unwrap();
+        assert!(matches!(
+            migrate(&mut store),
+            Err(Error::IncompatibleSchema { found, .. }) if found == SCHEMA_VERSION + 1
+        ));
+    }
+}
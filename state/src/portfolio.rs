@@ -0,0 +1,235 @@
+use crate::{net_complete_sets, Db, Error, State, StoreRead};
+use hivemind_types::{
+    nalgebra::DVector,
+    rust_decimal::prelude::*,
+    rust_decimal_macros::dec,
+    sdk_types::{self, Address, OutPoint},
+    *,
+};
+use std::collections::HashMap;
+
+/// What a position pays out on: a share, possibly with wildcards, or a predicate.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ShareId {
+    Share(Vec<u32>),
+    Predicate(Predicate),
+}
+
+/// All shares of the same kind held by an address in one market.
+#[derive(Debug, Clone)]
+pub struct Holding {
+    pub market: OutPoint,
+    pub share: ShareId,
+    pub amount: u64,
+    pub outpoints: Vec<OutPoint>,
+    /// Current marginal LMSR price of one share.
+    pub price: Decimal,
+    /// Value received for selling the whole holding back to the market maker.
+    pub liquidation_value: Decimal,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Portfolio {
+    pub values: Vec<(OutPoint, u64)>,
+    pub holdings: Vec<Holding>,
+    pub orders: Vec<(OutPoint, Output)>,
+    /// Bid orders on resolved markets, their escrowed value can be reclaimed by cancelling them.
+    /// Winning positions don't show up here, they are paid out as Value outputs at resolution.
+    pub claimable: Vec<(OutPoint, u64)>,
+    /// Sum of all Value outputs and escrowed value.
+    pub total_value: u64,
+    /// Value received for selling all holdings back to the market makers, market by market.
+    pub liquidation_value: Decimal,
+}
+
+impl State {
+    /// Returns everything held by `address`, marked to market at current LMSR prices.
+    pub fn portfolio(&self, txn: &impl StoreRead, address: &Address) -> Result<Portfolio, Error> {
+        let mut portfolio = Portfolio::default();
+        let mut holdings: HashMap<(OutPoint, ShareId), (u64, Vec<OutPoint>)> = HashMap::new();
+        for outpoint in self.get_address_outpoints(txn, address)? {
+            let output = self.get_utxo(txn, &outpoint)?;
+            let content = match &output.content {
+                sdk_types::Content::Value(value) => {
+                    portfolio.values.push((outpoint, *value));
+                    portfolio.total_value += value;
+                    continue;
+                }
+                sdk_types::Content::Custom(content) => content,
+                _ => continue,
+            };
+            if let HivemindContent::LimitOrder { market, .. } = content {
+                let escrow = content.get_escrow_value().ok_or(Error::InvalidLimitOrder)?;
+                portfolio.total_value += escrow;
+                if escrow > 0 && self.is_resolved(txn, market)? {
+                    portfolio.claimable.push((outpoint, escrow));
+                }
+                portfolio.orders.push((outpoint, output.clone()));
+            }
+            let mut shares = vec![];
+            match content {
+                HivemindContent::PredicatePosition {
+                    market,
+                    predicate,
+                    value,
+                } => shares.push((*market, ShareId::Predicate(predicate.clone()), *value)),
+                _ => {
+                    if let Some((market, positions)) = content.get_positions() {
+                        for (share, value) in positions {
+                            shares.push((market, ShareId::Share(share), value));
+                        }
+                    }
+                }
+            }
+            for (market, share, value) in shares {
+                let (amount, outpoints) = holdings.entry((market, share)).or_default();
+                *amount += value;
+                outpoints.push(outpoint);
+            }
+        }
+
+        let mut market_to_delta: HashMap<OutPoint, DVector<Decimal>> = HashMap::new();
+        for ((market, share), (amount, outpoints)) in holdings {
+            let Market { b, shape, .. } = self.get_market(txn, &market)?;
+            let flat_indices = match &share {
+                ShareId::Share(share) => self.share_to_flat_index(txn, &market, share)?,
+                ShareId::Predicate(predicate) => predicate.flat_indices(&shape),
+            };
+            let state = DVector::from(self.get_vector(txn, &market)?);
+            let mut delta = DVector::from_element(state.len(), dec!(0));
+            for flat_index in &flat_indices {
+                delta[*flat_index as usize] -= Decimal::from(amount);
+            }
+            let liquidation_value = self.get_liquidation_value(txn, &market, delta.clone())?;
+            *market_to_delta
+                .entry(market)
+                .or_insert(DVector::from_element(state.len(), dec!(0))) += delta;
+            portfolio.holdings.push(Holding {
+                market,
+                share,
+                amount,
+                outpoints,
+                price: lmsr_price(Decimal::from(b), &state, &flat_indices),
+                liquidation_value,
+            });
+        }
+        // Selling everything in a market at once moves the price against the seller, but complete
+        // sets across holdings are burned at par, so this can be more or less than the sum of
+        // the liquidation values of separate holdings.
+        for (market, delta) in market_to_delta {
+            portfolio.liquidation_value += self.get_liquidation_value(txn, &market, delta)?;
+        }
+        Ok(portfolio)
+    }
+
+    // Complete sets are burned at par and the rest is sold to the market maker.
+    fn get_liquidation_value(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+        mut delta: DVector<Decimal>,
+    ) -> Result<Decimal, Error> {
+        let burned = -net_complete_sets(&mut delta);
+        Ok(burned - self.get_cost(txn, &HashMap::from([(*market, delta)]))?)
+    }
+
+    fn is_resolved(&self, txn: &impl StoreRead, market: &OutPoint) -> Result<bool, Error> {
+        // Markets missing from the state were pruned after they resolved.
+        match txn.get::<_, Market>(Db::Markets, market)? {
+            Some(market) => Ok(market.outcomes.iter().all(Option::is_some)),
+            None => Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{address, custom, outpoint, value, Fixture, FUNDS};
+
+    #[test]
+    fn portfolios_hold_values_shares_and_orders() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let (alice, funds) = fixture.accounts[0];
+        let bid = HivemindContent::LimitOrder {
+            market,
+            share: vec![1],
+            side: Side::Bid,
+            amount: 100,
+            price: dec!(0.5),
+        };
+        let buy = Transaction {
+            inputs: vec![funds],
+            outputs: vec![
+                fixture.position(alice, 0, 1000),
+                fixture.position(alice, 0, 500),
+                custom(alice, bid),
+                value(alice, FUNDS - 2000),
+            ],
+        };
+        fixture.connect(3, vec![buy.clone()]);
+
+        let txn = &fixture.store;
+        let portfolio = fixture.state.portfolio(txn, &alice).unwrap();
+        assert_eq!(portfolio.values, vec![(outpoint(&buy, 3), FUNDS - 2000)]);
+        // The bid escrows 50 for 100 shares at 0.5.
+        assert_eq!(portfolio.total_value, FUNDS - 2000 + 50);
+        assert_eq!(portfolio.orders.len(), 1);
+        assert!(portfolio.claimable.is_empty());
+        assert_eq!(portfolio.holdings.len(), 1);
+        let holding = &portfolio.holdings[0];
+        assert_eq!(holding.share, ShareId::Share(vec![0]));
+        assert_eq!(holding.amount, 1500);
+        assert_eq!(holding.outpoints.len(), 2);
+        // Shares were bought, so their price is over one half and selling them returns less than
+        // their face value.
+        assert!(holding.price > dec!(0.5));
+        assert!(holding.liquidation_value > dec!(750) && holding.liquidation_value < dec!(1500));
+        assert_eq!(portfolio.liquidation_value, holding.liquidation_value);
+
+        let bob = fixture.accounts[1].0;
+        let portfolio = fixture.state.portfolio(txn, &bob).unwrap();
+        assert_eq!(portfolio.total_value, FUNDS);
+        assert!(portfolio.holdings.is_empty());
+        let portfolio = fixture.state.portfolio(txn, &address(9)).unwrap();
+        assert_eq!(portfolio.total_value, 0);
+        assert!(portfolio.values.is_empty() && portfolio.orders.is_empty());
+    }
+
+    #[test]
+    fn complete_sets_are_liquidated_at_par() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let (bob, funds) = fixture.accounts[1];
+        let wildcard = HivemindContent::Position {
+            market,
+            share: vec![WILDCARD],
+            value: 300,
+        };
+        let mint = Transaction {
+            inputs: vec![funds],
+            outputs: vec![
+                fixture.position(bob, 0, 1000),
+                fixture.position(bob, 1, 1000),
+                custom(bob, wildcard),
+                value(bob, FUNDS - 1300),
+            ],
+        };
+        assert_eq!(fixture.validate(&mint).unwrap(), 0);
+        fixture.connect(3, vec![mint]);
+
+        let portfolio = fixture.state.portfolio(&fixture.store, &bob).unwrap();
+        let liquidation_value = |share: Vec<u32>| {
+            let share = ShareId::Share(share);
+            let holding = portfolio.holdings.iter().find(|h| h.share == share);
+            holding.unwrap().liquidation_value
+        };
+        // Either share alone is sold to the market maker below one half, but together they are
+        // complete sets worth one each, and so is a share on every outcome.
+        assert!(liquidation_value(vec![0]) < dec!(500));
+        assert!(liquidation_value(vec![1]) < dec!(500));
+        assert_eq!(liquidation_value(vec![WILDCARD]), dec!(300));
+        assert_eq!(portfolio.liquidation_value, dec!(1300));
+    }
+}
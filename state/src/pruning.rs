@@ -0,0 +1,305 @@
+use crate::{undo::undo_key, Db, Error, State, StoreRead, StoreWrite, UndoEntry};
+use hivemind_types::{sdk_types::OutPoint, Market};
+use serde::{Deserialize, Serialize};
+
+const PRUNING_KEY: &[u8] = b"pruning";
+
+/// Whether the state of resolved markets is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PruningMode {
+    /// Keeps every market, so their state and history can be queried and proven forever.
+    Archive,
+    /// Deletes the state vector, market entry and history of markets `depth` blocks after they
+    /// resolved, and undo data of bodies more than `max_reorg_depth` blocks below the tip, so
+    /// deeper reorgs fail with `Error::NoUndoData`.
+    Prune { depth: u32, max_reorg_depth: u32 },
+}
+
+impl State {
+    /// Prunes markets that resolved `depth` blocks before `height`, and returns them. It must be
+    /// called after the body at `height` was connected, in the same transaction.
+    ///
+    /// Pruned entries are deleted without updating the commitment, so pruning and archive nodes
+    /// agree on it, but pruning nodes can't prove them and can't export snapshots. The mode is
+    /// recorded in meta the first time anything is pruned, see `get_pruning`. Resolved
+    /// markets can't be traded on, so pruned entries are never read by connect_body again. They
+    /// are added to the undo data of `height`, so disconnecting the body restores them until
+    /// that undo data is deleted in turn.
+    pub fn prune(
+        &self,
+        txn: &mut impl StoreWrite,
+        height: u32,
+        mode: PruningMode,
+    ) -> Result<Vec<OutPoint>, Error> {
+        let (depth, max_reorg_depth) = match mode {
+            PruningMode::Archive => return Ok(vec![]),
+            PruningMode::Prune {
+                depth,
+                max_reorg_depth,
+            } => (depth, max_reorg_depth),
+        };
+        self.prune_undo(txn, height.saturating_sub(max_reorg_depth))?;
+        let resolved_height = match height.checked_sub(depth) {
+            Some(resolved_height) => resolved_height,
+            None => return Ok(vec![]),
+        };
+        let markets = self.get_index(txn, Db::ResolvedMarkets, &resolved_height)?;
+        if markets.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut undo = self.get_undo(txn, height)?.unwrap_or_default();
+        // Disconnecting the body that first pruned anything restores an unpruned state, so the
+        // mode is recorded in its undo data. Later bodies leave it recorded.
+        if self.get_pruning(txn)? != Some(mode) {
+            let mode = bincode::serialize(&mode)?;
+            self.write(txn, &mut undo, Db::Meta, PRUNING_KEY.to_vec(), Some(&mode))?;
+        }
+        for market in &markets {
+            // Indexes are not committed, so they are cleaned up the usual way.
+            let decisions = match txn.get::<_, Market>(Db::Markets, market)? {
+                Some(market) => market.decisions,
+                None => vec![],
+            };
+            for decision in &decisions {
+                self.remove_from_index(txn, &mut undo, Db::DecisionToMarkets, decision, market)?;
+            }
+            for order in self.get_index(txn, Db::MarketToOrders, market)? {
+                self.remove_from_index(txn, &mut undo, Db::MarketToOrders, market, &order)?;
+            }
+            self.remove_from_index(
+                txn,
+                &mut undo,
+                Db::ResolvedMarkets,
+                &resolved_height,
+                market,
+            )?;
+
+            let key = bincode::serialize(market)?;
+            let mut entries = vec![];
+            for db in [Db::Vectors, Db::Markets] {
+                if let Some(value) = txn.get_raw(db, &key)? {
+                    entries.push((db, key.clone(), value));
+                }
+            }
+            for item in txn.prefix_iter(Db::MarketHistory, &key)? {
+                let (key, value) = item?;
+                entries.push((Db::MarketHistory, key, value));
+            }
+            for (db, key, value) in entries {
+                txn.delete_raw(db, &key)?;
+                undo.push(UndoEntry {
+                    db,
+                    key,
+                    previous: Some(value),
+                });
+            }
+        }
+        self.put_undo(txn, height, &undo)?;
+        Ok(markets)
+    }
+
+    fn prune_undo(&self, txn: &mut impl StoreWrite, height: u32) -> Result<(), Error> {
+        let end = match height.checked_sub(1) {
+            Some(end) => end,
+            None => return Ok(()),
+        };
+        let keys = txn
+            .range(Db::Undo, &undo_key(0), &undo_key(end), false)?
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in keys {
+            txn.delete_raw(Db::Undo, &key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the mode the state was pruned with, `None` if nothing was ever pruned.
+    pub fn get_pruning(&self, txn: &impl StoreRead) -> Result<Option<PruningMode>, Error> {
+        match txn.get_raw(Db::Meta, PRUNING_KEY)? {
+            Some(mode) => Ok(Some(bincode::deserialize(&mode)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{custom, dump, outpoint, value, value_of, Fixture, FUNDS};
+    use crate::MemoryStore;
+    use hivemind_types::{proof, rust_decimal_macros::dec, HivemindContent, Side, Transaction};
+
+    #[test]
+    fn reorgs_restore_pruned_markets() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let decision = fixture.decision;
+        let (alice, funds) = fixture.accounts[0];
+        let buy = Transaction {
+            inputs: vec![funds],
+            outputs: vec![fixture.position(alice, 0, 1000), value(alice, FUNDS - 1000)],
+        };
+        fixture.connect(3, vec![buy.clone()]);
+        let position = outpoint(&buy, 0);
+        let committed = [
+            Db::Utxos,
+            Db::Vectors,
+            Db::Markets,
+            Db::MarketToPositions,
+            Db::Commitment,
+        ];
+        let unresolved = dump(&fixture.store, &committed);
+        let resolve = |outcome| Transaction {
+            inputs: vec![decision],
+            outputs: vec![custom(
+                alice,
+                HivemindContent::Resolution { decision, outcome },
+            )],
+        };
+        fixture.connect(4, vec![resolve(0)]);
+        let payout = fixture.state.get_utxo(&fixture.store, &position).unwrap();
+        assert_eq!(value_of(&payout), 1000);
+        // Resolved markets can't be traded on.
+        let (bob, funds) = fixture.accounts[1];
+        let buy = Transaction {
+            inputs: vec![funds],
+            outputs: vec![fixture.position(bob, 0, 1000), value(bob, FUNDS - 1000)],
+        };
+        assert!(matches!(
+            fixture.validate(&buy),
+            Err(Error::MarketResolved { .. })
+        ));
+
+        fixture.connect(5, vec![]);
+        assert_eq!(fixture.state.get_pruning(&fixture.store).unwrap(), None);
+        let commitment = fixture.state.commitment(&fixture.store).unwrap();
+        let mode = PruningMode::Prune {
+            depth: 1,
+            max_reorg_depth: 2,
+        };
+        assert!(fixture
+            .state
+            .prune(&mut fixture.store, 5, PruningMode::Archive)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            fixture.state.prune(&mut fixture.store, 5, mode).unwrap(),
+            vec![market]
+        );
+        // Pruning nodes agree with archive nodes on the commitment.
+        assert_eq!(
+            fixture.state.commitment(&fixture.store).unwrap(),
+            commitment
+        );
+        assert!(fixture.state.get_market(&fixture.store, &market).is_err());
+        assert_eq!(
+            fixture.state.get_pruning(&fixture.store).unwrap(),
+            Some(mode)
+        );
+        assert!(matches!(
+            fixture
+                .state
+                .export_snapshot(&fixture.store, std::io::sink()),
+            Err(Error::StatePruned)
+        ));
+        assert!(matches!(
+            fixture.state.prove(
+                &fixture.store,
+                Db::Markets,
+                bincode::serialize(&market).unwrap()
+            ),
+            Err(Error::EntryPruned { db: Db::Markets })
+        ));
+        // Outcomes of decisions whose markets were pruned are proven by their resolution.
+        let outcome = fixture
+            .state
+            .prove_decision_outcome(&fixture.store, &decision)
+            .unwrap();
+        assert_eq!(
+            proof::verify_decision_outcome(&commitment, &decision, &outcome),
+            Some(0)
+        );
+
+        fixture
+            .state
+            .disconnect_body(&mut fixture.store, 5)
+            .unwrap();
+        assert!(fixture.state.get_market(&fixture.store, &market).is_ok());
+        assert_eq!(fixture.state.get_pruning(&fixture.store).unwrap(), None);
+        fixture
+            .state
+            .disconnect_body(&mut fixture.store, 4)
+            .unwrap();
+        assert_eq!(dump(&fixture.store, &committed), unresolved);
+        // The new branch resolves the decision the other way.
+        fixture.connect(4, vec![resolve(1)]);
+        assert!(fixture.state.get_utxo(&fixture.store, &position).is_err());
+    }
+
+    #[test]
+    fn undo_data_and_indexes_of_pruned_markets_are_deleted() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let (alice, funds) = fixture.accounts[0];
+        let bid = HivemindContent::LimitOrder {
+            market,
+            share: vec![1],
+            side: Side::Bid,
+            amount: 100,
+            price: dec!(0.5),
+        };
+        let buy = Transaction {
+            inputs: vec![funds],
+            outputs: vec![
+                fixture.position(alice, 0, 1000),
+                custom(alice, bid),
+                value(alice, FUNDS - 1050),
+            ],
+        };
+        let mode = PruningMode::Prune {
+            depth: 1,
+            max_reorg_depth: 1,
+        };
+        let size = |store: &MemoryStore| -> usize {
+            dump(store, &Db::ALL)
+                .iter()
+                .flat_map(|(_, entries)| entries)
+                .map(|(key, value)| key.len() + value.len())
+                .sum()
+        };
+        fixture.connect(3, vec![buy]);
+        fixture.resolve(4, 0);
+        let resolved = size(&fixture.store);
+        let indexes = [
+            Db::DecisionToMarkets,
+            Db::MarketToOrders,
+            Db::ResolvedMarkets,
+        ];
+        assert!(dump(&fixture.store, &indexes)
+            .iter()
+            .all(|(_, entries)| !entries.is_empty()));
+
+        for height in 5..8 {
+            fixture.connect(height, vec![]);
+            fixture
+                .state
+                .prune(&mut fixture.store, height, mode)
+                .unwrap();
+        }
+        // Only the bodies a reorg can still disconnect have undo data.
+        let heights: Vec<u32> = dump(&fixture.store, &[Db::Undo])[0]
+            .1
+            .iter()
+            .map(|(key, _)| u32::from_be_bytes(key[..].try_into().unwrap()))
+            .collect();
+        assert_eq!(heights, vec![6, 7]);
+        assert!(matches!(
+            fixture.state.disconnect_body(&mut fixture.store, 5),
+            Err(Error::NoUndoData { height: 5 })
+        ));
+        assert!(dump(&fixture.store, &indexes)
+            .iter()
+            .all(|(_, entries)| entries.is_empty()));
+        assert!(size(&fixture.store) < resolved);
+    }
+}
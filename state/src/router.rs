@@ -0,0 +1,405 @@
+use crate::{Error, State, StoreRead};
+use hivemind_types::{
+    nalgebra::DVector,
+    rust_decimal::prelude::*,
+    rust_decimal_macros::dec,
+    sdk_types::{self, Address, OutPoint},
+    *,
+};
+use std::collections::HashMap;
+
+/// Part of a route that is filled from a resting ask order.
+#[derive(Debug, Clone)]
+pub struct OrderFill {
+    pub outpoint: OutPoint,
+    pub order: Output,
+    pub amount: u64,
+    /// Value paid to the order owner.
+    pub payment: u64,
+}
+
+/// Cheapest way to buy `amount` of `share`, split between resting ask orders and the market
+/// maker.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub market: OutPoint,
+    pub share: Vec<u32>,
+    pub amount: u64,
+    pub fills: Vec<OrderFill>,
+    /// Shares bought from the market maker, as a delta of the market state.
+    pub amm_delta: Vec<Decimal>,
+    /// Total value paid to order owners and to the market maker.
+    pub cost: Decimal,
+}
+
+struct Ask {
+    outpoint: OutPoint,
+    order: Output,
+    flat_indices: Vec<u32>,
+    amount: u64,
+    price: Decimal,
+}
+
+impl State {
+    /// Any ask covering part of `share` can be used: asks on the share itself, on a marginal
+    /// share covering some of its outcomes, or several asks that together make up the share. The
+    /// market maker sells whatever asks don't, all at once, so an ask is worth taking while its
+    /// price is below the market maker's price after that fill. Asks are taken greedily, the one
+    /// furthest below that price first, until the two prices meet.
+    pub fn route_buy(
+        &self,
+        txn: &impl StoreRead,
+        market: &OutPoint,
+        share: &[u32],
+        amount: u64,
+    ) -> Result<Route, Error> {
+        let b = Decimal::from(self.get_market(txn, market)?.b);
+        let flat_indices = self.share_to_flat_index(txn, market, share)?;
+        let mut asks = vec![];
+        for (outpoint, order) in self.get_open_orders(txn, market)? {
+            if let sdk_types::Content::Custom(HivemindContent::LimitOrder {
+                share: order_share,
+                side: Side::Ask,
+                amount,
+                price,
+                ..
+            }) = &order.content
+            {
+                let order_indices = self.share_to_flat_index(txn, market, order_share)?;
+                if order_indices
+                    .iter()
+                    .all(|index| flat_indices.contains(index))
+                {
+                    asks.push(Ask {
+                        outpoint,
+                        flat_indices: order_indices,
+                        amount: *amount,
+                        price: *price,
+                        order,
+                    });
+                }
+            }
+        }
+
+        let state = DVector::from(self.get_vector(txn, market)?);
+        // Amount of every share still to be bought from the market maker.
+        let mut amm_delta = DVector::from_element(state.len(), dec!(0));
+        for flat_index in &flat_indices {
+            amm_delta[*flat_index as usize] = Decimal::from(amount);
+        }
+        let mut fills = vec![];
+        let mut orders_cost = dec!(0);
+        loop {
+            let post_fill = &state + &amm_delta;
+            let best = asks
+                .iter()
+                .enumerate()
+                .map(|(i, ask)| {
+                    let amm_price = lmsr_price(b, &post_fill, &ask.flat_indices);
+                    (i, ask.price / amm_price)
+                })
+                .filter(|(_, ratio)| *ratio < dec!(1))
+                .min_by(|a, b| a.1.cmp(&b.1));
+            let Some((i, _)) = best else {
+                break;
+            };
+            let ask = asks.swap_remove(i);
+            // The ask can replace at most what the market maker still sells of every share it
+            // covers, and it replaces it until the market maker's post-fill price drops to the
+            // ask's price.
+            let most = ask
+                .flat_indices
+                .iter()
+                .map(|index| amm_delta[*index as usize])
+                .min()
+                .unwrap_or(dec!(0));
+            let mut rest = amm_delta.clone();
+            for index in &ask.flat_indices {
+                rest[*index as usize] -= most;
+            }
+            let kept = lmsr_amount_to_price(b, &(&state + &rest), &ask.flat_indices, ask.price)
+                .map_or(dec!(0), |kept| kept.ceil());
+            let filled = (most - kept).min(Decimal::from(ask.amount));
+            if filled <= dec!(0) {
+                continue;
+            }
+            let filled = filled
+                .to_u64()
+                .ok_or(Error::U64Overflow { decimal: filled })?;
+            for index in &ask.flat_indices {
+                amm_delta[*index as usize] -= Decimal::from(filled);
+            }
+            let payment = order_value(filled, ask.price).ok_or(Error::InvalidLimitOrder)?;
+            orders_cost += Decimal::from(payment);
+            fills.push(OrderFill {
+                outpoint: ask.outpoint,
+                order: ask.order,
+                amount: filled,
+                payment,
+            });
+        }
+
+        let amm_cost = self.get_cost(txn, &HashMap::from([(*market, amm_delta.clone())]))?;
+        Ok(Route {
+            market: *market,
+            share: share.to_vec(),
+            amount,
+            fills,
+            amm_delta: amm_delta.iter().copied().collect(),
+            cost: orders_cost + amm_cost,
+        })
+    }
+}
+
+impl Route {
+    /// Builds a transaction skeleton that executes this route. It spends the filled orders, pays
+    /// their owners, recreates partially filled remainders and sends all bought shares to
+    /// `buyer`. The buyer still has to add inputs covering `cost` plus fee, and change outputs.
+    pub fn to_transaction(&self, buyer: Address) -> Transaction {
+        let mut inputs = vec![];
+        let mut outputs = vec![Output {
+            address: buyer,
+            content: sdk_types::Content::Custom(HivemindContent::Position {
+                market: self.market,
+                share: self.share.clone(),
+                value: self.amount,
+            }),
+        }];
+        for fill in &self.fills {
+            inputs.push(fill.outpoint);
+            outputs.push(Output {
+                address: fill.order.address,
+                content: sdk_types::Content::Value(fill.payment),
+            });
+            if let sdk_types::Content::Custom(HivemindContent::LimitOrder {
+                market,
+                share,
+                side,
+                amount,
+                price,
+            }) = &fill.order.content
+            {
+                if fill.amount < *amount {
+                    outputs.push(Output {
+                        address: fill.order.address,
+                        content: sdk_types::Content::Custom(HivemindContent::LimitOrder {
+                            market: *market,
+                            share: share.clone(),
+                            side: *side,
+                            amount: amount - fill.amount,
+                            price: *price,
+                        }),
+                    });
+                }
+            }
+        }
+        Transaction { inputs, outputs }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{custom, outpoint, value, Fixture, FUNDS};
+
+    fn ask(
+        owner: Address,
+        market: OutPoint,
+        share: Vec<u32>,
+        amount: u64,
+        price: Decimal,
+    ) -> Output {
+        let order = HivemindContent::LimitOrder {
+            market,
+            share,
+            side: Side::Ask,
+            amount,
+            price,
+        };
+        custom(owner, order)
+    }
+
+    fn amm_cost(
+        fixture: &Fixture,
+        market: OutPoint,
+        flat_indices: &[usize],
+        amount: u64,
+    ) -> Decimal {
+        let size = fixture
+            .state
+            .get_vector(&fixture.store, &market)
+            .unwrap()
+            .len();
+        let mut delta = DVector::from_element(size, dec!(0));
+        for index in flat_indices {
+            delta[*index] = Decimal::from(amount);
+        }
+        fixture
+            .state
+            .get_cost(&fixture.store, &HashMap::from([(market, delta)]))
+            .unwrap()
+    }
+
+    fn execute(
+        fixture: &mut Fixture,
+        height: u32,
+        route: &Route,
+        buyer: usize,
+        orders: &[OutPoint],
+    ) {
+        let (address, funds) = fixture.accounts[buyer];
+        let mut transaction = route.to_transaction(address);
+        transaction.inputs.push(funds);
+        let cost = route.cost.ceil().to_u64().unwrap();
+        transaction.outputs.push(value(address, FUNDS - cost));
+        assert!(fixture.validate(&transaction).unwrap() <= 1);
+        assert_eq!(
+            fixture.filled_orders(&transaction),
+            orders.iter().copied().collect()
+        );
+        fixture.connect(height, vec![transaction]);
+    }
+
+    #[test]
+    fn routes_fill_cheaper_asks_before_the_market_maker() {
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let (bob, bob_funds) = fixture.accounts[1];
+        // The market maker quotes one half, so only the first ask is cheaper.
+        let post = Transaction {
+            inputs: vec![bob_funds],
+            outputs: vec![
+                ask(bob, market, vec![0], 100, dec!(0.4)),
+                ask(bob, market, vec![0], 100, dec!(0.9)),
+                fixture.position(bob, 1, 200),
+                value(bob, FUNDS - 200),
+            ],
+        };
+        fixture.connect(3, vec![post.clone()]);
+
+        let route = fixture
+            .state
+            .route_buy(&fixture.store, &market, &[0], 1000)
+            .unwrap();
+        assert_eq!(route.fills.len(), 1);
+        assert_eq!(route.fills[0].outpoint, outpoint(&post, 0));
+        assert_eq!(route.fills[0].payment, 40);
+        assert_eq!(route.amm_delta, vec![dec!(900), dec!(0)]);
+        assert!(route.cost < amm_cost(&fixture, market, &[0], 1000));
+        execute(&mut fixture, 4, &route, 2, &[outpoint(&post, 0)]);
+    }
+
+    #[test]
+    fn asks_are_taken_until_the_post_fill_price_meets_them() {
+        // Large enough to move prices of a market with b = 1.
+        const AMOUNT: u64 = 1_000_000_000_000_000;
+        let mut fixture = Fixture::new(false);
+        let market = fixture.market;
+        let (bob, bob_funds) = fixture.accounts[1];
+        // Above the price the market maker quotes now, but below its price after selling
+        // 3 * AMOUNT / 2.
+        let post = Transaction {
+            inputs: vec![bob_funds],
+            outputs: vec![
+                ask(bob, market, vec![0], AMOUNT, dec!(0.6)),
+                fixture.position(bob, 1, AMOUNT),
+                value(bob, FUNDS - AMOUNT),
+            ],
+        };
+        fixture.connect(3, vec![post.clone()]);
+        let vector = DVector::from(fixture.vector());
+        assert!(lmsr_price(dec!(1), &vector, &[0]) < dec!(0.6));
+
+        // A small buy never reaches the ask, so it is the same as buying from the market maker.
+        let small = fixture
+            .state
+            .route_buy(&fixture.store, &market, &[0], 1000)
+            .unwrap();
+        assert!(small.fills.is_empty());
+        assert_eq!(small.cost, amm_cost(&fixture, market, &[0], 1000));
+
+        let route = fixture
+            .state
+            .route_buy(&fixture.store, &market, &[0], 3 * AMOUNT / 2)
+            .unwrap();
+        let filled = route.fills[0].amount;
+        assert!(0 < filled && filled < AMOUNT);
+        assert_eq!(route.amm_delta[0], Decimal::from(3 * AMOUNT / 2 - filled));
+        // The ask is taken until the market maker's last share costs as much as the ask.
+        let post_fill = &vector + DVector::from(route.amm_delta.clone());
+        let price = lmsr_price(dec!(1), &post_fill, &[0]);
+        assert!((price - dec!(0.6)).abs() < dec!(0.000001));
+        assert!(route.cost < amm_cost(&fixture, market, &[0], 3 * AMOUNT / 2));
+        execute(&mut fixture, 4, &route, 2, &[outpoint(&post, 0)]);
+    }
+
+    #[test]
+    fn marginal_shares_are_assembled_from_asks_on_the_shares_they_cover() {
+        let mut fixture = Fixture::new(false);
+        let alice = fixture.accounts[0].0;
+        let (bob, bob_funds) = fixture.accounts[1];
+        let decision = HivemindContent::Decision {
+            query: [1; 32],
+            size: 2,
+            resolvable_height: 100,
+        };
+        let decision = Transaction {
+            inputs: vec![],
+            outputs: vec![custom(alice, decision)],
+        };
+        let market = HivemindContent::Market {
+            b: 1,
+            decisions: vec![fixture.decision, outpoint(&decision, 0)],
+            batch: false,
+        };
+        let market = Transaction {
+            inputs: vec![],
+            outputs: vec![custom(alice, market)],
+        };
+        fixture.connect(3, vec![decision, market.clone()]);
+        let market = outpoint(&market, 0);
+        // Bob mints complete sets and offers parts of the marginal share [1, *], the whole of it,
+        // and a share it doesn't cover.
+        let rest = HivemindContent::PositionBundle {
+            market,
+            shares: [(vec![0, 1], 200), (vec![1, 1], 40)].into(),
+        };
+        let post = Transaction {
+            inputs: vec![bob_funds],
+            outputs: vec![
+                ask(bob, market, vec![1, 0], 100, dec!(0.1)),
+                ask(bob, market, vec![1, 1], 60, dec!(0.1)),
+                ask(bob, market, vec![1, WILDCARD], 100, dec!(0.4)),
+                ask(bob, market, vec![0, 0], 200, dec!(0.01)),
+                custom(bob, rest),
+                value(bob, FUNDS - 200),
+            ],
+        };
+        fixture.connect(4, vec![post.clone()]);
+
+        let route = fixture
+            .state
+            .route_buy(&fixture.store, &market, &[1, WILDCARD], 100)
+            .unwrap();
+        // Both cheap asks are taken first, which leaves nothing for the ask on [1, *] to
+        // replace, and the market maker sells the rest of [1, 1].
+        let filled: Vec<(OutPoint, u64)> = route
+            .fills
+            .iter()
+            .map(|fill| (fill.outpoint, fill.amount))
+            .collect();
+        assert_eq!(
+            filled,
+            vec![(outpoint(&post, 0), 100), (outpoint(&post, 1), 60)]
+        );
+        assert_eq!(route.amm_delta, vec![dec!(0), dec!(0), dec!(0), dec!(40)]);
+        assert!(route.cost < amm_cost(&fixture, market, &[2, 3], 100));
+        execute(
+            &mut fixture,
+            5,
+            &route,
+            2,
+            &[outpoint(&post, 0), outpoint(&post, 1)],
+        );
+    }
+}
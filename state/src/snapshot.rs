@@ -0,0 +1,324 @@
+use crate::{
+    address_key, commitment, index_key, Db, Error, State, StoreRead, StoreWrite, SCHEMA_VERSION,
+};
+use bincode::Options as _;
+use hivemind_types::{block::Header, merkle::MerkleHash, sdk_types::OutPoint, *};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+const MAGIC: [u8; 8] = *b"HIVESNAP";
+/// Version of the snapshot file format, independent of the schema version of its entries.
+pub const SNAPSHOT_VERSION: u32 = 3;
+// The committed databases, indexes are rebuilt from them on import.
+const SNAPSHOT_DBS: [Db; 8] = [
+    Db::Utxos,
+    Db::Vectors,
+    Db::Markets,
+    Db::MarketToPositions,
+    Db::Bundles,
+    Db::Peg,
+    Db::OraclePool,
+    Db::VoteCoinRewards,
+];
+// Records are decoded before the checksum is checked, this bounds a corrupt length.
+const MAX_RECORD_SIZE: u64 = 64 * 1024 * 1024;
+
+// Same encoding as `bincode::serialize`, with a size limit.
+fn decode_options() -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_RECORD_SIZE)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub schema_version: u32,
+    /// Header of the block the snapshot was taken after.
+    pub block: Header,
+}
+
+// A snapshot is a header followed by entries and an end record with the blake3 checksum of the
+// encoded header and entries, so it can be written and read without holding it in memory.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Entry {
+        db: Db,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    End {
+        entries: u64,
+        checksum: MerkleHash,
+    },
+}
+
+impl State {
+    /// Writes all entries covered by the state commitment as of the tip. Pruned states are
+    /// missing some of them and can't be exported.
+    pub fn export_snapshot<W: Write>(
+        &self,
+        txn: &impl StoreRead,
+        mut writer: W,
+    ) -> Result<SnapshotHeader, Error> {
+        if self.get_pruning(txn)?.is_some() {
+            return Err(Error::StatePruned);
+        }
+        let tip = self.get_tip(txn)?.ok_or(Error::NoTip)?;
+        let block = self
+            .get_header(txn, &tip)?
+            .ok_or(Error::UnknownBlock { hash: tip })?;
+        let header = SnapshotHeader {
+            magic: MAGIC,
+            version: SNAPSHOT_VERSION,
+            schema_version: SCHEMA_VERSION,
+            block,
+        };
+        let mut hasher = blake3::Hasher::new();
+        let bytes = bincode::serialize(&header)?;
+        hasher.update(&bytes);
+        writer.write_all(&bytes)?;
+        let mut entries = 0;
+        for db in SNAPSHOT_DBS {
+            for item in txn.prefix_iter(db, &[])? {
+                let (key, value) = item?;
+                let bytes = bincode::serialize(&Record::Entry { db, key, value })?;
+                hasher.update(&bytes);
+                writer.write_all(&bytes)?;
+                entries += 1;
+            }
+        }
+        let end = Record::End {
+            entries,
+            checksum: *hasher.finalize().as_bytes(),
+        };
+        bincode::serialize_into(&mut writer, &end)?;
+        writer.flush()?;
+        Ok(header)
+    }
+
+    /// Loads a snapshot into an empty state and checks that it results in `commitment`, which
+    /// should come from a trusted source such as a block header. The header of the snapshot's
+    /// block becomes the tip, so the next block can be connected on top of it. Entries are
+    /// written as they are read, so the transaction must be aborted after an error, and a
+    /// `MemoryStore` discarded.
+    ///
+    /// Undo data, history, events and bodies are not part of snapshots, so bodies connected
+    /// before the snapshot height can't be disconnected.
+    pub fn import_snapshot<R: Read>(
+        &self,
+        txn: &mut impl StoreWrite,
+        mut reader: R,
+        commitment: &MerkleHash,
+    ) -> Result<SnapshotHeader, Error> {
+        for db in SNAPSHOT_DBS {
+            if !txn.is_empty(db)? {
+                return Err(Error::StateNotEmpty);
+            }
+        }
+        if self.get_tip(txn)?.is_some() {
+            return Err(Error::StateNotEmpty);
+        }
+        let header: SnapshotHeader = decode_options().deserialize_from(&mut reader)?;
+        if header.magic != MAGIC || header.version != SNAPSHOT_VERSION {
+            return Err(Error::InvalidSnapshot);
+        }
+        if header.schema_version != SCHEMA_VERSION {
+            return Err(Error::IncompatibleSchema {
+                found: header.schema_version,
+                supported: SCHEMA_VERSION,
+            });
+        }
+        if header.block.commitment != *commitment {
+            return Err(Error::CommitmentMismatch);
+        }
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&bincode::serialize(&header)?);
+        let mut imported = 0;
+        loop {
+            let record: Record = decode_options().deserialize_from(&mut reader)?;
+            match &record {
+                Record::Entry { db, key, value } => {
+                    if !SNAPSHOT_DBS.contains(db) {
+                        return Err(Error::InvalidSnapshot);
+                    }
+                    hasher.update(&bincode::serialize(&record)?);
+                    txn.put_raw(*db, key, value)?;
+                    commitment::update_commitment(txn, *db, key, Some(value))?;
+                    imported += 1;
+                }
+                Record::End { entries, checksum } => {
+                    if *entries != imported || checksum != hasher.finalize().as_bytes() {
+                        return Err(Error::SnapshotChecksumMismatch);
+                    }
+                    break;
+                }
+            }
+        }
+        if self.commitment(txn)? != *commitment {
+            return Err(Error::CommitmentMismatch);
+        }
+        self.rebuild_indexes(txn, header.block.height)?;
+        let hash = header.block.hash();
+        txn.put(Db::Headers, &hash, &header.block)?;
+        txn.put(Db::BlockHashes, &header.block.height, &hash)?;
+        self.set_tip(txn, &hash)?;
+        Ok(header)
+    }
+
+    // Markets that are already resolved are indexed as resolved at `height`.
+    fn rebuild_indexes(&self, txn: &mut impl StoreWrite, height: u32) -> Result<(), Error> {
+        let mut index_keys = vec![];
+        let mut pending_withdrawals = vec![];
+        let mut decision_to_resolution = vec![];
+        for item in txn.prefix_iter(Db::Markets, &[])? {
+            let (outpoint, market) = item?;
+            let outpoint: OutPoint = bincode::deserialize(&outpoint)?;
+            let market: Market = bincode::deserialize(&market)?;
+            if market.outcomes.iter().all(Option::is_some) {
+                index_keys.push((Db::ResolvedMarkets, index_key(&height, &outpoint)?));
+            }
+            for decision in &market.decisions {
+                index_keys.push((Db::DecisionToMarkets, index_key(decision, &outpoint)?));
+            }
+        }
+        for item in txn.prefix_iter(Db::Utxos, &[])? {
+            let (outpoint, output) = item?;
+            let outpoint: OutPoint = bincode::deserialize(&outpoint)?;
+            let output: Output = bincode::deserialize(&output)?;
+            index_keys.push((
+                Db::AddressToOutPoints,
+                address_key(&output.address, &outpoint)?,
+            ));
+            match output.content {
+                sdk_types::Content::Withdrawal { .. } => {
+                    pending_withdrawals.push(outpoint);
+                }
+                sdk_types::Content::Custom(HivemindContent::LimitOrder { market, .. }) => {
+                    index_keys.push((Db::MarketToOrders, index_key(&market, &outpoint)?));
+                }
+                sdk_types::Content::Custom(HivemindContent::Decision {
+                    resolvable_height, ..
+                }) => {
+                    let key = index_key(&resolvable_height.to_be_bytes(), &outpoint)?;
+                    index_keys.push((Db::HeightToDecisions, key));
+                }
+                sdk_types::Content::Custom(HivemindContent::Resolution { decision, .. }) => {
+                    decision_to_resolution.push((decision, outpoint));
+                }
+                _ => {}
+            }
+        }
+        for db in [
+            Db::AddressToOutPoints,
+            Db::MarketToOrders,
+            Db::DecisionToMarkets,
+            Db::HeightToDecisions,
+            Db::ResolvedMarkets,
+        ] {
+            txn.clear(db)?;
+        }
+        for (db, key) in &index_keys {
+            txn.put_raw(*db, key, &[])?;
+        }
+        txn.clear(Db::PendingWithdrawals)?;
+        for outpoint in &pending_withdrawals {
+            txn.put_raw(Db::PendingWithdrawals, &bincode::serialize(outpoint)?, &[])?;
+        }
+        txn.clear(Db::DecisionToResolution)?;
+        for (decision, outpoint) in &decision_to_resolution {
+            txn.put(Db::DecisionToResolution, decision, outpoint)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{dump, value, Fixture, FUNDS};
+    use crate::MemoryStore;
+
+    #[test]
+    fn snapshots_round_trip() {
+        let mut fixture = Fixture::new(false);
+        let (alice, funds) = fixture.accounts[0];
+        let buy = Transaction {
+            inputs: vec![funds],
+            outputs: vec![fixture.position(alice, 0, 1000), value(alice, FUNDS - 1000)],
+        };
+        fixture.connect(3, vec![buy]);
+        let commitment = fixture.state.commitment(&fixture.store).unwrap();
+        assert!(matches!(
+            fixture
+                .state
+                .export_snapshot(&fixture.store, std::io::sink()),
+            Err(Error::NoTip)
+        ));
+        let tip = Header {
+            prev_block_hash: [0; 32],
+            height: 3,
+            body_hash: [0; 32],
+            commitment,
+            deposits: vec![],
+            bundle_status: None,
+        };
+        fixture.store.put(Db::Headers, &tip.hash(), &tip).unwrap();
+        fixture
+            .store
+            .put(Db::BlockHashes, &3u32, &tip.hash())
+            .unwrap();
+        fixture
+            .state
+            .set_tip(&mut fixture.store, &tip.hash())
+            .unwrap();
+        let mut snapshot = vec![];
+        let header = fixture
+            .state
+            .export_snapshot(&fixture.store, &mut snapshot)
+            .unwrap();
+        assert_eq!(header.block, tip);
+
+        let state = State::new();
+        let mut store = MemoryStore::new().unwrap();
+        assert_eq!(
+            state
+                .import_snapshot(&mut store, &snapshot[..], &commitment)
+                .unwrap(),
+            header
+        );
+        let mut dbs = SNAPSHOT_DBS.to_vec();
+        dbs.extend([
+            Db::Commitment,
+            Db::AddressToOutPoints,
+            Db::MarketToOrders,
+            Db::DecisionToMarkets,
+            Db::HeightToDecisions,
+            Db::DecisionToResolution,
+            Db::ResolvedMarkets,
+            Db::Headers,
+            Db::BlockHashes,
+        ]);
+        assert_eq!(dump(&store, &dbs), dump(&fixture.store, &dbs));
+        assert_eq!(state.get_tip(&store).unwrap(), Some(tip.hash()));
+        assert!(matches!(
+            state.import_snapshot(&mut store, &snapshot[..], &commitment),
+            Err(Error::StateNotEmpty)
+        ));
+
+        let mut store = MemoryStore::new().unwrap();
+        assert!(matches!(
+            state.import_snapshot(&mut store, &snapshot[..], &[0; 32]),
+            Err(Error::CommitmentMismatch)
+        ));
+        let mut corrupt = snapshot.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let mut store = MemoryStore::new().unwrap();
+        assert!(matches!(
+            state.import_snapshot(&mut store, &corrupt[..], &commitment),
+            Err(Error::SnapshotChecksumMismatch)
+        ));
+    }
+}
@@ -0,0 +1,488 @@
+use crate::{migrations, Error};
+use heed::{types::ByteSlice, Database, RoTxn, RwTxn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// Database of the state. Keys and values are bincode encoded unless noted otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Db {
+    Utxos,
+    Vectors,
+    Markets,
+    // There is some aparent redundancy, position outpoints are stored twice: once as keys in utxos
+    // db and once in market_to_positions db.
+    //
+    // Keys are bincode encoded market outpoints followed by bincode encoded position outpoints,
+    // so positions are inserted and removed in O(log n) and listed with a prefix iterator.
+    MarketToPositions,
+    // Keys are bincode encoded addresses followed by bincode encoded outpoints of their utxos.
+    AddressToOutPoints,
+    // Open limit orders of every market, both bids and asks. Keys are market outpoints followed by
+    // order outpoints, like every index below, see `index_key`.
+    MarketToOrders,
+    // Markets using every decision, so resolving a decision only touches its markets.
+    DecisionToMarkets,
+    // Unresolved decisions by the height at which they become resolvable. Keys start with
+    // big-endian heights, so decisions are listed by height with a range scan.
+    HeightToDecisions,
+    // Snapshots of market states after every block that traded on them, see history_key.
+    MarketHistory,
+    // Previous values of all entries written by the body connected at every height, keyed by
+    // big-endian height.
+    Undo,
+    // Events of connected bodies by height, only written if the node stores them.
+    Events,
+    // Nodes of the sparse Merkle tree behind the state commitment, see commitment.rs.
+    Commitment,
+    // Schema version and other metadata about the databases themselves, keys are strings.
+    Meta,
+    // Positions as a `Vec<OutPoint>` per market, only read by migrations.
+    LegacyMarketToPositions,
+    // Markets by the height at which they resolved, so they can be pruned later.
+    ResolvedMarkets,
+    // Headers of all known blocks by block hash, see archive.rs.
+    Headers,
+    // Bodies of all known blocks by block hash.
+    Bodies,
+    // Hashes of the blocks of the best chain by height.
+    BlockHashes,
+    // Outpoints of withdrawals not yet in a bundle, values are empty.
+    PendingWithdrawals,
+    // Withdrawal bundles by the height at which they were built.
+    Bundles,
+    // Unspent Resolution outputs by the decision they resolve, so outcomes of decisions that no
+    // market uses can be proven.
+    DecisionToResolution,
+    // Oracle fees not yet claimed by VoteCoin holders, see emission.rs. Keys are strings.
+    OraclePool,
+    // Oracle fees accrued per unit of VoteCoin when every unspent VoteCoin output was created.
+    VoteCoinRewards,
+    // State of the peg that isn't held in utxos or bundles, the deposit sequence and the pending
+    // bundle. Keys are strings.
+    Peg,
+}
+
+impl Db {
+    pub const ALL: [Db; 24] = [
+        Db::Utxos,
+        Db::Vectors,
+        Db::Markets,
+        Db::MarketToPositions,
+        Db::AddressToOutPoints,
+        Db::MarketToOrders,
+        Db::DecisionToMarkets,
+        Db::HeightToDecisions,
+        Db::MarketHistory,
+        Db::Undo,
+        Db::Events,
+        Db::Commitment,
+        Db::Meta,
+        Db::LegacyMarketToPositions,
+        Db::ResolvedMarkets,
+        Db::Headers,
+        Db::Bodies,
+        Db::BlockHashes,
+        Db::PendingWithdrawals,
+        Db::Bundles,
+        Db::DecisionToResolution,
+        Db::OraclePool,
+        Db::VoteCoinRewards,
+        Db::Peg,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Db::Utxos => "utxos",
+            Db::Vectors => "vectors",
+            Db::Markets => "markets",
+            Db::MarketToPositions => "market_positions",
+            Db::AddressToOutPoints => "address_to_outpoints",
+            Db::MarketToOrders => "market_to_orders",
+            Db::DecisionToMarkets => "decision_to_markets",
+            Db::HeightToDecisions => "height_to_decisions",
+            Db::MarketHistory => "market_history",
+            Db::Undo => "undo",
+            Db::Events => "events",
+            Db::Commitment => "commitment",
+            Db::Meta => "meta",
+            Db::LegacyMarketToPositions => "market_to_positions",
+            Db::ResolvedMarkets => "resolved_markets",
+            Db::Headers => "headers",
+            Db::Bodies => "bodies",
+            Db::BlockHashes => "block_hashes",
+            Db::PendingWithdrawals => "pending_withdrawals",
+            Db::Bundles => "bundles",
+            Db::DecisionToResolution => "decision_to_resolution",
+            Db::OraclePool => "oracle_pool",
+            Db::VoteCoinRewards => "votecoin_rewards",
+            Db::Peg => "peg",
+        }
+    }
+}
+
+/// An encoded key and value.
+pub type Entry = (Vec<u8>, Vec<u8>);
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<Entry, Error>> + 'a>;
+
+/// Read access to the databases of the state within a transaction.
+pub trait StoreRead {
+    fn get_raw(&self, db: Db, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Returns entries with keys starting with `prefix`, in key order.
+    fn prefix_iter(&self, db: Db, prefix: &[u8]) -> Result<Entries<'_>, Error>;
+
+    /// Returns entries with keys in `start..=end`, in key order or in reverse key order if `rev`.
+    fn range(&self, db: Db, start: &[u8], end: &[u8], rev: bool) -> Result<Entries<'_>, Error>;
+
+    fn is_empty(&self, db: Db) -> Result<bool, Error> {
+        Ok(self.prefix_iter(db, &[])?.next().is_none())
+    }
+
+    fn get<K: Serialize, V: DeserializeOwned>(&self, db: Db, key: &K) -> Result<Option<V>, Error>
+    where
+        Self: Sized,
+    {
+        match self.get_raw(db, &bincode::serialize(key)?)? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Write access to the databases of the state within a transaction. Writes are only visible to
+/// other transactions after the transaction is committed.
+pub trait StoreWrite: StoreRead {
+    fn put_raw(&mut self, db: Db, key: &[u8], value: &[u8]) -> Result<(), Error>;
+
+    fn delete_raw(&mut self, db: Db, key: &[u8]) -> Result<(), Error>;
+
+    fn clear(&mut self, db: Db) -> Result<(), Error>;
+
+    fn put<K: Serialize, V: Serialize>(&mut self, db: Db, key: &K, value: &V) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        self.put_raw(db, &bincode::serialize(key)?, &bincode::serialize(value)?)
+    }
+
+    fn delete<K: Serialize>(&mut self, db: Db, key: &K) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        self.delete_raw(db, &bincode::serialize(key)?)
+    }
+}
+
+/// Databases of the state in an LMDB environment.
+pub struct HeedStore {
+    databases: Vec<Database<ByteSlice, ByteSlice>>,
+}
+
+impl HeedStore {
+    /// Number of named databases the environment must allow.
+    pub const NUM_DBS: u32 = Db::ALL.len() as u32;
+
+    /// Opens all databases, creating the missing ones, and migrates them to `SCHEMA_VERSION`.
+    pub fn new(env: &heed::Env) -> Result<Self, Error> {
+        let mut databases = vec![];
+        for db in Db::ALL {
+            databases.push(env.create_database(Some(db.name()))?);
+        }
+        let store = HeedStore { databases };
+        let mut txn = env.write_txn()?;
+        migrations::migrate(&mut store.write(&mut txn))?;
+        txn.commit()?;
+        Ok(store)
+    }
+
+    /// Opens existing databases without creating or migrating them, so it works on read-only
+    /// environments. Databases must already be at `SCHEMA_VERSION`.
+    pub fn open(env: &heed::Env) -> Result<Self, Error> {
+        let mut databases = vec![];
+        for db in Db::ALL {
+            databases.push(env.open_database(Some(db.name()))?);
+        }
+        let version = match &databases[Db::Meta as usize] {
+            Some(meta) => {
+                let rotxn = env.read_txn()?;
+                match meta.get(&rotxn, migrations::SCHEMA_VERSION_KEY)? {
+                    Some(version) => bincode::deserialize(version)?,
+                    None => 0,
+                }
+            }
+            None => 0,
+        };
+        if version != migrations::SCHEMA_VERSION {
+            return Err(Error::IncompatibleSchema {
+                found: version,
+                supported: migrations::SCHEMA_VERSION,
+            });
+        }
+        let databases = databases
+            .into_iter()
+            .zip(Db::ALL)
+            .map(|(database, db)| database.ok_or(Error::MissingDatabase { db }))
+            .collect::<Result<_, _>>()?;
+        Ok(HeedStore { databases })
+    }
+
+    pub fn read<'a, 'e>(&'a self, txn: &'a RoTxn<'e>) -> HeedRead<'a, 'e> {
+        HeedRead { store: self, txn }
+    }
+
+    pub fn write<'a, 'e, 'p>(&'a self, txn: &'a mut RwTxn<'e, 'p>) -> HeedWrite<'a, 'e, 'p> {
+        HeedWrite { store: self, txn }
+    }
+
+    // Databases are opened in the order of `Db::ALL`, which lists them in declaration order.
+    fn database(&self, db: Db) -> &Database<ByteSlice, ByteSlice> {
+        &self.databases[db as usize]
+    }
+
+    fn get_raw(&self, txn: &RoTxn, db: Db, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.database(db).get(txn, key)?.map(<[u8]>::to_vec))
+    }
+
+    fn prefix_iter<'a>(
+        &'a self,
+        txn: &'a RoTxn,
+        db: Db,
+        prefix: &[u8],
+    ) -> Result<Entries<'a>, Error> {
+        let iter = self.database(db).prefix_iter(txn, prefix)?;
+        Ok(Box::new(iter.map(to_owned)))
+    }
+
+    fn range<'a>(
+        &'a self,
+        txn: &'a RoTxn,
+        db: Db,
+        start: &[u8],
+        end: &[u8],
+        rev: bool,
+    ) -> Result<Entries<'a>, Error> {
+        let database = self.database(db);
+        let range = (Bound::Included(start), Bound::Included(end));
+        if rev {
+            Ok(Box::new(database.rev_range(txn, &range)?.map(to_owned)))
+        } else {
+            Ok(Box::new(database.range(txn, &range)?.map(to_owned)))
+        }
+    }
+}
+
+fn to_owned(item: Result<(&[u8], &[u8]), heed::Error>) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let (key, value) = item?;
+    Ok((key.to_vec(), value.to_vec()))
+}
+
+/// Read access through a heed read transaction.
+pub struct HeedRead<'a, 'e> {
+    store: &'a HeedStore,
+    txn: &'a RoTxn<'e>,
+}
+
+impl<'a, 'e> StoreRead for HeedRead<'a, 'e> {
+    fn get_raw(&self, db: Db, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.store.get_raw(self.txn, db, key)
+    }
+
+    fn prefix_iter(&self, db: Db, prefix: &[u8]) -> Result<Entries<'_>, Error> {
+        self.store.prefix_iter(self.txn, db, prefix)
+    }
+
+    fn range(&self, db: Db, start: &[u8], end: &[u8], rev: bool) -> Result<Entries<'_>, Error> {
+        self.store.range(self.txn, db, start, end, rev)
+    }
+}
+
+/// Read and write access through a heed write transaction, which the caller commits.
+pub struct HeedWrite<'a, 'e, 'p> {
+    store: &'a HeedStore,
+    txn: &'a mut RwTxn<'e, 'p>,
+}
+
+impl<'a, 'e, 'p> StoreRead for HeedWrite<'a, 'e, 'p> {
+    fn get_raw(&self, db: Db, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.store.get_raw(&*self.txn, db, key)
+    }
+
+    fn prefix_iter(&self, db: Db, prefix: &[u8]) -> Result<Entries<'_>, Error> {
+        self.store.prefix_iter(&*self.txn, db, prefix)
+    }
+
+    fn range(&self, db: Db, start: &[u8], end: &[u8], rev: bool) -> Result<Entries<'_>, Error> {
+        self.store.range(&*self.txn, db, start, end, rev)
+    }
+}
+
+impl<'a, 'e, 'p> StoreWrite for HeedWrite<'a, 'e, 'p> {
+    fn put_raw(&mut self, db: Db, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.store.database(db).put(self.txn, key, value)?;
+        Ok(())
+    }
+
+    fn delete_raw(&mut self, db: Db, key: &[u8]) -> Result<(), Error> {
+        self.store.database(db).delete(self.txn, key)?;
+        Ok(())
+    }
+
+    fn clear(&mut self, db: Db) -> Result<(), Error> {
+        self.store.database(db).clear(self.txn)?;
+        Ok(())
+    }
+}
+
+/// Databases of the state in memory, for tests and simulations. It is its own transaction:
+/// writes are visible immediately, and a clone taken beforehand serves as a rollback point.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    databases: BTreeMap<Db, BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Result<Self, Error> {
+        let mut store = MemoryStore::default();
+        migrations::migrate(&mut store)?;
+        Ok(store)
+    }
+}
+
+fn to_entries<'a>(iter: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)> + 'a) -> Entries<'a> {
+    Box::new(iter.map(|(key, value)| Ok((key.clone(), value.clone()))))
+}
+
+impl StoreRead for MemoryStore {
+    fn get_raw(&self, db: Db, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self
+            .databases
+            .get(&db)
+            .and_then(|database| database.get(key))
+            .cloned())
+    }
+
+    fn prefix_iter(&self, db: Db, prefix: &[u8]) -> Result<Entries<'_>, Error> {
+        let database = match self.databases.get(&db) {
+            Some(database) => database,
+            None => return Ok(Box::new(std::iter::empty())),
+        };
+        let prefix = prefix.to_vec();
+        let iter = database
+            .range(prefix.clone()..)
+            .take_while(move |(key, _)| key.starts_with(&prefix));
+        Ok(to_entries(iter))
+    }
+
+    fn range(&self, db: Db, start: &[u8], end: &[u8], rev: bool) -> Result<Entries<'_>, Error> {
+        let range = start.to_vec()..=end.to_vec();
+        let database = match self.databases.get(&db) {
+            Some(database) if range.start() <= range.end() => database,
+            _ => return Ok(Box::new(std::iter::empty())),
+        };
+        if rev {
+            Ok(to_entries(database.range(range).rev()))
+        } else {
+            Ok(to_entries(database.range(range)))
+        }
+    }
+}
+
+impl StoreWrite for MemoryStore {
+    fn put_raw(&mut self, db: Db, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.databases
+            .entry(db)
+            .or_default()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete_raw(&mut self, db: Db, key: &[u8]) -> Result<(), Error> {
+        if let Some(database) = self.databases.get_mut(&db) {
+            database.remove(key);
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, db: Db) -> Result<(), Error> {
+        self.databases.remove(&db);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heed_and_memory_stores_agree() {
+        let dir = std::env::temp_dir().join(format!("hivemind-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let env = heed::EnvOpenOptions::new()
+            .map_size(16 * 1024 * 1024)
+            .max_dbs(HeedStore::NUM_DBS)
+            .open(&dir)
+            .unwrap();
+        // An environment without databases has no schema version to open them at.
+        assert!(matches!(
+            HeedStore::open(&env),
+            Err(Error::IncompatibleSchema { found: 0, .. })
+        ));
+        let heed = HeedStore::new(&env).unwrap();
+        let mut txn = env.write_txn().unwrap();
+        let mut heed = heed.write(&mut txn);
+        let mut memory = MemoryStore::new().unwrap();
+        for store in [&mut heed as &mut dyn StoreWrite, &mut memory] {
+            for key in [[0, 1], [1, 0], [1, 1], [1, 2], [2, 0]] {
+                store.put_raw(Db::Markets, &key, &key).unwrap();
+            }
+            store.delete_raw(Db::Markets, &[1, 2]).unwrap();
+        }
+        let entries = |store: &dyn StoreWrite| {
+            let collect = |entries: Entries| entries.map(Result::unwrap).collect::<Vec<_>>();
+            (
+                collect(store.prefix_iter(Db::Markets, &[1]).unwrap()),
+                collect(store.range(Db::Markets, &[0, 2], &[2, 0], false).unwrap()),
+                collect(store.range(Db::Markets, &[0, 2], &[2, 0], true).unwrap()),
+                collect(store.range(Db::Markets, &[2], &[1], false).unwrap()),
+            )
+        };
+        let expected = (
+            vec![(vec![1, 0], vec![1, 0]), (vec![1, 1], vec![1, 1])],
+            vec![
+                (vec![1, 0], vec![1, 0]),
+                (vec![1, 1], vec![1, 1]),
+                (vec![2, 0], vec![2, 0]),
+            ],
+            vec![
+                (vec![2, 0], vec![2, 0]),
+                (vec![1, 1], vec![1, 1]),
+                (vec![1, 0], vec![1, 0]),
+            ],
+            vec![],
+        );
+        assert_eq!(entries(&heed), expected);
+        assert_eq!(entries(&memory), expected);
+        txn.commit().unwrap();
+
+        // Existing databases are opened as they are, without migrating them.
+        let opened = HeedStore::open(&env).unwrap();
+        let rotxn = env.read_txn().unwrap();
+        let read = opened.read(&rotxn);
+        let collect = |entries: Entries| entries.map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(
+            collect(read.prefix_iter(Db::Markets, &[1]).unwrap()),
+            expected.0
+        );
+    }
+
+    #[test]
+    fn databases_are_listed_in_declaration_order() {
+        for (index, db) in Db::ALL.into_iter().enumerate() {
+            assert_eq!(db as usize, index);
+        }
+        let names: std::collections::HashSet<_> = Db::ALL.iter().map(|db| db.name()).collect();
+        assert_eq!(names.len(), Db::ALL.len());
+    }
+}
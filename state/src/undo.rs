@@ -0,0 +1,128 @@
+use crate::{commitment, index_key, Db, Error, State, StoreRead, StoreWrite};
+use hivemind_types::sdk_types::OutPoint;
+use serde::{Deserialize, Serialize};
+
+/// Value of a database entry before a body was connected, `None` if the entry didn't exist.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UndoEntry {
+    pub db: Db,
+    pub key: Vec<u8>,
+    pub previous: Option<Vec<u8>>,
+}
+
+// Undo data is keyed by big-endian height, so it is ordered by height, see `State::prune`.
+pub(crate) fn undo_key(height: u32) -> [u8; 4] {
+    height.to_be_bytes()
+}
+
+impl State {
+    pub(crate) fn get_undo(
+        &self,
+        txn: &impl StoreRead,
+        height: u32,
+    ) -> Result<Option<Vec<UndoEntry>>, Error> {
+        match txn.get_raw(Db::Undo, &undo_key(height))? {
+            Some(undo) => Ok(Some(bincode::deserialize(&undo)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn put_undo(
+        &self,
+        txn: &mut impl StoreWrite,
+        height: u32,
+        undo: &[UndoEntry],
+    ) -> Result<(), Error> {
+        txn.put_raw(Db::Undo, &undo_key(height), &bincode::serialize(undo)?)
+    }
+
+    /// Writes an entry and records its previous value in `undo`.
+    pub(crate) fn put<K: Serialize, V: Serialize>(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        db: Db,
+        key: &K,
+        value: &V,
+    ) -> Result<(), Error> {
+        let key = bincode::serialize(key)?;
+        let value = bincode::serialize(value)?;
+        self.write(txn, undo, db, key, Some(&value))
+    }
+
+    /// Deletes an entry and records its previous value in `undo`.
+    pub(crate) fn delete<K: Serialize>(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        db: Db,
+        key: &K,
+    ) -> Result<(), Error> {
+        let key = bincode::serialize(key)?;
+        self.write(txn, undo, db, key, None)
+    }
+
+    /// Writes `value` under an already encoded key, or deletes the entry if `value` is `None`,
+    /// and records its previous value in `undo`.
+    pub(crate) fn write(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        db: Db,
+        key: Vec<u8>,
+        value: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        let previous = txn.get_raw(db, &key)?;
+        match value {
+            Some(value) => txn.put_raw(db, &key, value)?,
+            None => txn.delete_raw(db, &key)?,
+        }
+        commitment::update_commitment(txn, db, &key, value)?;
+        undo.push(UndoEntry { db, key, previous });
+        Ok(())
+    }
+
+    /// Adds `outpoint` to the index entries of `key`, see `index_key`.
+    pub(crate) fn push_to_index<K: Serialize>(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        db: Db,
+        key: &K,
+        outpoint: OutPoint,
+    ) -> Result<(), Error> {
+        let key = index_key(key, &outpoint)?;
+        self.write(txn, undo, db, key, Some(&[]))
+    }
+
+    /// Removes `outpoint` from the index entries of `key`.
+    pub(crate) fn remove_from_index<K: Serialize>(
+        &self,
+        txn: &mut impl StoreWrite,
+        undo: &mut Vec<UndoEntry>,
+        db: Db,
+        key: &K,
+        outpoint: &OutPoint,
+    ) -> Result<(), Error> {
+        let key = index_key(key, outpoint)?;
+        self.write(txn, undo, db, key, None)
+    }
+
+    /// Reverts the body connected at `height` by restoring every entry it wrote. Bodies must be
+    /// disconnected in the reverse order they were connected in.
+    pub fn disconnect_body(&self, txn: &mut impl StoreWrite, height: u32) -> Result<(), Error> {
+        let undo = self
+            .get_undo(txn, height)?
+            .ok_or(Error::NoUndoData { height })?;
+        for entry in undo.iter().rev() {
+            match &entry.previous {
+                Some(previous) => txn.put_raw(entry.db, &entry.key, previous)?,
+                None => txn.delete_raw(entry.db, &entry.key)?,
+            }
+            commitment::update_commitment(txn, entry.db, &entry.key, entry.previous.as_deref())?;
+        }
+        txn.delete_raw(Db::Undo, &undo_key(height))?;
+        txn.delete(Db::Events, &height)?;
+        Ok(())
+    }
+}
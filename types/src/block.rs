@@ -0,0 +1,40 @@
+//! Headers linking bodies into a chain.
+
+use crate::{
+    drivechain::{BundleStatus, Deposit},
+    merkle::MerkleHash,
+    Body,
+};
+use serde::{Deserialize, Serialize};
+
+pub type BlockHash = [u8; 32];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    /// Hash of the parent block, all zeros for the first block.
+    pub prev_block_hash: BlockHash,
+    pub height: u32,
+    pub body_hash: [u8; 32],
+    /// State commitment after the body and deposits are connected.
+    pub commitment: MerkleHash,
+    /// Deposits credited after the body is connected, in sequence order.
+    pub deposits: Vec<Deposit>,
+    /// Final status of the pending withdrawal bundle, once the mainchain paid or failed it,
+    /// applied after deposits.
+    pub bundle_status: Option<BundleStatus>,
+}
+
+impl Header {
+    pub fn hash(&self) -> BlockHash {
+        hash(self)
+    }
+}
+
+pub fn body_hash(body: &Body) -> [u8; 32] {
+    hash(body)
+}
+
+fn hash<T: Serialize>(value: &T) -> [u8; 32] {
+    let bytes = bincode::serialize(value).expect("in memory serialization can't fail");
+    *blake3::hash(&bytes).as_bytes()
+}
@@ -0,0 +1,51 @@
+//! Deposits from and withdrawals to the mainchain of a drivechain sidechain.
+
+use sdk_types::{Address, OutPoint};
+use serde::{Deserialize, Serialize};
+
+/// Value sent to the sidechain by a mainchain transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deposit {
+    /// Position of the deposit among all deposits to the sidechain, starting at 0. Deposits are
+    /// credited in this order.
+    pub sequence: u64,
+    pub outpoint: bitcoin::OutPoint,
+    pub address: Address,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleOutput {
+    pub address: bitcoin::Address,
+    pub value: u64,
+}
+
+/// Template of the mainchain transaction paying out withdrawals. It only depends on the
+/// withdrawals, so every node builds the same bundle at the same height.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WithdrawalBundle {
+    pub height: u32,
+    /// Withdrawal outputs removed from the sidechain by the bundle.
+    pub withdrawals: Vec<OutPoint>,
+    /// Outputs of the mainchain transaction, one per withdrawal in the same order.
+    pub outputs: Vec<BundleOutput>,
+    /// Sum of the mainchain fees of the withdrawals.
+    pub fee: u64,
+}
+
+impl WithdrawalBundle {
+    pub fn hash(&self) -> [u8; 32] {
+        let bytes = bincode::serialize(self).expect("in memory serialization can't fail");
+        *blake3::hash(&bytes).as_bytes()
+    }
+}
+
+/// What the mainchain did with a proposed bundle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BundleStatus {
+    /// Not paid yet, or not known to the mainchain.
+    Pending,
+    Paid,
+    /// Rejected or expired, its withdrawals are refunded on the sidechain.
+    Failed,
+}
@@ -3,7 +3,14 @@ use rust_decimal::prelude::*;
 use rust_decimal_macros::dec;
 use sdk_authorization_ed25519_dalek::*;
 use sdk_types::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+
+pub mod block;
+pub mod drivechain;
+pub mod merkle;
+pub mod proof;
 
 pub use nalgebra;
 pub use rust_decimal;
@@ -14,9 +21,6 @@ pub use sdk_types;
 // Maybe accounts model would work better for this?
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HivemindContent {
-    // VoteCoin {
-    //     value: u64,
-    // },
     Resolution {
         decision: OutPoint,
         outcome: u32,
@@ -27,20 +31,310 @@ pub enum HivemindContent {
         resolvable_height: u32,
     },
     Market {
-        // (x0 || x1 || x2) && (x3 || x4)
+        // Shares of arbitrary formulas like (x0 || x1 || x2) && (x3 || x4) can be traded with
+        // PredicatePosition outputs.
         b: u64,
         decisions: Vec<OutPoint>,
+        // All trades on a batch auction market within a block are cleared together at uniform
+        // prices, so their order in the block doesn't matter.
+        batch: bool,
     },
-    // IDEA: Don't require fees when people spend Share outputs
-    // to incentivize them to keep the UTXO set small.
+    // Spending Position outputs doesn't require fees as long as the transaction reduces the number
+    // of UTXOs, to incentivize people to keep the UTXO set small.
     //
-    // TODO: Maybe use HashMap<ShareId, Value> instead of separate outputs for every share_id. It
-    // would reduce the number of UTXOs.
+    // A WILDCARD in share stands for any outcome of that decision, so [k, WILDCARD] is a marginal
+    // share on the first decision.
     Position {
         market: sdk_types::OutPoint,
         share: Vec<u32>,
         value: u64,
     },
+    // Many shares of the same market in a single output, so holding a full ladder of outcomes
+    // takes one UTXO instead of one per share.
+    PositionBundle {
+        market: sdk_types::OutPoint,
+        shares: BTreeMap<Vec<u32>, u64>,
+    },
+    // Pays out `value` if `predicate` holds for the outcomes of the market's decisions. It is
+    // priced by the LMSR as `value` of every share for which the predicate is true.
+    PredicatePosition {
+        market: sdk_types::OutPoint,
+        predicate: Predicate,
+        value: u64,
+    },
+    // Resting order to trade `amount` of `share` at `price` value per share. A bid escrows
+    // `amount * price` value and an ask escrows `amount` shares.
+    //
+    // Anyone can spend an order without its owner's authorization as long as the transaction
+    // fills it: pays the owner and recreates the unfilled remainder with the same parameters. Its
+    // input still needs a valid signature, by any key, see `State::validate_authorizations`.
+    LimitOrder {
+        market: sdk_types::OutPoint,
+        share: Vec<u32>,
+        side: Side,
+        amount: u64,
+        price: Decimal,
+    },
+    // Buys `amount` of `share` at whatever the price is when the transaction is connected, as
+    // long as it costs no more than `max_cost`. The transaction reserves `max_cost`, the output
+    // turns into a Position and the unspent part of `max_cost` is returned to `change`.
+    //
+    // The change isn't known when the transaction is signed, so it is paid in an output the
+    // transaction doesn't contain: the change of the i-th Trade output is at vout
+    // `outputs.len() + i`. Batch auction refunds of the transaction are added to the change of
+    // its first Trade output, or paid at vout `outputs.len()` to the address of its first input
+    // if it has no Trade output. These outpoints only depend on the signed transaction.
+    Trade {
+        market: sdk_types::OutPoint,
+        share: Vec<u32>,
+        amount: u64,
+        max_cost: u64,
+        change: Address,
+    },
+    // Reputation of the voters who resolve decisions. It is allocated at genesis and can be moved
+    // but never created by transactions. Spending it claims its share of the oracle fees accrued
+    // since it was created.
+    VoteCoin {
+        value: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+/// A market and `(share, value)` pairs of it held by an output.
+pub type Positions = (OutPoint, Vec<(Vec<u32>, u64)>);
+
+impl HivemindContent {
+    /// Returns the market and `(share, value)` pairs held by a `Position`, a `PositionBundle`, a
+    /// `Trade` or escrowed by an ask `LimitOrder`.
+    pub fn get_positions(&self) -> Option<Positions> {
+        match self {
+            Self::Position {
+                market,
+                share,
+                value,
+            } => Some((*market, vec![(share.clone(), *value)])),
+            Self::LimitOrder {
+                market,
+                share,
+                side: Side::Ask,
+                amount,
+                ..
+            }
+            | Self::Trade {
+                market,
+                share,
+                amount,
+                ..
+            } => Some((*market, vec![(share.clone(), *amount)])),
+            Self::PositionBundle { market, shares } => Some((
+                *market,
+                shares
+                    .iter()
+                    .map(|(share, value)| (share.clone(), *value))
+                    .collect(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Returns the market of any output holding shares: positions and ask orders.
+    pub fn get_position_market(&self) -> Option<OutPoint> {
+        match self {
+            Self::Position { market, .. }
+            | Self::PositionBundle { market, .. }
+            | Self::PredicatePosition { market, .. }
+            | Self::Trade { market, .. }
+            | Self::LimitOrder {
+                market,
+                side: Side::Ask,
+                ..
+            } => Some(*market),
+            _ => None,
+        }
+    }
+
+    pub fn get_votecoin_value(&self) -> u64 {
+        match self {
+            Self::VoteCoin { value } => *value,
+            _ => 0,
+        }
+    }
+
+    /// Returns the value escrowed by a bid `LimitOrder`.
+    pub fn get_escrow_value(&self) -> Option<u64> {
+        match self {
+            Self::LimitOrder {
+                side: Side::Bid,
+                amount,
+                price,
+                ..
+            } => order_value(*amount, *price),
+            _ => Some(0),
+        }
+    }
+}
+
+/// Value paid for `amount` shares at `price`, rounded up in favour of the order owner.
+pub fn order_value(amount: u64, price: Decimal) -> Option<u64> {
+    Decimal::from(amount)
+        .checked_mul(price)
+        .and_then(|value| value.ceil().to_u64())
+}
+
+/// Share index matching every outcome of a decision.
+pub const WILDCARD: u32 = u32::MAX;
+
+/// Predicates can't be nested deeper than this, so decoding and evaluating them can't overflow
+/// the stack.
+pub const MAX_PREDICATE_DEPTH: usize = 16;
+/// Maximum number of `Outcomes`, `Range`, `And`, `Or` and `Not` nodes in a predicate.
+pub const MAX_PREDICATE_NODES: usize = 64;
+/// Predicates are evaluated for every outcome of their market, so they can only be used on
+/// markets with at most this many outcomes.
+pub const MAX_PREDICATE_MARKET_SIZE: u32 = 1024;
+
+/// Boolean formula over the outcomes of a market's decisions, where decisions are referred to by
+/// their index in the market.
+// Serialize and Deserialize are derived as inherent functions, the trait impls below wrap them
+// to bound the nesting depth while decoding.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(remote = "Self")]
+pub enum Predicate {
+    /// Decision resolved to one of `outcomes`.
+    Outcomes {
+        decision: u32,
+        outcomes: Vec<u32>,
+    },
+    /// Decision resolved to an outcome in `start..end`, e.g. "turnout > 60%" for a scaled
+    /// decision.
+    Range {
+        decision: u32,
+        start: u32,
+        end: u32,
+    },
+    And(Vec<Predicate>),
+    Or(Vec<Predicate>),
+    Not(Box<Predicate>),
+}
+
+impl Predicate {
+    pub fn evaluate(&self, outcomes: &[u32]) -> bool {
+        match self {
+            Self::Outcomes {
+                decision,
+                outcomes: set,
+            } => set.contains(&outcomes[*decision as usize]),
+            Self::Range {
+                decision,
+                start,
+                end,
+            } => (*start..*end).contains(&outcomes[*decision as usize]),
+            Self::And(predicates) => predicates.iter().all(|p| p.evaluate(outcomes)),
+            Self::Or(predicates) => predicates.iter().any(|p| p.evaluate(outcomes)),
+            Self::Not(predicate) => !predicate.evaluate(outcomes),
+        }
+    }
+
+    /// Checks that the predicate is within the depth and size limits, that the market is small
+    /// enough to evaluate it on, and that all referenced decisions and outcomes exist in a market
+    /// of this shape. Predicates must be valid before they are evaluated.
+    pub fn is_valid(&self, shape: &[u32]) -> bool {
+        let size = shape
+            .iter()
+            .try_fold(1u32, |size, decision_size| size.checked_mul(*decision_size));
+        if !matches!(size, Some(size) if size <= MAX_PREDICATE_MARKET_SIZE) {
+            return false;
+        }
+        let mut nodes = 0;
+        self.is_bounded(1, &mut nodes) && self.refers_to(shape)
+    }
+
+    fn is_bounded(&self, depth: usize, nodes: &mut usize) -> bool {
+        *nodes += 1;
+        if depth > MAX_PREDICATE_DEPTH || *nodes > MAX_PREDICATE_NODES {
+            return false;
+        }
+        match self {
+            Self::Outcomes { .. } | Self::Range { .. } => true,
+            Self::And(predicates) | Self::Or(predicates) => {
+                predicates.iter().all(|p| p.is_bounded(depth + 1, nodes))
+            }
+            Self::Not(predicate) => predicate.is_bounded(depth + 1, nodes),
+        }
+    }
+
+    fn refers_to(&self, shape: &[u32]) -> bool {
+        let decision_size = |decision: &u32| shape.get(*decision as usize).copied();
+        match self {
+            Self::Outcomes { decision, outcomes } => match decision_size(decision) {
+                Some(size) => outcomes.iter().all(|outcome| *outcome < size),
+                None => false,
+            },
+            Self::Range {
+                decision,
+                start,
+                end,
+            } => match decision_size(decision) {
+                Some(size) => start <= end && *end <= size,
+                None => false,
+            },
+            Self::And(predicates) | Self::Or(predicates) => {
+                predicates.iter().all(|p| p.refers_to(shape))
+            }
+            Self::Not(predicate) => predicate.refers_to(shape),
+        }
+    }
+
+    /// Returns the indices of the flattened market state vector for which this predicate holds.
+    pub fn flat_indices(&self, shape: &[u32]) -> Vec<u32> {
+        let size: u32 = shape.iter().product();
+        (0..size)
+            .filter(|flat_index| self.evaluate(&flat_index_to_share(shape, *flat_index)))
+            .collect()
+    }
+}
+
+thread_local! {
+    static PREDICATE_DECODE_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+impl Serialize for Predicate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Predicate::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Predicate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let depth = PREDICATE_DECODE_DEPTH.with(|depth| {
+            depth.set(depth.get() + 1);
+            depth.get()
+        });
+        let predicate = if depth > MAX_PREDICATE_DEPTH {
+            Err(D::Error::custom("predicate is nested too deeply"))
+        } else {
+            Predicate::deserialize(deserializer)
+        };
+        PREDICATE_DECODE_DEPTH.with(|depth| depth.set(depth.get() - 1));
+        predicate
+    }
+}
+
+/// Inverse of flattening a share into an index of a market state vector, the first decision
+/// varies slowest.
+pub fn flat_index_to_share(shape: &[u32], flat_index: u32) -> Vec<u32> {
+    let mut share = vec![0; shape.len()];
+    let mut rest = flat_index;
+    for (index, size) in shape.iter().enumerate().rev() {
+        share[index] = rest % size;
+        rest /= size;
+    }
+    share
 }
 
 impl GetValue for HivemindContent {
@@ -61,6 +355,7 @@ pub struct Market {
     pub shape: Vec<u32>,
     pub decisions: Vec<OutPoint>,
     pub outcomes: Vec<Option<u32>>,
+    pub batch: bool,
 }
 
 pub type Output = sdk_types::Output<HivemindContent>;
@@ -68,8 +363,147 @@ pub type Transaction = sdk_types::Transaction<HivemindContent>;
 pub type AuthorizedTransaction = sdk_types::AuthorizedTransaction<Authorization, HivemindContent>;
 pub type Body = sdk_types::Body<Authorization, HivemindContent>;
 
+// We multiply b by max_money to avoid exp overflow.
+const MAX_MONEY: Decimal = dec!(21_000_000_00_000_000);
+
 pub fn lmsr_cost(b: Decimal, state: &DVector<Decimal>) -> Decimal {
-    // We multiply b by max_money to avoid exp overflow.
-    let max_money = dec!(21_000_000_00_000_000);
-    state.map(|q| (q / (b * max_money)).exp()).sum().ln() * b * max_money
+    state.map(|q| (q / (b * MAX_MONEY)).exp()).sum().ln() * b * MAX_MONEY
+}
+
+/// Marginal price of buying one unit of every share in `indices`.
+pub fn lmsr_price(b: Decimal, state: &DVector<Decimal>, indices: &[u32]) -> Decimal {
+    let weights = state.map(|q| (q / (b * MAX_MONEY)).exp());
+    let covered: Decimal = indices.iter().map(|index| weights[*index as usize]).sum();
+    covered / weights.sum()
+}
+
+/// Steps of the midpoint rule in `lmsr_batch_prices`.
+pub const BATCH_PRICE_STEPS: u32 = 16;
+
+/// Average marginal prices along the path from `state` to `state + delta`, so that every unit
+/// traded in a batch is priced the same.
+///
+/// The average has no closed form beyond two shares, so it is integrated with the midpoint rule.
+/// Along the path the second derivative of every price is at most `r^2`, where `r` is the range
+/// of `delta / (b * MAX_MONEY)`, so every price is within `r^2 / (24 * BATCH_PRICE_STEPS^2)` of
+/// the exact average and `prices.dot(delta)` only approximates the LMSR cost of the batch.
+pub fn lmsr_batch_prices(
+    b: Decimal,
+    state: &DVector<Decimal>,
+    delta: &DVector<Decimal>,
+) -> DVector<Decimal> {
+    midpoint_prices(b, state, delta, BATCH_PRICE_STEPS)
+}
+
+fn midpoint_prices(
+    b: Decimal,
+    state: &DVector<Decimal>,
+    delta: &DVector<Decimal>,
+    steps: u32,
+) -> DVector<Decimal> {
+    let mut prices = DVector::from_element(state.len(), dec!(0));
+    for step in 0..steps {
+        let t = (Decimal::from(step) + dec!(0.5)) / Decimal::from(steps);
+        let weights = (state + delta * t).map(|q| (q / (b * MAX_MONEY)).exp());
+        prices += &weights / weights.sum();
+    }
+    prices / Decimal::from(steps)
+}
+
+/// Amount of every share in `indices` that has to be bought for `lmsr_price` to reach `price`.
+/// Returns `None` if no finite amount reaches it.
+pub fn lmsr_amount_to_price(
+    b: Decimal,
+    state: &DVector<Decimal>,
+    indices: &[u32],
+    price: Decimal,
+) -> Option<Decimal> {
+    let weights = state.map(|q| (q / (b * MAX_MONEY)).exp());
+    let covered: Decimal = indices.iter().map(|index| weights[*index as usize]).sum();
+    let rest = weights.sum() - covered;
+    if price >= dec!(1) || rest <= dec!(0) {
+        return None;
+    }
+    if price <= covered / (covered + rest) {
+        return Some(dec!(0));
+    }
+    // Buying x of every covered share multiplies their weights by exp(x / b), so the price
+    // reaches `price` when covered * exp(x / b) * (1 - price) = price * rest.
+    Some((price * rest / (covered * (dec!(1) - price))).ln() * b * MAX_MONEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested(depth: usize) -> Predicate {
+        let mut predicate = Predicate::Outcomes {
+            decision: 0,
+            outcomes: vec![0],
+        };
+        for _ in 1..depth {
+            predicate = Predicate::Not(Box::new(predicate));
+        }
+        predicate
+    }
+
+    #[test]
+    fn predicate_depth_is_bounded() {
+        let predicate = nested(MAX_PREDICATE_DEPTH);
+        assert!(predicate.is_valid(&[2]));
+        let bytes = bincode::serialize(&predicate).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Predicate>(&bytes).unwrap(),
+            predicate
+        );
+
+        let predicate = nested(MAX_PREDICATE_DEPTH + 1);
+        assert!(!predicate.is_valid(&[2]));
+        let bytes = bincode::serialize(&predicate).unwrap();
+        assert!(bincode::deserialize::<Predicate>(&bytes).is_err());
+    }
+
+    #[test]
+    fn predicate_size_is_bounded() {
+        let outcomes = Predicate::Outcomes {
+            decision: 0,
+            outcomes: vec![0],
+        };
+        assert!(!Predicate::Or(vec![outcomes.clone(); MAX_PREDICATE_NODES]).is_valid(&[2]));
+        assert!(!outcomes.is_valid(&[2, MAX_PREDICATE_MARKET_SIZE]));
+        assert!(outcomes.is_valid(&[2, MAX_PREDICATE_MARKET_SIZE / 2]));
+    }
+
+    #[test]
+    fn batch_prices_are_within_their_error_bound() {
+        let money = |units: Decimal| units * MAX_MONEY;
+        let cases = [
+            (dec!(1), vec![dec!(0), dec!(0)], vec![dec!(1), dec!(0)]),
+            (
+                dec!(1),
+                vec![dec!(0), dec!(0), dec!(0)],
+                vec![dec!(3), dec!(-1), dec!(0)],
+            ),
+            (
+                dec!(2),
+                vec![dec!(1), dec!(-2), dec!(0)],
+                vec![dec!(-4), dec!(2), dec!(1)],
+            ),
+        ];
+        for (b, state, delta) in cases {
+            let state = DVector::from_vec(state).map(|q| money(q * b));
+            let delta = DVector::from_vec(delta).map(|d| money(d * b));
+            let max = delta.iter().max().unwrap();
+            let min = delta.iter().min().unwrap();
+            let range = (max - min) / money(b);
+            let bound = range * range / Decimal::from(24 * BATCH_PRICE_STEPS.pow(2));
+            let prices = lmsr_batch_prices(b, &state, &delta);
+            let exact = midpoint_prices(b, &state, &delta, 1024);
+            for (price, exact) in prices.iter().zip(exact.iter()) {
+                assert!((price - exact).abs() <= bound, "{price} {exact} {bound}");
+            }
+            // The prices of all shares still add up to one.
+            assert!((prices.sum() - dec!(1)).abs() < dec!(0.000001));
+        }
+    }
 }
@@ -0,0 +1,133 @@
+//! Sparse Merkle tree committing to key value pairs of several databases.
+//!
+//! Every entry is a leaf at the path `blake3(tree || key)` of a binary tree of depth 256, so the
+//! root only depends on the set of entries and not on the order they were written in. The tree is
+//! compressed: an empty subtree hashes to `EMPTY` and a subtree with a single leaf hashes to the
+//! hash of that leaf, wherever it is below the subtree. Only subtrees with at least two leaves are
+//! hashed from their children, so a leaf sits about `log2(n)` levels below the root.
+
+use serde::{Deserialize, Serialize};
+
+pub type MerkleHash = [u8; 32];
+
+// Tags of the databases in the tree.
+pub const UTXOS_TREE: u8 = 0;
+pub const VECTORS_TREE: u8 = 1;
+pub const MARKETS_TREE: u8 = 2;
+pub const MARKET_TO_POSITIONS_TREE: u8 = 3;
+pub const BUNDLES_TREE: u8 = 4;
+pub const PEG_TREE: u8 = 5;
+pub const ORACLE_POOL_TREE: u8 = 6;
+pub const VOTECOIN_REWARDS_TREE: u8 = 7;
+
+/// Depth of the tree, one level per bit of a path.
+pub const DEPTH: usize = 256;
+
+/// Returns the path of an entry of database `tree`.
+pub fn leaf_path(tree: u8, key: &[u8]) -> MerkleHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[tree]);
+    hasher.update(key);
+    *hasher.finalize().as_bytes()
+}
+
+pub fn leaf_hash(path: &MerkleHash, value: &[u8]) -> MerkleHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0]);
+    hasher.update(path);
+    hasher.update(value);
+    *hasher.finalize().as_bytes()
+}
+
+pub fn node_hash(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[1]);
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+/// Hash of an empty subtree.
+pub const EMPTY: MerkleHash = [0; 32];
+
+/// Returns whether the node at `height` above the leaf at `path` is the right child of its
+/// parent. Bits of a path are read from the most significant bit of its first byte, which picks
+/// the child of the root.
+pub fn is_right(path: &MerkleHash, height: usize) -> bool {
+    let bit = DEPTH - 1 - height;
+    path[bit / 8] & (0x80 >> (bit % 8)) != 0
+}
+
+/// Returns the path of the sibling of the node at `height` above the leaf at `path`, with all
+/// bits below that node cleared.
+pub fn sibling_prefix(path: &MerkleHash, height: usize) -> MerkleHash {
+    let mut sibling = prefix(path, height);
+    let bit = DEPTH - 1 - height;
+    sibling[bit / 8] ^= 0x80 >> (bit % 8);
+    sibling
+}
+
+/// Returns `path` with all bits below the node at `height` cleared, which identifies that node
+/// among the nodes at the same height.
+pub fn prefix(path: &MerkleHash, height: usize) -> MerkleHash {
+    let mut prefix = *path;
+    for bit in DEPTH - height..DEPTH {
+        prefix[bit / 8] &= !(0x80 >> (bit % 8));
+    }
+    prefix
+}
+
+/// Proof that the entry at `key` of database `tree` has `value`, or doesn't exist if `value` is
+/// `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub tree: u8,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    /// Path and leaf hash of another entry that is the only leaf of the subtree the key would be
+    /// in, for some proofs of absence.
+    pub leaf: Option<(MerkleHash, MerkleHash)>,
+    /// Hashes of the siblings of the nodes from the subtree holding the key up to the root,
+    /// `None` for empty subtrees.
+    pub siblings: Vec<Option<MerkleHash>>,
+}
+
+impl MerkleProof {
+    /// Returns the root of the tree the proof was made against.
+    pub fn root(&self) -> MerkleHash {
+        let path = leaf_path(self.tree, &self.key);
+        let height = DEPTH.saturating_sub(self.siblings.len());
+        let mut hash = match (&self.value, &self.leaf) {
+            (Some(value), _) => leaf_hash(&path, value),
+            (None, Some((_, hash))) => *hash,
+            (None, None) => EMPTY,
+        };
+        for (index, sibling) in self.siblings.iter().enumerate() {
+            let sibling = sibling.unwrap_or(EMPTY);
+            hash = if is_right(&path, height + index) {
+                node_hash(&sibling, &hash)
+            } else {
+                node_hash(&hash, &sibling)
+            };
+        }
+        hash
+    }
+
+    pub fn verify(&self, root: &MerkleHash) -> bool {
+        if self.siblings.len() > DEPTH {
+            return false;
+        }
+        // The other leaf must be in the subtree the key would be in, so the key isn't.
+        if let Some((other, _)) = &self.leaf {
+            let path = leaf_path(self.tree, &self.key);
+            let height = DEPTH - self.siblings.len();
+            if self.value.is_some()
+                || *other == path
+                || prefix(other, height) != prefix(&path, height)
+            {
+                return false;
+            }
+        }
+        self.root() == *root
+    }
+}
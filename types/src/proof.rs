@@ -0,0 +1,106 @@
+//! Proofs about the state against a state commitment, verifiable without the state itself.
+
+use crate::{
+    merkle::{self, MerkleHash, MerkleProof},
+    HivemindContent, Market, Output,
+};
+use rust_decimal::Decimal;
+use sdk_types::{Address, OutPoint};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Proof of the outcome of a decision, through a market that uses it or through the unspent
+/// Resolution output that resolved it. Resolution outputs are only valid in the transaction that
+/// spends their decision, so there is at most one per decision.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DecisionOutcomeProof {
+    Market {
+        market: OutPoint,
+        proof: MerkleProof,
+    },
+    Resolution {
+        outpoint: OutPoint,
+        proof: MerkleProof,
+    },
+}
+
+/// Proof of a market and its LMSR state vector.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarketStateProof {
+    pub market: MerkleProof,
+    pub state: MerkleProof,
+}
+
+// Returns the value of an existing entry if the proof is valid and is for the entry of `tree` at
+// `key`.
+fn verify_entry<K: Serialize, V: DeserializeOwned>(
+    root: &MerkleHash,
+    tree: u8,
+    key: &K,
+    proof: &MerkleProof,
+) -> Option<V> {
+    if proof.tree != tree || proof.key != bincode::serialize(key).ok()? || !proof.verify(root) {
+        return None;
+    }
+    bincode::deserialize(proof.value.as_ref()?).ok()
+}
+
+/// Returns the utxo at `outpoint` if the proof shows it exists under `root`.
+pub fn verify_utxo(root: &MerkleHash, outpoint: &OutPoint, proof: &MerkleProof) -> Option<Output> {
+    verify_entry(root, merkle::UTXOS_TREE, outpoint, proof)
+}
+
+/// Returns the utxo at `outpoint` if the proof shows it exists under `root`, is owned by
+/// `address` and holds shares of `market`.
+pub fn verify_position(
+    root: &MerkleHash,
+    outpoint: &OutPoint,
+    address: &Address,
+    market: &OutPoint,
+    proof: &MerkleProof,
+) -> Option<Output> {
+    let output = verify_utxo(root, outpoint, proof)?;
+    let position_market = match &output.content {
+        sdk_types::Content::Custom(content) => content.get_position_market(),
+        _ => None,
+    };
+    if output.address != *address || position_market != Some(*market) {
+        return None;
+    }
+    Some(output)
+}
+
+/// Returns the outcome `decision` resolved to if the proof shows it under `root`, `None` if the
+/// proof is invalid or the decision isn't resolved.
+pub fn verify_decision_outcome(
+    root: &MerkleHash,
+    decision: &OutPoint,
+    proof: &DecisionOutcomeProof,
+) -> Option<u32> {
+    match proof {
+        DecisionOutcomeProof::Market { market, proof } => {
+            let market: Market = verify_entry(root, merkle::MARKETS_TREE, market, proof)?;
+            let index = market.decisions.iter().position(|d| d == decision)?;
+            *market.outcomes.get(index)?
+        }
+        DecisionOutcomeProof::Resolution { outpoint, proof } => {
+            match verify_utxo(root, outpoint, proof)?.content {
+                sdk_types::Content::Custom(HivemindContent::Resolution {
+                    decision: resolved,
+                    outcome,
+                }) if resolved == *decision => Some(outcome),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Returns a market and its state vector if the proof shows them under `root`.
+pub fn verify_market_state(
+    root: &MerkleHash,
+    market: &OutPoint,
+    proof: &MarketStateProof,
+) -> Option<(Market, Vec<Decimal>)> {
+    let state = verify_entry(root, merkle::VECTORS_TREE, market, &proof.state)?;
+    let market = verify_entry(root, merkle::MARKETS_TREE, market, &proof.market)?;
+    Some((market, state))
+}